@@ -0,0 +1,36 @@
+//! Compiles and runs arbitrary source, which must never panic. Run it with
+//! `cargo fuzz run interpret fuzz/corpus/interpret`, the corpus is seeded from `examples/`.
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+use rlox_vm::{
+    compiler::Compiler,
+    render::{Renderer, Style},
+    vm::VM,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source.to_string(),
+        Err(_) => return,
+    };
+    let renderer = Renderer::new("fuzz.lox", &source, Style::Plain);
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+
+    match compiler.compile() {
+        Ok(function) => {
+            vm.set_instruction_limit(Some(100_000));
+            if let Err(error) = vm.run_main(&function, &mut io::sink()) {
+                renderer.runtime_error(&error);
+            }
+        }
+        Err(diagnostics) => {
+            for diagnostic in diagnostics {
+                renderer.diagnostic(&diagnostic);
+            }
+        }
+    }
+});
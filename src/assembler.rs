@@ -0,0 +1,634 @@
+//! A textual format for bytecode, the inverse of the [disassembler](crate::disassembler), to
+//! write functions by hand without going through the compiler.
+//!
+//! The source is the body of the script function. Each line holds an operation, named as the
+//! disassembler shows it, followed by its operands:
+//!
+//! ```text
+//! ; Comments start with a semicolon
+//! fun double 1 {          ; A function taking one argument, constant of the enclosing one
+//!     GetLocal 0
+//!     Constant 2          ; Literal constants: numbers, "strings", true, false and nil
+//!     Multiply
+//!     Return
+//! }
+//!     Closure double      ; Captures follow as `local 1` or `upvalue 0`
+//!     DefineGlobal double ; Globals are referred by name
+//! again:                  ; Labels name the offset of the next operation
+//!     GetGlobal double
+//!     Constant 21
+//!     Call 1
+//!     Print
+//!     Jump again          ; Jumps go to labels of their function
+//! ```
+//!
+//! Nested functions become constants of the one they're defined in when a `Closure` refers to
+//! them. Operands that are constant indexes can also be given raw, as `#3`, and so can the
+//! symbols of globals and the distances of jumps. Nothing is checked beyond the operands fitting
+//! their encoding, so the code can be as broken as needed.
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    chunk::{
+        OpCode, Operation, SourceLocation, Upvalue, MAX_BYTE_OPERAND, MAX_CONSTANTS,
+        MAX_SHORT_OPERAND,
+    },
+    diagnostic::{Diagnostic, Span},
+    interner::Interner,
+    object::ObjFunction,
+    value::Value,
+};
+
+/// Assembles the source into the script function, using the interner for its strings and
+/// globals. Fails on the first error found.
+pub fn assemble(source: &str, interner: &mut Interner) -> Result<ObjFunction, Diagnostic> {
+    let lines = tokenize(source)?;
+    let mut assembler = Assembler {
+        lines: lines.into_iter(),
+        interner,
+    };
+    assembler.function("main", 0, None)
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: u32,
+    column: u32,
+    span: Span,
+}
+
+impl Token {
+    fn error(&self, message: &str) -> Diagnostic {
+        Diagnostic::error(message, self.line, self.column, self.span)
+    }
+
+    fn location(&self) -> SourceLocation {
+        SourceLocation {
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+/// Splits each line in tokens, leaving out comments and lines without anything else.
+fn tokenize(source: &str) -> Result<Vec<Vec<Token>>, Diagnostic> {
+    let mut lines = vec![];
+    let mut line_start = 0;
+    for (index, line) in source.split_inclusive('\n').enumerate() {
+        let number = index as u32 + 1;
+        let mut tokens = vec![];
+        let mut chars = line.char_indices().peekable();
+
+        while let Some(&(start, c)) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+                continue;
+            }
+            if c == ';' {
+                break;
+            }
+
+            let mut end = start + c.len_utf8();
+            chars.next();
+            if c == '"' {
+                loop {
+                    match chars.next() {
+                        Some((at, '"')) => {
+                            end = at + 1;
+                            break;
+                        }
+                        Some((_, '\n')) | None => {
+                            let span =
+                                Span::new(line_start + start, line_start + line.trim_end().len());
+                            let column = line[..start].chars().count() as u32 + 1;
+                            return Err(Diagnostic::error(
+                                "Unterminated string.",
+                                number,
+                                column,
+                                span,
+                            ));
+                        }
+                        Some(_) => (),
+                    }
+                }
+            } else {
+                while let Some(&(at, c)) = chars.peek() {
+                    if c.is_whitespace() || c == ';' {
+                        break;
+                    }
+                    end = at + c.len_utf8();
+                    chars.next();
+                }
+            }
+
+            tokens.push(Token {
+                text: line[start..end].to_string(),
+                line: number,
+                column: line[..start].chars().count() as u32 + 1,
+                span: Span::new(line_start + start, line_start + end),
+            });
+        }
+
+        if !tokens.is_empty() {
+            lines.push(tokens);
+        }
+        line_start += line.len();
+    }
+    Ok(lines)
+}
+
+/// A jump whose distance is known once every label of its function is.
+struct PendingJump {
+    offset: usize,
+    // Where the distance is measured from, the end of the jump
+    next: usize,
+    backwards: bool,
+    label: Token,
+}
+
+/// A closure over a nested function, which may be defined after it.
+struct PendingClosure {
+    constant: usize,
+    captures: usize,
+    function: Token,
+}
+
+struct Assembler<'a> {
+    lines: std::vec::IntoIter<Vec<Token>>,
+    interner: &'a mut Interner,
+}
+
+impl Assembler<'_> {
+    /// Assembles lines up to the `}` closing the function, or the end of the source if it's
+    /// the script, which has no `header`.
+    fn function(
+        &mut self,
+        name: &str,
+        arity: u8,
+        header: Option<&Token>,
+    ) -> Result<ObjFunction, Diagnostic> {
+        let mut function = ObjFunction::new(name);
+        function.arity = arity;
+        let mut labels: HashMap<String, usize> = HashMap::new();
+        let mut jumps = vec![];
+        let mut closures = vec![];
+        let mut nested: HashMap<String, ObjFunction> = HashMap::new();
+
+        loop {
+            let mut tokens = match self.lines.next() {
+                Some(tokens) => tokens.into_iter(),
+                None => match header {
+                    Some(header) => return Err(header.error("Expect '}' after function body.")),
+                    None => break,
+                },
+            };
+            let first = tokens.next().expect("Only lines with tokens are kept");
+
+            if first.text == "}" {
+                if header.is_none() {
+                    return Err(first.error("Unexpected '}' outside of a function."));
+                }
+                Self::expect_end(&mut tokens)?;
+                break;
+            }
+
+            if first.text == "fun" {
+                let name = tokens
+                    .next()
+                    .ok_or_else(|| first.error("Expect function name."))?;
+                let mut arity = 0;
+                let mut brace = tokens.next();
+                if let Some(token) = brace.as_ref().filter(|token| token.text != "{") {
+                    arity = Self::byte(token)? as u8;
+                    brace = tokens.next();
+                }
+                if brace.as_ref().map(|token| token.text.as_str()) != Some("{") {
+                    return Err(name.error("Expect '{' before function body."));
+                }
+                Self::expect_end(&mut tokens)?;
+
+                let body = self.function(&name.text, arity, Some(&name))?;
+                if nested.insert(name.text.clone(), body).is_some() {
+                    return Err(name.error("A function with this name is already defined here."));
+                }
+                continue;
+            }
+
+            let instruction = match first.text.strip_suffix(':') {
+                Some(label) => {
+                    if labels
+                        .insert(label.to_string(), function.chunk.len())
+                        .is_some()
+                    {
+                        return Err(first.error("This label is already defined."));
+                    }
+                    tokens.next()
+                }
+                None => Some(first),
+            };
+            let mnemonic = match instruction {
+                Some(mnemonic) => mnemonic,
+                None => continue,
+            };
+            let opcode = OpCode::ALL
+                .iter()
+                .find(|opcode| format!("{:?}", opcode) == mnemonic.text)
+                .copied()
+                .ok_or_else(|| mnemonic.error("Unknown operation."))?;
+
+            let mut operand = || {
+                tokens
+                    .next()
+                    .ok_or_else(|| mnemonic.error("Expect an operand."))
+            };
+            let offset = function.chunk.len();
+            let op = match opcode {
+                OpCode::Constant | OpCode::ConstantLong => {
+                    Operation::Constant(self.constant(&mut function, &operand()?)?)
+                }
+                OpCode::Nil => Operation::Nil,
+                OpCode::True => Operation::True,
+                OpCode::False => Operation::False,
+                OpCode::Pop => Operation::Pop,
+                OpCode::GetGlobal => Operation::GetGlobal(self.global(&operand()?)?),
+                OpCode::DefineGlobal => Operation::DefineGlobal(self.global(&operand()?)?),
+                OpCode::SetGlobal => Operation::SetGlobal(self.global(&operand()?)?),
+                OpCode::GetLocal => Operation::GetLocal(Self::byte(&operand()?)?),
+                OpCode::SetLocal => Operation::SetLocal(Self::byte(&operand()?)?),
+                OpCode::GetUpvalue => Operation::GetUpvalue(Self::byte(&operand()?)?),
+                OpCode::SetUpvalue => Operation::SetUpvalue(Self::byte(&operand()?)?),
+                OpCode::Equal => Operation::Equal,
+                OpCode::Greater => Operation::Greater,
+                OpCode::Less => Operation::Less,
+                OpCode::Add => Operation::Add,
+                OpCode::Substract => Operation::Substract,
+                OpCode::Multiply => Operation::Multiply,
+                OpCode::Divide => Operation::Divide,
+                OpCode::Not => Operation::Not,
+                OpCode::Negate => Operation::Negate,
+                OpCode::Print => Operation::Print,
+                OpCode::JumpIfFalse | OpCode::Jump | OpCode::Loop => {
+                    let target = operand()?;
+                    let distance = match target.text.strip_prefix('#') {
+                        Some(raw) => Self::number(&target, raw, MAX_SHORT_OPERAND)?,
+                        None => {
+                            jumps.push(PendingJump {
+                                offset,
+                                next: offset + 3,
+                                backwards: opcode == OpCode::Loop,
+                                label: target,
+                            });
+                            0
+                        }
+                    };
+                    match opcode {
+                        OpCode::JumpIfFalse => Operation::JumpIfFalse(distance),
+                        OpCode::Jump => Operation::Jump(distance),
+                        _ => Operation::Loop(distance),
+                    }
+                }
+                OpCode::Call => Operation::Call(Self::byte(&operand()?)? as u8),
+                OpCode::Closure => {
+                    let target = operand()?;
+                    let mut upvalues = vec![];
+                    while let Some(kind) = tokens.next() {
+                        let is_local = match kind.text.as_str() {
+                            "local" => true,
+                            "upvalue" => false,
+                            _ => return Err(kind.error("Expect 'local' or 'upvalue'.")),
+                        };
+                        let index = tokens
+                            .next()
+                            .ok_or_else(|| kind.error("Expect an index."))?;
+                        upvalues.push(Upvalue {
+                            is_local,
+                            index: Self::byte(&index)?,
+                        });
+                    }
+
+                    let constant = match target.text.strip_prefix('#') {
+                        Some(raw) => Self::number(&target, raw, MAX_SHORT_OPERAND)?,
+                        None => {
+                            // Filled in once the function is known to exist
+                            let constant = function.chunk.add_constant(Value::Nil);
+                            closures.push(PendingClosure {
+                                constant,
+                                captures: upvalues.len(),
+                                function: target,
+                            });
+                            constant
+                        }
+                    };
+                    Self::fits(&mnemonic, constant, MAX_SHORT_OPERAND)?;
+                    Operation::Closure(constant, upvalues)
+                }
+                OpCode::CloseUpvalue => Operation::CloseUpvalue,
+                OpCode::Class => Operation::Class(self.name(&mut function, &operand()?)?),
+                OpCode::GetProperty => {
+                    Operation::GetProperty(self.name(&mut function, &operand()?)?)
+                }
+                OpCode::SetProperty => {
+                    Operation::SetProperty(self.name(&mut function, &operand()?)?)
+                }
+                OpCode::Method => Operation::Method(self.name(&mut function, &operand()?)?),
+                OpCode::Invoke => {
+                    let name = self.name(&mut function, &operand()?)?;
+                    Operation::Invoke(name, Self::byte(&operand()?)? as u8)
+                }
+                OpCode::Inherit => Operation::Inherit,
+                OpCode::GetSuper => Operation::GetSuper(self.name(&mut function, &operand()?)?),
+                OpCode::SuperInvoke => {
+                    let name = self.name(&mut function, &operand()?)?;
+                    Operation::SuperInvoke(name, Self::byte(&operand()?)? as u8)
+                }
+                OpCode::Return => Operation::Return,
+            };
+            Self::expect_end(&mut tokens)?;
+            function.chunk.write(op, mnemonic.location());
+        }
+
+        for jump in jumps {
+            let target = *labels
+                .get(&jump.label.text)
+                .ok_or_else(|| jump.label.error("Undefined label."))?;
+            let distance = if jump.backwards {
+                jump.next.checked_sub(target)
+            } else {
+                target.checked_sub(jump.next)
+            };
+            let distance = match distance {
+                Some(distance) if distance <= MAX_SHORT_OPERAND => distance as u16,
+                Some(_) => return Err(jump.label.error("Too much code to jump over.")),
+                None if jump.backwards => {
+                    return Err(jump.label.error("Loops can only jump backwards."))
+                }
+                None => return Err(jump.label.error("Jumps can only go forward.")),
+            };
+            function.chunk.patch_jump(jump.offset, distance);
+        }
+
+        let mut captures: HashMap<&str, usize> = HashMap::new();
+        for closure in &closures {
+            let name = closure.function.text.as_str();
+            let nested_function = nested
+                .get_mut(name)
+                .ok_or_else(|| closure.function.error("Undefined function."))?;
+            if *captures.entry(name).or_insert(closure.captures) != closure.captures {
+                return Err(closure
+                    .function
+                    .error("Every closure over a function must capture as many variables."));
+            }
+            nested_function.upvalue_count = closure.captures;
+        }
+
+        // Every closure over a function shares it
+        let nested: HashMap<String, Rc<ObjFunction>> = nested
+            .into_iter()
+            .map(|(name, function)| (name, Rc::new(function)))
+            .collect();
+        for closure in closures {
+            function.chunk.constants[closure.constant] =
+                Value::Function(Rc::clone(&nested[&closure.function.text]));
+        }
+        Ok(function)
+    }
+
+    fn expect_end<I: Iterator<Item = Token>>(tokens: &mut I) -> Result<(), Diagnostic> {
+        match tokens.next() {
+            Some(token) => Err(token.error("Unexpected operand.")),
+            None => Ok(()),
+        }
+    }
+
+    fn number(token: &Token, text: &str, max: usize) -> Result<usize, Diagnostic> {
+        let value: usize = text.parse().map_err(|_| token.error("Expect a number."))?;
+        Self::fits(token, value, max)
+    }
+
+    fn fits(token: &Token, value: usize, max: usize) -> Result<usize, Diagnostic> {
+        if value > max {
+            return Err(token.error(&format!("The operand can't be bigger than {}.", max)));
+        }
+        Ok(value)
+    }
+
+    fn byte(token: &Token) -> Result<usize, Diagnostic> {
+        Self::number(token, &token.text, MAX_BYTE_OPERAND)
+    }
+
+    /// The index of a literal constant, added to the function, or a raw index.
+    fn constant(&mut self, function: &mut ObjFunction, token: &Token) -> Result<usize, Diagnostic> {
+        if let Some(raw) = token.text.strip_prefix('#') {
+            return Self::number(token, raw, MAX_CONSTANTS);
+        }
+
+        let text = token.text.as_str();
+        let value = match text {
+            "nil" => Value::Nil,
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            _ if text.starts_with('"') => {
+                Value::String(self.interner.intern(&text[1..text.len() - 1]))
+            }
+            _ => Value::Number(
+                text.parse()
+                    .map_err(|_| token.error("Expect a constant."))?,
+            ),
+        };
+        Self::fits(token, function.chunk.add_constant(value), MAX_CONSTANTS)
+    }
+
+    /// The string constant holding a name, like the one of a property, or a raw index.
+    fn name(&mut self, function: &mut ObjFunction, token: &Token) -> Result<usize, Diagnostic> {
+        if let Some(raw) = token.text.strip_prefix('#') {
+            return Self::number(token, raw, MAX_SHORT_OPERAND);
+        }
+        let name = Value::String(self.interner.intern(&token.text));
+        Self::fits(token, function.chunk.add_constant(name), MAX_SHORT_OPERAND)
+    }
+
+    /// The symbol of a global variable, or a raw one.
+    fn global(&mut self, token: &Token) -> Result<usize, Diagnostic> {
+        if let Some(raw) = token.text.strip_prefix('#') {
+            return Self::number(token, raw, MAX_SHORT_OPERAND);
+        }
+        Self::fits(token, self.interner.symbol(&token.text), MAX_SHORT_OPERAND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assemble;
+    use crate::{
+        chunk::{Operation, Upvalue},
+        interner::Interner,
+        value::Value,
+        vm::VM,
+    };
+
+    fn assemble_ok(source: &str) -> (crate::object::ObjFunction, Interner) {
+        let mut interner = Interner::new();
+        let function = assemble(source, &mut interner).expect("This assembly should be valid");
+        (function, interner)
+    }
+
+    fn assert_error(source: &str, message: &str, line: u32, column: u32) {
+        let error = assemble(source, &mut Interner::new()).unwrap_err();
+        assert_eq!(
+            (error.message.as_str(), error.line, error.column),
+            (message, line, column)
+        );
+    }
+
+    #[test]
+    fn operations() {
+        let (function, mut interner) = assemble_ok(
+            "
+    Constant 1.5        ; a comment
+    Constant \"a b\"
+    DefineGlobal x
+    GetGlobal #0
+    GetLocal 3
+    Invoke size 2
+    Return",
+        );
+        assert_eq!(
+            function.chunk.operations(),
+            vec![
+                Operation::Constant(0),
+                Operation::Constant(1),
+                Operation::DefineGlobal(interner.symbol("x")),
+                Operation::GetGlobal(0),
+                Operation::GetLocal(3),
+                Operation::Invoke(2, 2),
+                Operation::Return,
+            ]
+        );
+        assert_eq!(function.chunk.constants[0], Value::Number(1.5));
+        assert_eq!(
+            function.chunk.constants[1],
+            Value::String(interner.intern("a b"))
+        );
+        assert_eq!(
+            function.chunk.constants[2],
+            Value::String(interner.intern("size"))
+        );
+        // Each operation comes from its line
+        assert_eq!(function.chunk.line(0), 2);
+        assert_eq!(function.chunk.line(function.chunk.len() - 1), 8);
+    }
+
+    #[test]
+    fn labels() {
+        let (function, _) = assemble_ok(
+            "
+start:
+    True
+    JumpIfFalse end
+    Loop start
+end: Nil
+    Jump #0
+    Return",
+        );
+        assert_eq!(
+            function.chunk.operations(),
+            vec![
+                Operation::True,
+                Operation::JumpIfFalse(3),
+                Operation::Loop(7),
+                Operation::Nil,
+                Operation::Jump(0),
+                Operation::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_functions() {
+        let (function, _) = assemble_ok(
+            "
+    Closure outer
+    Return
+fun outer {
+    Constant 1
+    Closure inner local 0
+    Return
+    fun inner 2 {
+        GetUpvalue 0
+        Return
+    }
+}",
+        );
+        let outer = match &function.chunk.constants[0] {
+            Value::Function(outer) => outer,
+            other => panic!("Expected a function, got {:?}", other),
+        };
+        assert_eq!((outer.name.as_str(), outer.arity), ("outer", 0));
+        assert_eq!(
+            outer.chunk.operations()[1],
+            Operation::Closure(
+                1,
+                vec![Upvalue {
+                    is_local: true,
+                    index: 0
+                }]
+            )
+        );
+        let inner = match &outer.chunk.constants[1] {
+            Value::Function(inner) => inner,
+            other => panic!("Expected a function, got {:?}", other),
+        };
+        assert_eq!((inner.arity, inner.upvalue_count), (2, 1));
+    }
+
+    #[test]
+    fn runs() {
+        let mut vm = VM::new();
+        let function = assemble(
+            "
+fun double 1 {
+    GetLocal 0
+    Constant 2
+    Multiply
+    Return
+}
+    Closure double
+    DefineGlobal double
+    GetGlobal double
+    Constant 21
+    Call 1
+    Print
+    Nil
+    Return",
+            vm.interner_mut(),
+        )
+        .unwrap();
+
+        let mut output = vec![];
+        vm.run_main(&function, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "42\n");
+    }
+
+    #[test]
+    fn errors() {
+        assert_error("    Frobnicate", "Unknown operation.", 1, 5);
+        assert_error("Constant", "Expect an operand.", 1, 1);
+        assert_error("Constant 1 2", "Unexpected operand.", 1, 12);
+        assert_error("Constant \"open", "Unterminated string.", 1, 10);
+        assert_error("GetLocal 256", "The operand can't be bigger than 255.", 1, 10);
+        assert_error("Jump nowhere", "Undefined label.", 1, 6);
+        assert_error("a:\na:", "This label is already defined.", 2, 1);
+        assert_error("x:\nJump x", "Jumps can only go forward.", 2, 6);
+        assert_error("Closure f", "Undefined function.", 1, 9);
+        assert_error("fun f {\nReturn", "Expect '}' after function body.", 1, 5);
+        assert_error("}", "Unexpected '}' outside of a function.", 1, 1);
+        assert_error(
+            "fun f {\n}\nClosure f\nClosure f local 0",
+            "Every closure over a function must capture as many variables.",
+            4,
+            9,
+        );
+    }
+}
@@ -1,11 +1,146 @@
-use std::io::Write;
-
-use crate::value::Value;
+use crate::{interner::SymbolId, value::Value};
 
 pub type IdentifierId = usize;
 pub type IdentifierName = String;
 pub type LocalVarIndex = usize;
+pub type UpvalueIndex = usize;
 
+/// Where a closure captures one of its upvalues from when it gets created: either a local
+/// of the enclosing function or one of the enclosing function's own upvalues.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Upvalue {
+    pub is_local: bool,
+    pub index: usize,
+}
+
+/// Where in the source an operation comes from. Lines and columns start at 1, and 0 means the
+/// location is unknown, like for chunks written by hand.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct SourceLocation {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl SourceLocation {
+    pub fn new(line: i32, column: i32) -> Self {
+        SourceLocation {
+            line: line.max(0) as u32,
+            column: column.max(0) as u32,
+        }
+    }
+}
+
+/// Biggest index a `Constant` operation can refer to.
+pub const MAX_CONSTANTS: usize = (1 << 24) - 1;
+/// Biggest value of the two bytes operands: globals, jumps, and the constants referred by
+/// anything but `Constant`.
+pub const MAX_SHORT_OPERAND: usize = u16::MAX as usize;
+/// Biggest value of the one byte operands: locals and upvalues.
+pub const MAX_BYTE_OPERAND: usize = u8::MAX as usize;
+
+/// Byte each operation starts with in the code of a [`Chunk`], followed by its operands.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OpCode {
+    Constant,
+    ConstantLong,
+    Nil,
+    True,
+    False,
+    Pop,
+
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+
+    GetLocal,
+    SetLocal,
+
+    GetUpvalue,
+    SetUpvalue,
+
+    Equal,
+    Greater,
+    Less,
+
+    Add,
+    Substract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+
+    JumpIfFalse,
+    Loop,
+    Jump,
+
+    Call,
+    Closure,
+    CloseUpvalue,
+
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    Invoke,
+    Inherit,
+    GetSuper,
+    SuperInvoke,
+
+    Return,
+}
+
+impl OpCode {
+    /// Every operation code, in the order of their discriminants.
+    pub const ALL: [OpCode; 38] = [
+        OpCode::Constant,
+        OpCode::ConstantLong,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::Less,
+        OpCode::Add,
+        OpCode::Substract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Print,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Jump,
+        OpCode::Call,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Class,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::Method,
+        OpCode::Invoke,
+        OpCode::Inherit,
+        OpCode::GetSuper,
+        OpCode::SuperInvoke,
+        OpCode::Return,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OpCode::ALL.get(byte as usize).copied()
+    }
+}
+
+/// Typed view of one encoded operation, see [`Chunk::write`] and [`Chunk::decode`]. Operands
+/// referring to names, like the ones of classes and properties, are indexes of string constants.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Operation {
     Constant(IdentifierId),
@@ -14,13 +149,16 @@ pub enum Operation {
     False,
     Pop,
 
-    GetGlobal(IdentifierName),
-    DefineGlobal(IdentifierName),
-    SetGlobal(IdentifierName),
+    GetGlobal(SymbolId),
+    DefineGlobal(SymbolId),
+    SetGlobal(SymbolId),
 
     GetLocal(LocalVarIndex),
     SetLocal(LocalVarIndex),
 
+    GetUpvalue(UpvalueIndex),
+    SetUpvalue(UpvalueIndex),
+
     Equal,
     Greater,
     Less,
@@ -33,46 +171,183 @@ pub enum Operation {
     Negate,
     Print,
 
+    // Jumps are measured in bytes, from the end of the jump operation
     JumpIfFalse(usize),
     Loop(usize),
     Jump(usize),
 
     Call(u8),
+    Closure(IdentifierId, Vec<Upvalue>),
+    CloseUpvalue,
+
+    Class(IdentifierId),
+    GetProperty(IdentifierId),
+    SetProperty(IdentifierId),
+    Method(IdentifierId),
+    Invoke(IdentifierId, u8),
+    Inherit,
+    GetSuper(IdentifierId),
+    SuperInvoke(IdentifierId, u8),
 
     Return,
 }
 
 impl Operation {
-    pub fn disassemble<W: Write>(
-        &self,
-        chunk: &Chunk,
-        offset: usize,
-        output: &mut W,
-    ) -> core::result::Result<(), std::io::Error> {
-        write!(output, "{:04} ", offset)?;
-        if offset > 0 {
-            write!(output, "   | ")?;
+    /// Appends the bytes of the operation to `code`. Operands must fit in their encoding,
+    /// see [`MAX_CONSTANTS`], [`MAX_SHORT_OPERAND`] and [`MAX_BYTE_OPERAND`].
+    fn encode(&self, code: &mut Vec<u8>) {
+        fn byte(code: &mut Vec<u8>, value: usize) {
+            code.push(value as u8);
+        }
+        fn short(code: &mut Vec<u8>, value: usize) {
+            code.extend_from_slice(&(value as u16).to_be_bytes());
         }
-        // else {
-        // 	print!("{:4} ", chunk.lines[offset]);
-        // }
-        match self {
-            Operation::Constant(constant_offset) => writeln!(
-                output,
-                "Constant	{} '{:?}'",
-                constant_offset, &chunk.constants[*constant_offset]
-            ),
 
-            op => writeln!(output, "{:?}", op),
+        match self {
+            Operation::Constant(index) if *index <= MAX_BYTE_OPERAND => {
+                code.push(OpCode::Constant as u8);
+                byte(code, *index);
+            }
+            Operation::Constant(index) => {
+                code.push(OpCode::ConstantLong as u8);
+                code.extend_from_slice(&(*index as u32).to_be_bytes()[1..]);
+            }
+            Operation::Nil => code.push(OpCode::Nil as u8),
+            Operation::True => code.push(OpCode::True as u8),
+            Operation::False => code.push(OpCode::False as u8),
+            Operation::Pop => code.push(OpCode::Pop as u8),
+            Operation::GetGlobal(symbol) => {
+                code.push(OpCode::GetGlobal as u8);
+                short(code, *symbol);
+            }
+            Operation::DefineGlobal(symbol) => {
+                code.push(OpCode::DefineGlobal as u8);
+                short(code, *symbol);
+            }
+            Operation::SetGlobal(symbol) => {
+                code.push(OpCode::SetGlobal as u8);
+                short(code, *symbol);
+            }
+            Operation::GetLocal(slot) => {
+                code.push(OpCode::GetLocal as u8);
+                byte(code, *slot);
+            }
+            Operation::SetLocal(slot) => {
+                code.push(OpCode::SetLocal as u8);
+                byte(code, *slot);
+            }
+            Operation::GetUpvalue(index) => {
+                code.push(OpCode::GetUpvalue as u8);
+                byte(code, *index);
+            }
+            Operation::SetUpvalue(index) => {
+                code.push(OpCode::SetUpvalue as u8);
+                byte(code, *index);
+            }
+            Operation::Equal => code.push(OpCode::Equal as u8),
+            Operation::Greater => code.push(OpCode::Greater as u8),
+            Operation::Less => code.push(OpCode::Less as u8),
+            Operation::Add => code.push(OpCode::Add as u8),
+            Operation::Substract => code.push(OpCode::Substract as u8),
+            Operation::Multiply => code.push(OpCode::Multiply as u8),
+            Operation::Divide => code.push(OpCode::Divide as u8),
+            Operation::Not => code.push(OpCode::Not as u8),
+            Operation::Negate => code.push(OpCode::Negate as u8),
+            Operation::Print => code.push(OpCode::Print as u8),
+            Operation::JumpIfFalse(jump) => {
+                code.push(OpCode::JumpIfFalse as u8);
+                short(code, *jump);
+            }
+            Operation::Loop(jump) => {
+                code.push(OpCode::Loop as u8);
+                short(code, *jump);
+            }
+            Operation::Jump(jump) => {
+                code.push(OpCode::Jump as u8);
+                short(code, *jump);
+            }
+            Operation::Call(arg_count) => {
+                code.push(OpCode::Call as u8);
+                code.push(*arg_count);
+            }
+            Operation::Closure(function, upvalues) => {
+                code.push(OpCode::Closure as u8);
+                short(code, *function);
+                byte(code, upvalues.len());
+                for upvalue in upvalues {
+                    code.push(upvalue.is_local as u8);
+                    byte(code, upvalue.index);
+                }
+            }
+            Operation::CloseUpvalue => code.push(OpCode::CloseUpvalue as u8),
+            Operation::Class(name) => {
+                code.push(OpCode::Class as u8);
+                short(code, *name);
+            }
+            Operation::GetProperty(name) => {
+                code.push(OpCode::GetProperty as u8);
+                short(code, *name);
+            }
+            Operation::SetProperty(name) => {
+                code.push(OpCode::SetProperty as u8);
+                short(code, *name);
+            }
+            Operation::Method(name) => {
+                code.push(OpCode::Method as u8);
+                short(code, *name);
+            }
+            Operation::Invoke(name, arg_count) => {
+                code.push(OpCode::Invoke as u8);
+                short(code, *name);
+                code.push(*arg_count);
+            }
+            Operation::Inherit => code.push(OpCode::Inherit as u8),
+            Operation::GetSuper(name) => {
+                code.push(OpCode::GetSuper as u8);
+                short(code, *name);
+            }
+            Operation::SuperInvoke(name, arg_count) => {
+                code.push(OpCode::SuperInvoke as u8);
+                short(code, *name);
+                code.push(*arg_count);
+            }
+            Operation::Return => code.push(OpCode::Return as u8),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// A local variable of a function, so debuggers can show it by name. It's in `slot` of the
+/// function's frame while the code from `start` up to `end` runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalVariable {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Code of a function, encoded as bytes: each operation is an [`OpCode`] followed by its
+/// operands, stored big-endian.
+#[derive(Debug, Clone)]
 pub struct Chunk {
-    pub code: Vec<Operation>,
+    pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    lines: Vec<u32>,
+    // Source location of each byte in `code`
+    locations: Vec<SourceLocation>,
+    local_variables: Vec<LocalVariable>,
+}
+
+// Locations are just debug information, two chunks doing the same are equal
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.code == other.code && self.constants == other.constants
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
@@ -80,13 +355,24 @@ impl Chunk {
         Chunk {
             code: vec![],
             constants: vec![],
-            lines: vec![],
+            locations: vec![],
+            local_variables: vec![],
         }
     }
 
-    pub fn write(&mut self, op: Operation, line: u32) {
-        self.code.push(op);
-        self.lines.push(line);
+    /// Chunk made of already encoded code, with the source location of each of its bytes.
+    pub fn from_parts(code: Vec<u8>, constants: Vec<Value>, locations: Vec<SourceLocation>) -> Self {
+        Chunk {
+            code,
+            constants,
+            locations,
+            local_variables: vec![],
+        }
+    }
+
+    pub fn write(&mut self, op: Operation, location: SourceLocation) {
+        op.encode(&mut self.code);
+        self.locations.resize(self.code.len(), location);
     }
 
     pub fn add_constant(&mut self, value: Value) -> IdentifierId {
@@ -94,25 +380,19 @@ impl Chunk {
         self.constants.len() - 1
     }
 
-    pub fn read_constant(&self, coffset: usize) -> &Value {
-        &self.constants[coffset]
+    #[inline]
+    pub fn read_constant(&self, coffset: usize) -> Option<&Value> {
+        self.constants.get(coffset)
     }
 
-    // pub fn disassemble(&self, name: &str) {
-    //     println!("== {} ==", name);
-    //     let mut offset: usize = 0;
-    //     for op in &self.code {
-    //         op.disassemble(self, offset);
-    //         offset += 1;
-    //     }
-    // }
-
     pub fn emit(&mut self, op: Operation) {
-        self.code.push(op);
+        self.write(op, SourceLocation::default());
     }
 
     pub fn emit_many(&mut self, ops: &mut Vec<Operation>) {
-        self.code.append(ops);
+        for op in ops.drain(..) {
+            self.emit(op);
+        }
     }
 
     pub fn emit_constant(&mut self, val: Value) {
@@ -120,20 +400,246 @@ impl Chunk {
         self.emit(Operation::Constant(constant));
     }
 
-    /// Get a reference to the chunk's code.
-    pub fn code(&self) -> &[Operation] {
-        self.code.as_ref()
+    /// Source location of the operation at `offset`, unknown if there is no such operation.
+    pub fn location(&self, offset: usize) -> SourceLocation {
+        self.locations.get(offset).copied().unwrap_or_default()
+    }
+
+    pub fn line(&self, offset: usize) -> u32 {
+        self.location(offset).line
+    }
+
+    /// Source location of every byte of the code.
+    pub fn locations(&self) -> &[SourceLocation] {
+        &self.locations
+    }
+
+    pub fn add_local_variable(&mut self, variable: LocalVariable) {
+        self.local_variables.push(variable);
+    }
+
+    pub fn local_variables(&self) -> &[LocalVariable] {
+        &self.local_variables
+    }
+
+    /// The local variables alive when the operation at `offset` runs, ordered by slot.
+    pub fn local_variables_at(&self, offset: usize) -> Vec<&LocalVariable> {
+        let mut alive: Vec<&LocalVariable> = self
+            .local_variables
+            .iter()
+            .filter(|variable| variable.start <= offset && offset < variable.end)
+            .collect();
+        alive.sort_by_key(|variable| variable.slot);
+        alive
     }
 
-    pub fn op_count(&self) -> usize {
+    /// Size of the code, in bytes.
+    pub fn len(&self) -> usize {
         self.code.len()
     }
 
-    pub fn op_get(&self, offset: usize) -> Option<&Operation> {
-        self.code.get(offset)
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    #[inline]
+    pub fn read_byte(&self, offset: usize) -> Option<u8> {
+        self.code.get(offset).copied()
+    }
+
+    #[inline]
+    pub fn read_short(&self, offset: usize) -> Option<u16> {
+        let bytes = self.code.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    #[inline]
+    pub fn read_long(&self, offset: usize) -> Option<u32> {
+        let bytes = self.code.get(offset..offset + 3)?;
+        Some(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
     }
 
-    pub fn op_patch(&mut self, op_offset: usize, new_op: Operation) {
-        self.code[op_offset] = new_op;
+    /// Decodes the operation starting at `offset`, along with the offset of the next one.
+    /// Returns `None` if there is no valid operation there.
+    pub fn decode(&self, offset: usize) -> Option<(Operation, usize)> {
+        let opcode = OpCode::from_byte(self.read_byte(offset)?)?;
+        let operand = offset + 1;
+        let byte = || self.read_byte(operand).map(usize::from);
+        let short = || self.read_short(operand).map(usize::from);
+
+        let decoded = match opcode {
+            OpCode::Constant => (Operation::Constant(byte()?), operand + 1),
+            OpCode::ConstantLong => {
+                let index = self.read_long(operand)? as usize;
+                (Operation::Constant(index), operand + 3)
+            }
+            OpCode::Nil => (Operation::Nil, operand),
+            OpCode::True => (Operation::True, operand),
+            OpCode::False => (Operation::False, operand),
+            OpCode::Pop => (Operation::Pop, operand),
+            OpCode::GetGlobal => (Operation::GetGlobal(short()?), operand + 2),
+            OpCode::DefineGlobal => (Operation::DefineGlobal(short()?), operand + 2),
+            OpCode::SetGlobal => (Operation::SetGlobal(short()?), operand + 2),
+            OpCode::GetLocal => (Operation::GetLocal(byte()?), operand + 1),
+            OpCode::SetLocal => (Operation::SetLocal(byte()?), operand + 1),
+            OpCode::GetUpvalue => (Operation::GetUpvalue(byte()?), operand + 1),
+            OpCode::SetUpvalue => (Operation::SetUpvalue(byte()?), operand + 1),
+            OpCode::Equal => (Operation::Equal, operand),
+            OpCode::Greater => (Operation::Greater, operand),
+            OpCode::Less => (Operation::Less, operand),
+            OpCode::Add => (Operation::Add, operand),
+            OpCode::Substract => (Operation::Substract, operand),
+            OpCode::Multiply => (Operation::Multiply, operand),
+            OpCode::Divide => (Operation::Divide, operand),
+            OpCode::Not => (Operation::Not, operand),
+            OpCode::Negate => (Operation::Negate, operand),
+            OpCode::Print => (Operation::Print, operand),
+            OpCode::JumpIfFalse => (Operation::JumpIfFalse(short()?), operand + 2),
+            OpCode::Loop => (Operation::Loop(short()?), operand + 2),
+            OpCode::Jump => (Operation::Jump(short()?), operand + 2),
+            OpCode::Call => (Operation::Call(self.read_byte(operand)?), operand + 1),
+            OpCode::Closure => {
+                let function = short()?;
+                let count = self.read_byte(operand + 2)? as usize;
+                let upvalues = (0..count)
+                    .map(|i| {
+                        let at = operand + 3 + i * 2;
+                        Some(Upvalue {
+                            is_local: self.read_byte(at)? != 0,
+                            index: self.read_byte(at + 1)? as usize,
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                (Operation::Closure(function, upvalues), operand + 3 + count * 2)
+            }
+            OpCode::CloseUpvalue => (Operation::CloseUpvalue, operand),
+            OpCode::Class => (Operation::Class(short()?), operand + 2),
+            OpCode::GetProperty => (Operation::GetProperty(short()?), operand + 2),
+            OpCode::SetProperty => (Operation::SetProperty(short()?), operand + 2),
+            OpCode::Method => (Operation::Method(short()?), operand + 2),
+            OpCode::Invoke => {
+                let arg_count = self.read_byte(operand + 2)?;
+                (Operation::Invoke(short()?, arg_count), operand + 3)
+            }
+            OpCode::Inherit => (Operation::Inherit, operand),
+            OpCode::GetSuper => (Operation::GetSuper(short()?), operand + 2),
+            OpCode::SuperInvoke => {
+                let arg_count = self.read_byte(operand + 2)?;
+                (Operation::SuperInvoke(short()?, arg_count), operand + 3)
+            }
+            OpCode::Return => (Operation::Return, operand),
+        };
+        Some(decoded)
+    }
+
+    /// Every operation in the chunk along with its offset, stopping at the first one that
+    /// can't be decoded.
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, Operation)> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let (op, next) = self.decode(offset)?;
+            let current = offset;
+            offset = next;
+            Some((current, op))
+        })
+    }
+
+    /// Typed view of the whole code, mostly useful on tests.
+    pub fn operations(&self) -> Vec<Operation> {
+        self.instructions().map(|(_, op)| op).collect()
+    }
+
+    /// Points the jump at `offset`, written with a placeholder, `jump` bytes forward.
+    pub fn patch_jump(&mut self, offset: usize, jump: u16) {
+        self.code[offset + 1..offset + 3].copy_from_slice(&jump.to_be_bytes());
+    }
+
+    /// Replaces the symbol of every operation on a global variable, for code moving between
+    /// interners. Fails if `remap` does, or if the code can't be decoded.
+    pub fn remap_globals<F>(&mut self, mut remap: F) -> Option<()>
+    where
+        F: FnMut(SymbolId) -> Option<SymbolId>,
+    {
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let (op, next) = self.decode(offset)?;
+            if let Operation::GetGlobal(symbol)
+            | Operation::DefineGlobal(symbol)
+            | Operation::SetGlobal(symbol) = op
+            {
+                let symbol = u16::try_from(remap(symbol)?).ok()?;
+                self.code[offset + 1..offset + 3].copy_from_slice(&symbol.to_be_bytes());
+            }
+            offset = next;
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunk, Operation, Upvalue};
+
+    #[test]
+    fn encoding_round_trips() {
+        let operations = vec![
+            Operation::Constant(3),
+            Operation::Constant(70_000),
+            Operation::GetGlobal(300),
+            Operation::GetLocal(255),
+            Operation::JumpIfFalse(1024),
+            Operation::Closure(
+                2,
+                vec![
+                    Upvalue { is_local: true, index: 1 },
+                    Upvalue { is_local: false, index: 0 },
+                ],
+            ),
+            Operation::Invoke(1, 3),
+            Operation::Return,
+        ];
+        let mut chunk = Chunk::new();
+        chunk.emit_many(&mut operations.clone());
+
+        assert_eq!(chunk.operations(), operations);
+        // 2 + 4 + 3 + 2 + 3 + (4 + 2 * 2) + 4 + 1
+        assert_eq!(chunk.len(), 27);
+    }
+
+    #[test]
+    fn invalid_code_is_not_decoded() {
+        let mut chunk = Chunk::new();
+        chunk.emit(Operation::Nil);
+        chunk.code.push(200);
+        chunk.code.push(0); // Constant without its operand
+
+        assert_eq!(chunk.decode(1), None);
+        assert_eq!(chunk.decode(2), None);
+        assert_eq!(chunk.operations(), vec![Operation::Nil]);
+    }
+
+    #[test]
+    fn jumps_are_patched() {
+        let mut chunk = Chunk::new();
+        chunk.emit(Operation::Jump(0));
+        chunk.emit(Operation::Nil);
+        chunk.patch_jump(0, 1);
+
+        assert_eq!(chunk.operations(), vec![Operation::Jump(1), Operation::Nil]);
+    }
+
+    #[test]
+    fn globals_are_remapped() {
+        let mut chunk = Chunk::new();
+        chunk.emit(Operation::GetGlobal(0));
+        chunk.emit(Operation::Nil);
+        chunk.emit(Operation::SetGlobal(1));
+
+        assert_eq!(chunk.remap_globals(|symbol| Some(symbol + 10)), Some(()));
+        assert_eq!(
+            chunk.operations(),
+            vec![Operation::GetGlobal(10), Operation::Nil, Operation::SetGlobal(11)]
+        );
+        assert_eq!(chunk.remap_globals(|_| None), None);
     }
 }
@@ -1,11 +1,16 @@
-use core::panic;
 use std::rc::Rc;
 
 use crate::{
-    chunk::{IdentifierName, LocalVarIndex, Operation},
-    object::{ObjFunction, ObjString},
+    chunk::{
+        IdentifierId, IdentifierName, LocalVarIndex, LocalVariable, Operation, SourceLocation,
+        Upvalue, UpvalueIndex, MAX_BYTE_OPERAND, MAX_CONSTANTS, MAX_SHORT_OPERAND,
+    },
+    diagnostic::{Diagnostic, Severity, Span},
+    interner::Interner,
+    object::ObjFunction,
     scanner::Scanner,
-    token::{TokenResult, TokenType},
+    symbols::{Declaration, SymbolIndex, SymbolKind},
+    token::{Token, TokenResult, TokenType},
     value::Value,
 };
 
@@ -59,7 +64,7 @@ impl Precedence {
             8 => Precedence::Unary,
             9 => Precedence::Call,
             10 => Precedence::Primary,
-            _ => panic!("Unrecognized order {}", order),
+            _ => Precedence::Primary,
         }
     }
 }
@@ -67,44 +72,123 @@ impl Precedence {
 #[derive(Debug)]
 struct Local {
     pub name: String,
-    pub depth: i8,
+    pub depth: i32,
+    pub is_captured: bool,
+    // Offset of the code from where it's initialized, for the debug information
+    pub start: usize,
+    // In the symbol index, unless the compiler made it up
+    pub declaration: Option<usize>,
 }
 
+#[derive(Debug, PartialEq)]
 enum FunctionType {
     Function,
+    Initializer,
+    Method,
     Script,
 }
 
+/// State of the function currently being compiled. Each nested function declaration pushes a
+/// new one so its locals and upvalues are resolved relative to its own frame.
+#[derive(Debug)]
+struct FunctionContext {
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: i32,
+}
+
+impl FunctionContext {
+    fn new(function_type: FunctionType) -> Self {
+        let mut locals = vec![];
+        if function_type == FunctionType::Method || function_type == FunctionType::Initializer {
+            // Methods get the receiver on the first slot
+            locals.push(Local {
+                name: String::from("this"),
+                depth: 0,
+                is_captured: false,
+                start: 0,
+                declaration: None,
+            });
+        }
+
+        FunctionContext {
+            function_type,
+            locals,
+            upvalues: vec![],
+            scope_depth: 0,
+        }
+    }
+}
+
+/// Class whose body is currently being compiled, used to validate the usages of `this` and
+/// `super`.
+#[derive(Debug)]
+struct ClassContext {
+    has_superclass: bool,
+}
+
+/// How deep declarations and expressions can nest inside each other.
+const MAX_NESTING: usize = 256;
+
+/// Bytes taken by a jump operation, along with its operand.
+const JUMP_SIZE: usize = 3;
+
 #[derive(Debug)]
 pub struct Compiler<'a> {
-    pub had_error: bool,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
+    // How deep the code being compiled is, see `enter_nesting`
+    nesting: usize,
+    // Whether the code nested too deep, and the rest of it was skipped
+    gave_up: bool,
 
     scanner: Scanner<'a>,
 
     previous: TokenResult<'a>,
     current: TokenResult<'a>,
 
-    locals: Vec<Local>,
-    scope_depth: i8,
+    contexts: Vec<FunctionContext>,
+    classes: Vec<ClassContext>,
+
+    symbols: SymbolIndex,
+    // Globals can be used before being declared, so they are resolved once the script is done
+    global_uses: Vec<(String, Span)>,
+    // Functions and classes being declared, innermost last
+    declaring: Vec<usize>,
+
+    // Shared with the VM that runs the result
+    interner: &'a mut Interner,
 }
 
 impl<'a> Compiler<'a> {
-    pub fn from_source(source: &'a String) -> Compiler<'a> {
+    /// Compiler for `source`. Strings and global names are interned into `interner`, which must
+    /// be the one of the VM running the compiled code, see [`crate::vm::VM::interner_mut`].
+    pub fn from_source(source: &'a String, interner: &'a mut Interner) -> Compiler<'a> {
         Compiler {
-            had_error: false,
+            diagnostics: vec![],
             panic_mode: false,
+            nesting: 0,
+            gave_up: false,
 
             scanner: Scanner::new(source),
             previous: TokenResult::invalid(),
             current: TokenResult::invalid(),
 
-            locals: vec![],
-            scope_depth: 0,
+            contexts: vec![FunctionContext::new(FunctionType::Script)],
+            classes: vec![],
+
+            symbols: SymbolIndex::default(),
+            global_uses: vec![],
+            declaring: vec![],
+
+            interner,
         }
     }
 
-    pub fn compile(&mut self) -> ObjFunction {
+    /// Compiles the whole source into the function for the top-level script. On failure,
+    /// returns every problem found, as the compiler recovers from errors to keep looking.
+    pub fn compile(&mut self) -> Result<ObjFunction, Vec<Diagnostic>> {
         let mut frame = ObjFunction::new("main");
         self.advance();
 
@@ -113,13 +197,37 @@ impl<'a> Compiler<'a> {
         }
 
         self.emit_return(&mut frame);
+        for (name, span) in std::mem::take(&mut self.global_uses) {
+            if let Some(declaration) = self.symbols.global(&name) {
+                self.symbols.refer(span, declaration);
+            }
+        }
 
-        #[cfg(feature = "debug_print_code")]
-        if !ret.had_error {
-            ret.chunk.disassemble("code");
+        let had_error = self
+            .diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error);
+        if had_error {
+            Err(std::mem::take(&mut self.diagnostics))
+        } else {
+            Ok(frame)
         }
+    }
 
-        frame
+    /// Where the names in the source are declared and used, once compiled. It's there even if
+    /// the source had errors, as far as the compiler got to understand it.
+    pub fn symbols(&self) -> &SymbolIndex {
+        &self.symbols
+    }
+
+    fn context(&self) -> &FunctionContext {
+        self.contexts.last().expect("There is always a function being compiled")
+    }
+
+    fn context_mut(&mut self) -> &mut FunctionContext {
+        self.contexts
+            .last_mut()
+            .expect("There is always a function being compiled")
     }
 
     fn advance(&mut self) {
@@ -129,7 +237,7 @@ impl<'a> Compiler<'a> {
             // TODO: see how can I remove this clone()
             match &self.current.data.clone() {
                 Ok(_) => break,
-                Err(message) => self.error_at_current(&message),
+                Err(message) => self.error_at_current(message),
             }
         }
     }
@@ -139,9 +247,10 @@ impl<'a> Compiler<'a> {
     }
 
     fn number(&mut self, frame: &mut ObjFunction) {
-        let token_data = self.previous.data.as_ref().unwrap();
-        let val = token_data.lexeme.parse::<f64>().unwrap();
-        frame.chunk.emit_constant(Value::Number(val));
+        match self.previous_lexeme().parse::<f64>() {
+            Ok(val) => self.emit_constant(Value::Number(val), frame),
+            Err(_) => self.error("Invalid number."),
+        }
     }
 
     fn grouping(&mut self, frame: &mut ObjFunction) {
@@ -151,63 +260,70 @@ impl<'a> Compiler<'a> {
 
     fn unary(&mut self, frame: &mut ObjFunction) {
         let operator_type = self.previous.token_type;
+        let location = self.previous_location();
 
         self.parse_precedence(&Precedence::Unary, frame);
 
         match operator_type {
-            TokenType::Minus => frame.chunk.emit(Operation::Negate),
-            TokenType::Bang => frame.chunk.emit(Operation::Not),
-            _ => todo!(),
+            TokenType::Minus => self.emit_at(Operation::Negate, location, frame),
+            TokenType::Bang => self.emit_at(Operation::Not, location, frame),
+            _ => self.error("Expect unary operator."),
         }
     }
 
     fn binary(&mut self, frame: &mut ObjFunction) {
         let operator_type = self.previous.token_type;
+        let location = self.previous_location();
 
         let next_precedence = Compiler::get_precedence(operator_type).next();
         self.parse_precedence(&next_precedence, frame);
 
         match operator_type {
             TokenType::BangEqual => {
-                frame.chunk.emit(Operation::Equal);
-                frame.chunk.emit(Operation::Not);
+                self.emit_at(Operation::Equal, location, frame);
+                self.emit_at(Operation::Not, location, frame);
             }
-            TokenType::EqualEqual => frame.chunk.emit(Operation::Equal),
-            TokenType::Greater => frame.chunk.emit(Operation::Greater),
+            TokenType::EqualEqual => self.emit_at(Operation::Equal, location, frame),
+            TokenType::Greater => self.emit_at(Operation::Greater, location, frame),
             TokenType::GreaterEqual => {
-                frame.chunk.emit(Operation::Less);
-                frame.chunk.emit(Operation::Not);
+                self.emit_at(Operation::Less, location, frame);
+                self.emit_at(Operation::Not, location, frame);
             }
-            TokenType::Less => frame.chunk.emit(Operation::Less),
+            TokenType::Less => self.emit_at(Operation::Less, location, frame),
             TokenType::LessEqual => {
-                frame.chunk.emit(Operation::Greater);
-                frame.chunk.emit(Operation::Not);
+                self.emit_at(Operation::Greater, location, frame);
+                self.emit_at(Operation::Not, location, frame);
             }
-            TokenType::Plus => frame.chunk.emit(Operation::Add),
-            TokenType::Minus => frame.chunk.emit(Operation::Substract),
-            TokenType::Star => frame.chunk.emit(Operation::Multiply),
-            TokenType::Slash => frame.chunk.emit(Operation::Divide),
-            _ => todo!(),
+            TokenType::Plus => self.emit_at(Operation::Add, location, frame),
+            TokenType::Minus => self.emit_at(Operation::Substract, location, frame),
+            TokenType::Star => self.emit_at(Operation::Multiply, location, frame),
+            TokenType::Slash => self.emit_at(Operation::Divide, location, frame),
+            _ => self.error("Expect binary operator."),
         }
     }
 
     fn literal(&mut self, frame: &mut ObjFunction) {
         match self.previous.token_type {
-            TokenType::True => frame.chunk.emit(Operation::True),
-            TokenType::False => frame.chunk.emit(Operation::False),
-            TokenType::Nil => frame.chunk.emit(Operation::Nil),
-            tt => panic!("Expected a literal, found {:?}", tt),
+            TokenType::True => self.emit(Operation::True, frame),
+            TokenType::False => self.emit(Operation::False, frame),
+            TokenType::Nil => self.emit(Operation::Nil, frame),
+            _ => self.error("Expect literal."),
         }
     }
 
     fn string(&mut self, frame: &mut ObjFunction) {
-        let s = self.previous.data.clone().unwrap().lexeme;
-        let obj_str = ObjString::from(s);
-        frame.chunk.emit_constant(Value::String(Rc::from(obj_str)));
+        let string = self.interner.intern(self.previous_lexeme());
+        self.emit_constant(Value::String(string), frame);
     }
 
     fn declaration(&mut self, frame: &mut ObjFunction) {
-        if self.matches(TokenType::Fun) {
+        if !self.enter_nesting() {
+            return;
+        }
+
+        if self.matches(TokenType::Class) {
+            self.class_declaration(frame);
+        } else if self.matches(TokenType::Fun) {
             self.fun_declaration(frame);
         } else if self.matches(TokenType::Var) {
             self.var_declaration(frame);
@@ -218,6 +334,7 @@ impl<'a> Compiler<'a> {
         if self.panic_mode {
             self.synchronize();
         }
+        self.nesting -= 1;
     }
 
     fn statement(&mut self, frame: &mut ObjFunction) {
@@ -232,9 +349,9 @@ impl<'a> Compiler<'a> {
         } else if self.matches(TokenType::For) {
             self.for_statement(frame);
         } else if self.matches(TokenType::LeftBrace) {
-            self.begin_scope(frame);
+            self.begin_scope();
             self.block(frame);
-            self.end_scope(frame, true);
+            self.end_scope(frame);
         } else {
             self.expression_statement(frame);
         }
@@ -243,17 +360,17 @@ impl<'a> Compiler<'a> {
     fn expression_statement(&mut self, frame: &mut ObjFunction) {
         self.expression(frame);
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
-        frame.chunk.emit(Operation::Pop);
+        self.emit(Operation::Pop, frame);
     }
 
     fn print_statement(&mut self, frame: &mut ObjFunction) {
         self.expression(frame);
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        frame.chunk.emit(Operation::Print);
+        self.emit(Operation::Print, frame);
     }
 
     fn var_declaration(&mut self, frame: &mut ObjFunction) {
-        if self.scope_depth == 0 {
+        if self.context().scope_depth == 0 {
             self.global_var_declaration(frame);
         } else {
             self.local_var_declaration(frame);
@@ -263,7 +380,8 @@ impl<'a> Compiler<'a> {
     fn global_var_declaration(&mut self, frame: &mut ObjFunction) {
         self.parse_variable("Expect variable name.");
         // TODO: see how can I remove this clone()
-        let name = self.previous.clone().data.unwrap().lexeme.to_string();
+        let name = self.previous_lexeme().to_string();
+        self.declare(SymbolKind::Variable);
 
         self.variable_expression(frame);
 
@@ -276,16 +394,21 @@ impl<'a> Compiler<'a> {
 
     fn parse_variable(&mut self, error_message: &str) -> IdentifierName {
         self.consume(TokenType::Identifier, error_message);
-        self.previous.data.as_ref().unwrap().lexeme.to_string()
+        self.previous_lexeme().to_string()
     }
 
     fn local_var_declaration(&mut self, frame: &mut ObjFunction) {
         self.consume(TokenType::Identifier, "Expect variable name.");
         // TODO: see how can I remove this clone()
-        let name = self.previous.clone().data.unwrap().lexeme.to_string();
+        let name = self.previous_lexeme().to_string();
 
+        // Declared before the initializer, which can't read it until it's done
+        let declaration = self.declare(SymbolKind::Variable);
+        if !self.declare_local(name, declaration) {
+            return;
+        }
         self.variable_expression(frame);
-        self.declare_local(name);
+        self.mark_initialized(frame);
         self.consume(
             TokenType::Semicolon,
             "Expect ';' after variable declaration.",
@@ -296,51 +419,67 @@ impl<'a> Compiler<'a> {
         if self.matches(TokenType::Equal) {
             self.expression(frame);
         } else {
-            frame.chunk.emit(Operation::Nil);
+            self.emit(Operation::Nil, frame);
         }
     }
 
     fn define_variable(&mut self, name: IdentifierName, frame: &mut ObjFunction) {
-        if self.scope_depth > 0 {
-            self.mark_initialized();
+        if self.context().scope_depth > 0 {
+            self.mark_initialized(frame);
         } else {
-            frame.chunk.emit(Operation::DefineGlobal(name));
+            let symbol = self.global_symbol(&name);
+            self.emit(Operation::DefineGlobal(symbol), frame);
         }
     }
 
-    fn declare_local(&mut self, name: IdentifierName) {
+    /// Adds a local to the current scope, returning whether it got a slot. If not, the error was
+    /// reported and no code should refer to it.
+    fn declare_local(&mut self, name: IdentifierName, declaration: Option<usize>) -> bool {
         self.validate_local(&name);
+        if self.context().locals.len() > MAX_BYTE_OPERAND {
+            self.error("Too many local variables in function.");
+            return false;
+        }
 
+        let context = self.context_mut();
         let local = Local {
             name,
-            depth: self.scope_depth,
+            // Not initialized yet, see `mark_initialized`
+            depth: -1,
+            is_captured: false,
+            start: 0,
+            declaration,
         };
 
-        self.locals.push(local);
+        context.locals.push(local);
+        true
     }
 
     fn variable(&mut self, can_assign: bool, frame: &mut ObjFunction) {
-        let name = self.previous.clone().data.unwrap().lexeme.to_string();
+        let name = self.previous_lexeme().to_string();
+        self.refer(&name);
         self.named_variable(name, can_assign, frame);
     }
 
     fn named_variable(&mut self, name: String, can_assign: bool, frame: &mut ObjFunction) {
-        // TODO: clean up this chain of ifs without creating operations are are not going to be used.
-        if let Some(i) = self.resolve_local(&name, frame) {
-            // self.emit_variable(Operation::GetLocal(i), Operation::SetLocal(i), can_assign);
-            if can_assign && self.matches(TokenType::Equal) {
-                self.expression(frame);
-                frame.chunk.emit(Operation::SetLocal(i));
-            } else {
-                frame.chunk.emit(Operation::GetLocal(i));
+        let current = self.contexts.len() - 1;
+        let (get_op, set_op) = if let Some(i) = self.resolve_local(current, &name) {
+            if self.contexts[current].locals[i].depth == -1 {
+                self.error("Can't read local variable in its own initializer.");
             }
+            (Operation::GetLocal(i), Operation::SetLocal(i))
+        } else if let Some(i) = self.resolve_upvalue(current, &name) {
+            (Operation::GetUpvalue(i), Operation::SetUpvalue(i))
         } else {
-            if can_assign && self.matches(TokenType::Equal) {
-                self.expression(frame);
-                frame.chunk.emit(Operation::SetGlobal(name));
-            } else {
-                frame.chunk.emit(Operation::GetGlobal(name));
-            }
+            let symbol = self.global_symbol(&name);
+            (Operation::GetGlobal(symbol), Operation::SetGlobal(symbol))
+        };
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression(frame);
+            self.emit(set_op, frame);
+        } else {
+            self.emit(get_op, frame);
         }
     }
 
@@ -368,25 +507,52 @@ impl<'a> Compiler<'a> {
     }
 
     fn error_at_current(&mut self, message: &str) {
-        self.error_at(self.current.line, message);
+        // The end of the file can be lines below, what's missing goes right after the last token
+        if self.current.token_type == TokenType::Eof {
+            if let Ok(last) = &self.previous.data {
+                let mut at_end = self.current.clone();
+                at_end.line = self.previous.line;
+                at_end.column = self.previous.column + (last.end - last.start) as i32;
+                at_end.data = Ok(Token {
+                    start: last.end,
+                    end: last.end,
+                    lexeme: "",
+                });
+                self.error_at(at_end, message);
+                return;
+            }
+        }
+        self.error_at(self.current.clone(), message);
+    }
+
+    fn error(&mut self, message: &str) {
+        self.error_at(self.previous.clone(), message);
     }
 
-    fn error_at(&mut self, line: i32, message: &str) {
-        if !self.panic_mode {
-            self.panic_mode = true;
-            println!(
-                "
-[line {}] Error: {}
-Compiler state: {:#?}
-",
-                line, message, self
-            );
-            self.had_error = true;
+    fn error_at(&mut self, token: TokenResult, message: &str) {
+        // Avoid a cascade of errors until the parser gets back in sync
+        if self.panic_mode {
+            return;
         }
+        self.panic_mode = true;
+
+        let span = match &token.data {
+            Ok(data) => Span::new(data.start, data.end),
+            // Error tokens are reported right after being scanned
+            Err(_) => self.scanner.token_span(),
+        };
+        let location = SourceLocation::new(token.line, token.column);
+        self.diagnostics.push(Diagnostic::error(
+            message,
+            location.line,
+            location.column,
+            span,
+        ));
     }
 
     fn synchronize(&mut self) {
-        self.panic_mode = false;
+        // Every enclosing declaration would fail once the rest of the code is skipped
+        self.panic_mode = self.gave_up;
 
         while self.current.token_type != TokenType::Eof {
             if self.previous.token_type == TokenType::Semicolon {
@@ -412,6 +578,9 @@ Compiler state: {:#?}
     }
 
     fn parse_precedence(&mut self, precedence: &Precedence, frame: &mut ObjFunction) {
+        if !self.enter_nesting() {
+            return;
+        }
         self.advance();
 
         let can_assign = *precedence <= Precedence::Assignment;
@@ -420,8 +589,29 @@ Compiler state: {:#?}
         // println!("checking precedence {:?} <= {:?} == {:?}", precedence, &Compiler::get_precedence(self.current.token_type), precedence <= &Compiler::get_precedence(self.current.token_type));
         while precedence <= &Compiler::get_precedence(self.current.token_type) {
             self.advance();
-            self.infix_rule(self.previous.token_type, frame);
+            self.infix_rule(self.previous.token_type, can_assign, frame);
         }
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+        self.nesting -= 1;
+    }
+
+    /// Keeps track of how deep the declarations and expressions being compiled are, failing
+    /// when they are too deep to be compiled without overflowing the stack. The rest of the
+    /// code is skipped then, as there's no telling where the nesting ends.
+    fn enter_nesting(&mut self) -> bool {
+        if self.nesting >= MAX_NESTING {
+            self.error_at_current("Too much nesting.");
+            self.gave_up = true;
+            while self.current.token_type != TokenType::Eof {
+                self.advance();
+            }
+            return false;
+        }
+        self.nesting += 1;
+        true
     }
 
     fn prefix_rule(&mut self, operator_type: TokenType, can_assign: bool, frame: &mut ObjFunction) {
@@ -435,11 +625,13 @@ Compiler state: {:#?}
             TokenType::Bang => self.unary(frame),
             TokenType::String => self.string(frame),
             TokenType::Identifier => self.variable(can_assign, frame),
-            tt => panic!("Expected expresion, got {:?}", tt),
+            TokenType::This => self.this(frame),
+            TokenType::Super => self.super_(frame),
+            _ => self.error("Expect expression."),
         }
     }
 
-    fn infix_rule(&mut self, operator_type: TokenType, frame: &mut ObjFunction) {
+    fn infix_rule(&mut self, operator_type: TokenType, can_assign: bool, frame: &mut ObjFunction) {
         match operator_type {
             TokenType::Minus => self.binary(frame),
             TokenType::Plus => self.binary(frame),
@@ -454,6 +646,7 @@ Compiler state: {:#?}
             TokenType::And => self.and(frame),
             TokenType::Or => self.or(frame),
             TokenType::LeftParen => self.call(frame),
+            TokenType::Dot => self.dot(can_assign, frame),
             _ => (), //panic!("Expect expresion"),
         }
     }
@@ -473,6 +666,7 @@ Compiler state: {:#?}
             TokenType::And => Precedence::And,
             TokenType::Or => Precedence::Or,
             TokenType::LeftParen => Precedence::Call,
+            TokenType::Dot => Precedence::Call,
             _ => Precedence::None,
         }
     }
@@ -485,43 +679,83 @@ Compiler state: {:#?}
         self.consume(TokenType::RightBrace, "Expect '}' after block.");
     }
 
-    fn begin_scope(&mut self, frame: &mut ObjFunction) {
-        self.scope_depth += 1;
+    fn begin_scope(&mut self) {
+        self.context_mut().scope_depth += 1;
     }
 
-    fn end_scope(&mut self, frame: &mut ObjFunction, undefine_locals: bool) {
-        self.scope_depth -= 1;
+    fn end_scope(&mut self, frame: &mut ObjFunction) {
+        let location = self.previous_location();
+        let context = self.context_mut();
+        context.scope_depth -= 1;
 
-        if undefine_locals {
-            while self.locals.len() > 0 && self.locals.last().unwrap().depth > self.scope_depth {
-                self.locals.pop();
-                frame.chunk.emit(Operation::Pop);
+        while let Some(local) = context.locals.last() {
+            if local.depth <= context.scope_depth {
+                break;
             }
+            let op = if local.is_captured {
+                Operation::CloseUpvalue
+            } else {
+                Operation::Pop
+            };
+            frame.chunk.write(op, location);
+            // Still there for the operation removing it
+            Self::add_local_variable(local, context.locals.len() - 1, frame);
+            context.locals.pop();
         }
     }
 
-    fn validate_local(&self, name: &String) {
-        for local in self.locals.iter().rev() {
-            if local.depth != -1 && local.depth < self.scope_depth {
+    fn validate_local(&mut self, name: &String) {
+        let context = self.context();
+        for local in context.locals.iter().rev() {
+            if local.depth != -1 && local.depth < context.scope_depth {
                 break;
             } else if local.name == *name {
-                panic!(
+                self.error(&format!(
                     "There is already a local variable called '{}' in this scope.",
                     name
-                );
+                ));
+                return;
             }
         }
     }
 
-    fn resolve_local(&self, name: &str, frame: &mut ObjFunction) -> Option<LocalVarIndex> {
-        let mut ret = None;
-        for (i, local) in self.locals.iter().rev().enumerate() {
-            if local.name == name {
-                ret = Some(i);
-                break;
-            }
+    fn resolve_local(&self, context: usize, name: &str) -> Option<LocalVarIndex> {
+        self.contexts[context]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name)
+    }
+
+    /// Looks for `name` in the functions enclosing the given one, capturing it on every
+    /// function in between so it can be reached once the enclosing frame has returned.
+    fn resolve_upvalue(&mut self, context: usize, name: &str) -> Option<UpvalueIndex> {
+        if context == 0 {
+            return None;
         }
-        ret
+
+        if let Some(local) = self.resolve_local(context - 1, name) {
+            self.contexts[context - 1].locals[local].is_captured = true;
+            return Some(self.add_upvalue(context, local, true));
+        }
+
+        self.resolve_upvalue(context - 1, name)
+            .map(|upvalue| self.add_upvalue(context, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, context: usize, index: usize, is_local: bool) -> UpvalueIndex {
+        let upvalues = &mut self.contexts[context].upvalues;
+        let upvalue = Upvalue { is_local, index };
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing;
+        }
+        // The amount of upvalues is encoded in a byte too
+        if upvalues.len() == MAX_BYTE_OPERAND {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        upvalues.push(upvalue);
+        upvalues.len() - 1
     }
 
     fn if_statement(&mut self, frame: &mut ObjFunction) {
@@ -530,12 +764,12 @@ Compiler state: {:#?}
         self.consume(TokenType::RightParen, "Expect ')' after 'if'.");
 
         let then_jump = self.emit_jump(Operation::JumpIfFalse(0), frame);
-        frame.chunk.emit(Operation::Pop);
+        self.emit(Operation::Pop, frame);
         self.statement(frame);
 
         let else_jump = self.emit_jump(Operation::Jump(0), frame);
         self.patch_jump(then_jump, frame);
-        frame.chunk.emit(Operation::Pop);
+        self.emit(Operation::Pop, frame);
 
         if self.matches(TokenType::Else) {
             self.statement(frame);
@@ -543,28 +777,26 @@ Compiler state: {:#?}
         self.patch_jump(else_jump, frame);
     }
 
+    /// Emits a jump to be patched once the code to jump over is compiled, returns its offset.
     fn emit_jump(&mut self, op: Operation, frame: &mut ObjFunction) -> usize {
-        frame.chunk.emit(op);
-        frame.chunk.op_count() - 1
+        let offset = frame.chunk.len();
+        self.emit(op, frame);
+        offset
     }
 
     fn patch_jump(&mut self, op_offset: usize, frame: &mut ObjFunction) {
-        let jump = frame.chunk.op_count() - 1 - op_offset;
-        let old_op = frame
-            .chunk
-            .op_get(op_offset)
-            .expect("Tried patching an unexisting operation");
-        let new_op = match old_op {
-            Operation::JumpIfFalse(_) => Operation::JumpIfFalse(jump),
-            Operation::Jump(_) => Operation::Jump(jump),
-            _ => panic!("Tried to patch_jump a non-jump operation"),
-        };
-        frame.chunk.op_patch(op_offset, new_op.clone());
+        // Jumps start counting after their own operand
+        let jump = frame.chunk.len() - op_offset - JUMP_SIZE;
+        if jump > MAX_SHORT_OPERAND {
+            self.error("Too much code to jump over.");
+            return;
+        }
+        frame.chunk.patch_jump(op_offset, jump as u16);
     }
 
     fn and(&mut self, frame: &mut ObjFunction) {
         let end_jump = self.emit_jump(Operation::JumpIfFalse(0), frame);
-        frame.chunk.emit(Operation::Pop);
+        self.emit(Operation::Pop, frame);
         self.parse_precedence(&Precedence::And, frame);
         self.patch_jump(end_jump, frame);
     }
@@ -574,34 +806,37 @@ Compiler state: {:#?}
         let end_jump = self.emit_jump(Operation::Jump(0), frame);
 
         self.patch_jump(else_jump, frame);
-        frame.chunk.emit(Operation::Pop);
+        self.emit(Operation::Pop, frame);
 
         self.parse_precedence(&Precedence::Or, frame);
         self.patch_jump(end_jump, frame);
     }
 
     fn while_statement(&mut self, frame: &mut ObjFunction) {
-        let loop_start = frame.chunk.op_count();
+        let loop_start = frame.chunk.len();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
         self.expression(frame);
         self.consume(TokenType::RightParen, "Expect ')' after 'condition'.");
 
         let exit_jump = self.emit_jump(Operation::JumpIfFalse(0), frame);
-        frame.chunk.emit(Operation::Pop);
+        self.emit(Operation::Pop, frame);
         self.statement(frame);
         self.emit_loop(loop_start, frame);
 
         self.patch_jump(exit_jump, frame);
-        frame.chunk.emit(Operation::Pop);
+        self.emit(Operation::Pop, frame);
     }
 
     fn emit_loop(&mut self, loop_start: usize, frame: &mut ObjFunction) {
-        let offset = frame.chunk.op_count() - loop_start + 1;
-        frame.chunk.emit(Operation::Loop(offset));
+        let offset = frame.chunk.len() + JUMP_SIZE - loop_start;
+        if offset > MAX_SHORT_OPERAND {
+            self.error("Loop body too large.");
+        }
+        self.emit(Operation::Loop(offset), frame);
     }
 
     fn for_statement(&mut self, frame: &mut ObjFunction) {
-        self.begin_scope(frame);
+        self.begin_scope();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
 
         // Initializer
@@ -614,7 +849,7 @@ Compiler state: {:#?}
         }
 
         // Conditional
-        let mut loop_start = frame.chunk.op_count();
+        let mut loop_start = frame.chunk.len();
         let mut exit_jump = None;
         if !self.matches(TokenType::Semicolon) {
             self.expression(frame);
@@ -622,16 +857,16 @@ Compiler state: {:#?}
 
             // Jump out of the loop if the condition is false
             exit_jump = Some(self.emit_jump(Operation::JumpIfFalse(0), frame));
-            frame.chunk.emit(Operation::Pop);
+            self.emit(Operation::Pop, frame);
         }
 
         // Increment
         if !self.matches(TokenType::RightParen) {
             let body_jump = self.emit_jump(Operation::Jump(0), frame);
-            let increment_start = frame.chunk.op_count();
+            let increment_start = frame.chunk.len();
             self.expression(frame);
 
-            frame.chunk.emit(Operation::Pop);
+            self.emit(Operation::Pop, frame);
             self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
 
             self.emit_loop(loop_start, frame);
@@ -644,41 +879,178 @@ Compiler state: {:#?}
 
         if let Some(offset) = exit_jump {
             self.patch_jump(offset, frame);
-            frame.chunk.emit(Operation::Pop);
+            self.emit(Operation::Pop, frame);
         }
 
-        self.end_scope(frame, true);
+        self.end_scope(frame);
     }
 
     fn fun_declaration(&mut self, frame: &mut ObjFunction) {
-        let global = self.parse_variable("Expect function name.");
-        // self.mark_initialized();
-        let new_frame = self.function(global.clone());
+        let name = self.parse_variable("Expect function name.");
+        let declaration = self.declare(SymbolKind::Function);
+        if self.context().scope_depth > 0 {
+            // Declared before compiling the body so the function can call itself
+            if !self.declare_local(name.clone(), declaration) {
+                return;
+            }
+            self.mark_initialized(frame);
+        }
+        self.function(name.clone(), FunctionType::Function, declaration, frame);
+        self.define_variable(name, frame);
+    }
+
+    fn class_declaration(&mut self, frame: &mut ObjFunction) {
+        let name = self.parse_variable("Expect class name.");
+        let declaration = self.declare(SymbolKind::Class);
+        if self.context().scope_depth > 0 && !self.declare_local(name.clone(), declaration) {
+            return;
+        }
+        let name_constant = self.identifier_constant(&name, frame);
+        self.emit(Operation::Class(name_constant), frame);
+        self.define_variable(name.clone(), frame);
+
+        self.classes.push(ClassContext {
+            has_superclass: false,
+        });
+
+        if self.matches(TokenType::Less) {
+            let superclass = self.parse_variable("Expect superclass name.");
+            self.refer(&superclass);
+            if superclass == name {
+                self.error("A class can't inherit from itself.");
+            }
+            self.named_variable(superclass, false, frame);
+
+            // The superclass lives in a local of its own scope, so methods capture it as 'super'
+            self.begin_scope();
+            if self.declare_local(String::from("super"), None) {
+                self.define_variable(String::from("super"), frame);
+            }
+
+            self.named_variable(name.clone(), false, frame);
+            self.emit(Operation::Inherit, frame);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // Leave the class on the stack so the methods can be bound to it
+        self.named_variable(name, false, frame);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        self.declaring.extend(declaration);
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method(frame);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        if let Some(declaration) = declaration {
+            self.declaring.pop();
+            self.end_declaration(declaration);
+        }
+        self.emit(Operation::Pop, frame);
+
+        if let Some(ClassContext {
+            has_superclass: true,
+        }) = self.classes.pop()
+        {
+            self.end_scope(frame);
+        }
+    }
+
+    fn method(&mut self, frame: &mut ObjFunction) {
+        let name = self.parse_variable("Expect method name.");
+        let declaration = self.declare(SymbolKind::Method);
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        let name_constant = self.identifier_constant(&name, frame);
+        self.function(name, function_type, declaration, frame);
+        self.emit(Operation::Method(name_constant), frame);
+    }
 
-        let function_value = Value::Function(Rc::from(new_frame));
-        let constant = frame.chunk.add_constant(function_value);
-        frame.chunk.emit(Operation::Constant(constant));
+    fn this(&mut self, frame: &mut ObjFunction) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
 
-        self.define_variable(global, frame);
+        self.variable(false, frame);
     }
 
-    fn function(&mut self, name: String) -> ObjFunction {
+    fn super_(&mut self, frame: &mut ObjFunction) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => {}
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        let name = self.parse_variable("Expect superclass method name.");
+        let name = self.identifier_constant(&name, frame);
+
+        self.named_variable(String::from("this"), false, frame);
+        if self.matches(TokenType::LeftParen) {
+            let arg_count = self.argument_list(frame);
+            self.named_variable(String::from("super"), false, frame);
+            self.emit(Operation::SuperInvoke(name, arg_count), frame);
+        } else {
+            self.named_variable(String::from("super"), false, frame);
+            self.emit(Operation::GetSuper(name), frame);
+        }
+    }
+
+    fn dot(&mut self, can_assign: bool, frame: &mut ObjFunction) {
+        let name = self.parse_variable("Expect property name after '.'.");
+        let name = self.identifier_constant(&name, frame);
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression(frame);
+            self.emit(Operation::SetProperty(name), frame);
+        } else if self.matches(TokenType::LeftParen) {
+            let arg_count = self.argument_list(frame);
+            self.emit(Operation::Invoke(name, arg_count), frame);
+        } else {
+            self.emit(Operation::GetProperty(name), frame);
+        }
+    }
+
+    fn function(
+        &mut self,
+        name: String,
+        function_type: FunctionType,
+        declaration: Option<usize>,
+        enclosing: &mut ObjFunction,
+    ) {
         let mut frame = ObjFunction::new(&name);
+        self.declaring.extend(declaration);
 
-        self.begin_scope(&mut frame);
+        self.contexts.push(FunctionContext::new(function_type));
+        self.begin_scope();
 
         self.consume(TokenType::LeftParen, "Expect '(' after function name.");
 
         // Function parameters
         if !self.check(TokenType::RightParen) {
             loop {
-                frame.arity += 1;
+                if frame.arity == u8::MAX {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                frame.arity = frame.arity.saturating_add(1);
 
                 self.consume(TokenType::Identifier, "Expect parameter name.");
-                let name = self.previous.clone().data.unwrap().lexeme.to_string();
-                self.declare_local(name);
+                let name = self.previous_lexeme().to_string();
+                let parameter = self.declare(SymbolKind::Parameter);
+                if let (Some(declaration), Some(_)) = (declaration, parameter) {
+                    let function = self.symbols.declaration_mut(declaration);
+                    function.parameters.push(name.clone());
+                }
+                if self.declare_local(name, parameter) {
+                    self.mark_initialized(&frame);
+                }
 
-                // self.define_variable(parameter_name, &mut frame);
                 if !self.matches(TokenType::Comma) {
                     break;
                 }
@@ -689,23 +1061,36 @@ Compiler state: {:#?}
 
         self.block(&mut frame);
         self.emit_return(&mut frame);
+        if let Some(declaration) = declaration {
+            self.declaring.pop();
+            self.end_declaration(declaration);
+        }
 
-        self.end_scope(&mut frame, false);
+        // No need to end the scope, returning from the function discards its locals
+        let context = self.contexts.pop().expect("Function context to be pushed");
+        for (slot, local) in context.locals.iter().enumerate() {
+            Self::add_local_variable(local, slot, &mut frame);
+        }
+        frame.upvalue_count = context.upvalues.len();
 
-        frame
+        let constant = self.make_constant(Value::Function(Rc::from(frame)), enclosing);
+        self.emit(Operation::Closure(constant, context.upvalues), enclosing);
     }
 
     fn call(&mut self, frame: &mut ObjFunction) {
         let arg_count = self.argument_list(frame);
-        frame.chunk.emit(Operation::Call(arg_count));
+        self.emit(Operation::Call(arg_count), frame);
     }
 
     fn argument_list(&mut self, frame: &mut ObjFunction) -> u8 {
-        let mut ret = 0;
+        let mut ret: u8 = 0;
         if !self.check(TokenType::RightParen) {
             loop {
                 self.expression(frame);
-                ret += 1;
+                if ret == u8::MAX {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                ret = ret.saturating_add(1);
                 if !self.matches(TokenType::Comma) {
                     break;
                 }
@@ -720,26 +1105,170 @@ Compiler state: {:#?}
         if self.matches(TokenType::Semicolon) {
             self.emit_return(frame);
         } else {
+            if self.context().function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
             self.expression(frame);
             self.consume(TokenType::Semicolon, "Expected ';' after return value.");
-            frame.chunk.emit(Operation::Return);
+            self.emit(Operation::Return, frame);
         }
     }
 
     fn emit_return(&self, frame: &mut ObjFunction) {
-        frame.chunk.emit(Operation::Nil);
-        frame.chunk.emit(Operation::Return);
+        if self.context().function_type == FunctionType::Initializer {
+            // Initializers always return the instance being initialized
+            self.emit(Operation::GetLocal(0), frame);
+        } else {
+            self.emit(Operation::Nil, frame);
+        }
+        self.emit(Operation::Return, frame);
+    }
+
+    fn emit(&self, op: Operation, frame: &mut ObjFunction) {
+        self.emit_at(op, self.previous_location(), frame);
+    }
+
+    fn emit_at(&self, op: Operation, location: SourceLocation, frame: &mut ObjFunction) {
+        frame.chunk.write(op, location);
+    }
+
+    fn emit_constant(&mut self, value: Value, frame: &mut ObjFunction) {
+        let constant = frame.chunk.add_constant(value);
+        if constant > MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.");
+        }
+        self.emit(Operation::Constant(constant), frame);
+    }
+
+    /// Adds a constant referred by an operation other than `Constant`, which only have two
+    /// bytes to point to it.
+    fn make_constant(&mut self, value: Value, frame: &mut ObjFunction) -> IdentifierId {
+        let constant = frame.chunk.add_constant(value);
+        if constant > MAX_SHORT_OPERAND {
+            self.error("Too many constants in one chunk.");
+        }
+        constant
+    }
+
+    /// String constant with a name, used by the operations on classes and properties.
+    fn identifier_constant(&mut self, name: &str, frame: &mut ObjFunction) -> IdentifierId {
+        let name = self.interner.intern(name);
+        self.make_constant(Value::String(name), frame)
+    }
+
+    fn global_symbol(&mut self, name: &str) -> usize {
+        let symbol = self.interner.symbol(name);
+        if symbol > MAX_SHORT_OPERAND {
+            self.error("Too many global variables.");
+        }
+        symbol
+    }
+
+    /// Adds the identifier just consumed to the symbol index, unless it was missing.
+    fn declare(&mut self, kind: SymbolKind) -> Option<usize> {
+        if self.previous.token_type != TokenType::Identifier {
+            return None;
+        }
+        let span = self.previous_span();
+        let declaration = Declaration {
+            name: self.previous_lexeme().to_string(),
+            kind,
+            span,
+            extent: span,
+            parameters: vec![],
+            parent: self.declaring.last().copied(),
+            global: self.context().scope_depth == 0,
+        };
+        Some(self.symbols.declare(declaration))
+    }
+
+    /// Extends the declaration up to the token just consumed, where its body ends.
+    fn end_declaration(&mut self, declaration: usize) {
+        let end = self.previous_span().end;
+        let declaration = self.symbols.declaration_mut(declaration);
+        declaration.extent.end = declaration.extent.end.max(end);
+    }
+
+    /// Adds the identifier just consumed, which is a use of `name`, to the symbol index.
+    fn refer(&mut self, name: &str) {
+        if self.previous.token_type != TokenType::Identifier {
+            return;
+        }
+        let span = self.previous_span();
+        // Locals shadow the ones of enclosing functions, which shadow the globals
+        let local = self
+            .contexts
+            .iter()
+            .rev()
+            .flat_map(|context| context.locals.iter().rev())
+            .find(|local| local.name == name);
+        match local {
+            Some(Local {
+                declaration: Some(declaration),
+                ..
+            }) => self.symbols.refer(span, *declaration),
+            // Made up by the compiler, like 'super'
+            Some(_) => {}
+            None => self.global_uses.push((name.to_string(), span)),
+        }
+    }
+
+    fn previous_span(&self) -> Span {
+        match &self.previous.data {
+            Ok(token) => Span::new(token.start, token.end),
+            Err(_) => Span::default(),
+        }
+    }
+
+    /// Where the last consumed token starts, which is where the code being emitted comes from.
+    fn previous_location(&self) -> SourceLocation {
+        SourceLocation::new(self.previous.line, self.previous.column)
+    }
+
+    fn previous_lexeme(&self) -> &'a str {
+        match &self.previous.data {
+            Ok(token) => token.lexeme,
+            Err(_) => "",
+        }
+    }
+
+    /// Makes the last declared local readable, once its initializer was compiled.
+    fn mark_initialized(&mut self, frame: &ObjFunction) {
+        let context = self.context_mut();
+        if context.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = context.locals.last_mut() {
+            local.depth = context.scope_depth;
+            local.start = frame.chunk.len();
+        }
     }
 
-    fn mark_initialized(&self) {
-        // TODO: I'm not validating local variables with markInitialized like the book does.
+    /// Records where the local in `slot` lives, up to the code written so far.
+    fn add_local_variable(local: &Local, slot: usize, frame: &mut ObjFunction) {
+        // Locals whose initializer failed to compile never lived
+        if local.depth == -1 {
+            return;
+        }
+        frame.chunk.add_local_variable(LocalVariable {
+            name: local.name.clone(),
+            slot,
+            start: local.start,
+            end: frame.chunk.len(),
+        });
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::Compiler;
-    use crate::{chunk::Operation, object::ObjFunction, value::Value};
+    use crate::{
+        chunk::{Operation, Upvalue},
+        interner::Interner,
+        object::ObjFunction,
+        symbols::SymbolKind,
+        value::Value,
+    };
     use std::rc::Rc;
 
     #[test]
@@ -880,14 +1409,14 @@ mod tests {
     fn global_vars() {
         assert_chunk(
             "var a;",
-            vec![Operation::Nil, Operation::DefineGlobal("a".to_string())],
+            vec![Operation::Nil, Operation::DefineGlobal(0)],
             vec![],
         );
         assert_chunk(
             "var a = 1;",
             vec![
                 Operation::Constant(0),
-                Operation::DefineGlobal("a".to_string()),
+                Operation::DefineGlobal(0),
             ],
             vec![Value::Number(1.0)],
         );
@@ -895,8 +1424,8 @@ mod tests {
             "var a = 1; a;",
             vec![
                 Operation::Constant(0),
-                Operation::DefineGlobal("a".to_string()),
-                Operation::GetGlobal("a".to_string()),
+                Operation::DefineGlobal(0),
+                Operation::GetGlobal(0),
                 Operation::Pop,
             ],
             vec![Value::Number(1.0)],
@@ -905,9 +1434,9 @@ mod tests {
             "var a = 1; a = 2;",
             vec![
                 Operation::Constant(0),
-                Operation::DefineGlobal("a".to_string()),
+                Operation::DefineGlobal(0),
                 Operation::Constant(1),
-                Operation::SetGlobal("a".to_string()),
+                Operation::SetGlobal(0),
                 Operation::Pop,
             ],
             vec![Value::Number(1.0), Value::Number(2.0)],
@@ -916,9 +1445,9 @@ mod tests {
             "var a = 1; var b = a;",
             vec![
                 Operation::Constant(0),
-                Operation::DefineGlobal("a".to_string()),
-                Operation::GetGlobal("a".to_string()),
-                Operation::DefineGlobal("b".to_string()),
+                Operation::DefineGlobal(0),
+                Operation::GetGlobal(0),
+                Operation::DefineGlobal(1),
             ],
             vec![Value::Number(1.0)],
         );
@@ -968,17 +1497,22 @@ mod tests {
             ],
             vec![Value::Number(1.0), Value::Number(2.0)],
         );
-        // assert_chunk(
-        //     "{ var a = 1; { var a = a; print a; } }",
-        //     vec![
-        //         Operation::Constant(0),
-        //         Operation::GetLocal(0),
-        //         Operation::GetLocal(0),
-        //         Operation::Print,
-        //         Operation::Pop,
-        //     ],
-        //     vec![Value::Number(1.0), Value::Number(2.0)],
-        // );
+        assert_compile_error(
+            "{ var a = 1; { var a = a; print a; } }",
+            "Can't read local variable in its own initializer.",
+        );
+        assert_chunk(
+            "{ var a = 1; { var b = a; print b; } }",
+            vec![
+                Operation::Constant(0),
+                Operation::GetLocal(0),
+                Operation::GetLocal(1),
+                Operation::Print,
+                Operation::Pop,
+                Operation::Pop,
+            ],
+            vec![Value::Number(1.0)],
+        );
     }
 
     #[test]
@@ -987,7 +1521,7 @@ mod tests {
             "if(true) { print 1; } print 2;",
             vec![
                 Operation::True,
-                Operation::JumpIfFalse(4),
+                Operation::JumpIfFalse(7),
                 //
                 Operation::Pop,
                 Operation::Constant(0),
@@ -1004,12 +1538,12 @@ mod tests {
             "if(true) { print 1; } else { print 2; }",
             vec![
                 Operation::True,
-                Operation::JumpIfFalse(4),
+                Operation::JumpIfFalse(7),
                 //
                 Operation::Pop,
                 Operation::Constant(0),
                 Operation::Print,
-                Operation::Jump(3),
+                Operation::Jump(4),
                 //
                 Operation::Pop,
                 Operation::Constant(1),
@@ -1021,24 +1555,24 @@ mod tests {
             "var a; if(1 == 2) { a = \"true\"; } else { a = \"false\"; } print a;",
             vec![
                 Operation::Nil,
-                Operation::DefineGlobal("a".to_string()),
+                Operation::DefineGlobal(0),
                 Operation::Constant(0),
                 Operation::Constant(1),
                 Operation::Equal,
-                Operation::JumpIfFalse(5),
+                Operation::JumpIfFalse(10),
                 //
                 Operation::Pop,
                 Operation::Constant(2),
-                Operation::SetGlobal("a".to_string()),
+                Operation::SetGlobal(0),
                 Operation::Pop,
-                Operation::Jump(4),
+                Operation::Jump(7),
                 //
                 Operation::Pop,
                 Operation::Constant(3),
-                Operation::SetGlobal("a".to_string()),
+                Operation::SetGlobal(0),
                 //
                 Operation::Pop,
-                Operation::GetGlobal("a".to_string()),
+                Operation::GetGlobal(0),
                 Operation::Print,
             ],
             vec![
@@ -1056,12 +1590,12 @@ mod tests {
             "while(true) { print 1; }",
             vec![
                 Operation::True,
-                Operation::JumpIfFalse(4),
+                Operation::JumpIfFalse(7),
                 //
                 Operation::Pop,
                 Operation::Constant(0),
                 Operation::Print,
-                Operation::Loop(6),
+                Operation::Loop(11),
                 //
                 Operation::Pop,
             ],
@@ -1072,22 +1606,22 @@ mod tests {
             "var a = 0; while(a < 5) { print a; a = a + 1; }",
             vec![
                 Operation::Constant(0),
-                Operation::DefineGlobal("a".to_string()),
+                Operation::DefineGlobal(0),
                 // Condition
-                Operation::GetGlobal("a".to_string()),
+                Operation::GetGlobal(0),
                 Operation::Constant(1),
                 Operation::Less,
-                Operation::JumpIfFalse(9),
+                Operation::JumpIfFalse(18),
                 // Loop
                 Operation::Pop,
-                Operation::GetGlobal("a".to_string()),
+                Operation::GetGlobal(0),
                 Operation::Print,
-                Operation::GetGlobal("a".to_string()),
+                Operation::GetGlobal(0),
                 Operation::Constant(2),
                 Operation::Add,
-                Operation::SetGlobal("a".to_string()),
+                Operation::SetGlobal(0),
                 Operation::Pop,
-                Operation::Loop(13),
+                Operation::Loop(27),
                 // End
                 Operation::Pop,
             ],
@@ -1106,20 +1640,20 @@ mod tests {
                 Operation::GetLocal(0),
                 Operation::Constant(1),
                 Operation::Less,
-                Operation::JumpIfFalse(11),
+                Operation::JumpIfFalse(21),
                 Operation::Pop,
-                Operation::Jump(6),
+                Operation::Jump(11),
                 // Increment
                 Operation::GetLocal(0),
                 Operation::Constant(2),
                 Operation::Add,
                 Operation::SetLocal(0),
                 Operation::Pop,
-                Operation::Loop(12),
+                Operation::Loop(23),
                 // Body
                 Operation::GetLocal(0),
                 Operation::Print,
-                Operation::Loop(9),
+                Operation::Loop(17),
                 // Cleanup (var and condition)
                 Operation::Pop,
                 Operation::Pop,
@@ -1144,8 +1678,8 @@ mod tests {
             "fun pepe() { print 1; }",
             vec![
                 // Definition
-                Operation::Constant(0),
-                Operation::DefineGlobal("pepe".to_string()),
+                Operation::Closure(0, vec![]),
+                Operation::DefineGlobal(0),
             ],
             vec![Value::Function(Rc::from(pepe.clone()))],
         );
@@ -1154,10 +1688,10 @@ mod tests {
             "fun pepe() { print 1; } pepe();",
             vec![
                 // Definition
-                Operation::Constant(0),
-                Operation::DefineGlobal("pepe".to_string()),
+                Operation::Closure(0, vec![]),
+                Operation::DefineGlobal(0),
                 // Call
-                Operation::GetGlobal("pepe".to_string()),
+                Operation::GetGlobal(0),
                 Operation::Call(0),
                 Operation::Pop,
             ],
@@ -1168,10 +1702,10 @@ mod tests {
             "fun pepe() { print 1; } print pepe();",
             vec![
                 // Definition
-                Operation::Constant(0),
-                Operation::DefineGlobal("pepe".to_string()),
+                Operation::Closure(0, vec![]),
+                Operation::DefineGlobal(0),
                 // Call
-                Operation::GetGlobal("pepe".to_string()),
+                Operation::GetGlobal(0),
                 Operation::Call(0),
                 Operation::Print,
             ],
@@ -1185,8 +1719,8 @@ mod tests {
         let mut add = ObjFunction::new("add");
         add.arity = 2;
         add.chunk.emit_many(&mut vec![
-            Operation::GetLocal(1),
             Operation::GetLocal(0),
+            Operation::GetLocal(1),
             Operation::Add,
             Operation::Return,
             Operation::Nil,
@@ -1197,8 +1731,8 @@ mod tests {
             "fun add(a, b) { return a + b; }",
             vec![
                 // Definition
-                Operation::Constant(0),
-                Operation::DefineGlobal("add".to_string()),
+                Operation::Closure(0, vec![]),
+                Operation::DefineGlobal(0),
             ],
             vec![Value::Function(Rc::from(add.clone()))],
         );
@@ -1207,10 +1741,10 @@ mod tests {
             "fun add(a, b) { return a + b; } print add(2,3);",
             vec![
                 // Definition
-                Operation::Constant(0),
-                Operation::DefineGlobal("add".to_string()),
+                Operation::Closure(0, vec![]),
+                Operation::DefineGlobal(0),
                 // Call
-                Operation::GetGlobal("add".to_string()),
+                Operation::GetGlobal(0),
                 Operation::Constant(1),
                 Operation::Constant(2),
                 Operation::Call(2),
@@ -1233,16 +1767,16 @@ mod tests {
             Operation::Constant(0),
             Operation::Greater,
             Operation::Not,
-            Operation::JumpIfFalse(4),
+            Operation::JumpIfFalse(7),
             // Then
             Operation::Pop,
             Operation::Constant(1),
             Operation::Return,
             // Else
-            Operation::Jump(9),
+            Operation::Jump(15),
             Operation::Pop,
             Operation::GetLocal(0),
-            Operation::GetGlobal("fact".to_string()),
+            Operation::GetGlobal(0),
             Operation::GetLocal(0),
             Operation::Constant(2),
             Operation::Substract,
@@ -1271,10 +1805,10 @@ print fact(5);
             ",
             vec![
                 // Definition
-                Operation::Constant(0),
-                Operation::DefineGlobal("fact".to_string()),
+                Operation::Closure(0, vec![]),
+                Operation::DefineGlobal(0),
                 // Call
-                Operation::GetGlobal("fact".to_string()),
+                Operation::GetGlobal(0),
                 Operation::Constant(1),
                 Operation::Call(1),
                 // Print
@@ -1285,13 +1819,155 @@ print fact(5);
     }
 
 
+    #[test]
+    fn nested_functions() {
+        // A helper declared inside a block, with locals of its own. Its slots start from its
+        // own parameters, not from the locals of the enclosing code.
+        let mut helper = ObjFunction::new("helper");
+        helper.arity = 1;
+        helper.chunk.emit_many(&mut vec![
+            Operation::GetLocal(0),
+            Operation::Constant(0),
+            Operation::Multiply,
+            Operation::GetLocal(1),
+            Operation::Return,
+            Operation::Nil,
+            Operation::Return,
+        ]);
+        helper.chunk.add_constant(Value::Number(2.0));
+
+        assert_chunk(
+            "{ var a = 1; var b = 2; fun helper(x) { var y = x * 2; return y; } print helper(b); }",
+            vec![
+                Operation::Constant(0),
+                Operation::Constant(1),
+                Operation::Closure(2, vec![]),
+                Operation::GetLocal(2),
+                Operation::GetLocal(1),
+                Operation::Call(1),
+                Operation::Print,
+                Operation::Pop,
+                Operation::Pop,
+                Operation::Pop,
+            ],
+            vec![
+                Value::Number(1.0),
+                Value::Number(2.0),
+                Value::Function(Rc::from(helper)),
+            ],
+        );
+
+        assert_compile_error(
+            "fun outer() { var a = 1; { var a = a; } }",
+            "Can't read local variable in its own initializer.",
+        );
+    }
+
+    #[test]
+    fn closures() {
+        // Definition of inner, capturing the local of outer
+        let mut inner = ObjFunction::new("inner");
+        inner.upvalue_count = 1;
+        inner.chunk.emit_many(&mut vec![
+            Operation::GetUpvalue(0),
+            Operation::Print,
+            Operation::Nil,
+            Operation::Return,
+        ]);
+
+        let mut outer = ObjFunction::new("outer");
+        outer.chunk.emit_many(&mut vec![
+            Operation::Constant(0),
+            Operation::Closure(
+                1,
+                vec![Upvalue {
+                    is_local: true,
+                    index: 0,
+                }],
+            ),
+            Operation::GetLocal(1),
+            Operation::Return,
+            Operation::Nil,
+            Operation::Return,
+        ]);
+        outer.chunk.add_constant(Value::Number(1.0));
+        outer
+            .chunk
+            .add_constant(Value::Function(Rc::from(inner.clone())));
+
+        assert_chunk(
+            "fun outer() { var a = 1; fun inner() { print a; } return inner; }",
+            vec![
+                Operation::Closure(0, vec![]),
+                Operation::DefineGlobal(0),
+            ],
+            vec![Value::Function(Rc::from(outer.clone()))],
+        );
+        assert_chunk(
+            "{ var a = 1; fun inner() { print a; } }",
+            vec![
+                Operation::Constant(0),
+                Operation::Closure(
+                    1,
+                    vec![Upvalue {
+                        is_local: true,
+                        index: 0,
+                    }],
+                ),
+                Operation::Pop,
+                Operation::CloseUpvalue,
+            ],
+            vec![
+                Value::Number(1.0),
+                Value::Function(Rc::from(inner.clone())),
+            ],
+        );
+    }
+
+    #[test]
+    fn classes() {
+        let mut get = ObjFunction::new("get");
+        get.chunk.emit_many(&mut vec![
+            Operation::GetLocal(0),
+            Operation::GetProperty(0),
+            Operation::Return,
+            Operation::Nil,
+            Operation::Return,
+        ]);
+        get.chunk.add_constant(Value::new_string("x"));
+
+        assert_chunk(
+            "class A { get() { return this.x; } } A().get();",
+            vec![
+                // Definition
+                Operation::Class(0),
+                Operation::DefineGlobal(0),
+                Operation::GetGlobal(0),
+                Operation::Closure(2, vec![]),
+                Operation::Method(1),
+                Operation::Pop,
+                // Invocation
+                Operation::GetGlobal(0),
+                Operation::Call(0),
+                Operation::Invoke(3, 0),
+                Operation::Pop,
+            ],
+            vec![
+                Value::new_string("A"),
+                Value::new_string("get"),
+                Value::Function(Rc::from(get.clone())),
+                Value::new_string("get"),
+            ],
+        );
+    }
+
     #[test]
     fn native_functions() {
         assert_chunk(
             "print clock();",
             vec![
                 // Definition
-                Operation::GetGlobal("clock".to_string()),
+                Operation::GetGlobal(0),
                 Operation::Call(0),
                 Operation::Print,
             ],
@@ -1301,6 +1977,178 @@ print fact(5);
 
     //////////////////////////
 
+    #[test]
+    fn source_locations() {
+        let source = String::from("var a = 1;\nprint a +\n    2;");
+        let mut interner = Interner::new();
+        let mut compiler = Compiler::from_source(&source, &mut interner);
+        let frame = compiler.compile().unwrap();
+
+        let locations: Vec<(u32, u32)> = frame
+            .chunk
+            .instructions()
+            .map(|(offset, _)| frame.chunk.location(offset))
+            .map(|location| (location.line, location.column))
+            .collect();
+
+        // Constant, DefineGlobal, GetGlobal, Constant, Add (at the operator), Print, Nil, Return
+        assert_eq!(
+            locations,
+            vec![(1, 9), (1, 10), (2, 7), (3, 5), (2, 9), (3, 6), (3, 7), (3, 7)]
+        );
+    }
+
+    #[test]
+    fn local_variables() {
+        let source = String::from("{\n  var a = 1;\n  {\n    var b = a;\n  }\n  print a;\n}");
+        let mut interner = Interner::new();
+        let function = Compiler::from_source(&source, &mut interner)
+            .compile()
+            .unwrap();
+        let variables: Vec<(&str, usize, usize, usize)> = function
+            .chunk
+            .local_variables()
+            .iter()
+            .map(|variable| {
+                (
+                    variable.name.as_str(),
+                    variable.slot,
+                    variable.start,
+                    variable.end,
+                )
+            })
+            .collect();
+        // Each is alive from right after its initializer until its scope pops it
+        assert_eq!(variables, vec![("b", 1, 4, 5), ("a", 0, 2, 9)]);
+
+        let names = |offset| -> Vec<&str> {
+            function
+                .chunk
+                .local_variables_at(offset)
+                .iter()
+                .map(|variable| variable.name.as_str())
+                .collect()
+        };
+        assert_eq!(names(0), Vec::<&str>::new());
+        assert_eq!(names(2), vec!["a"]);
+        assert_eq!(names(4), vec!["a", "b"]);
+        assert_eq!(names(9), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn symbols() {
+        let source = String::from(
+            "var a = 1;
+fun add(x, y) {
+  var z = x + y;
+  return z + a;
+}
+class A < B { get() { return add(1, 2); } }
+print add(a, later);
+var later = 2;",
+        );
+        let mut interner = Interner::new();
+        let mut compiler = Compiler::from_source(&source, &mut interner);
+        assert!(compiler.compile().is_ok());
+        let symbols = compiler.symbols();
+
+        let declarations: Vec<(&str, SymbolKind, Option<usize>, bool)> = symbols
+            .declarations()
+            .iter()
+            .map(|declaration| {
+                (
+                    declaration.name.as_str(),
+                    declaration.kind,
+                    declaration.parent,
+                    declaration.global,
+                )
+            })
+            .collect();
+        assert_eq!(
+            declarations,
+            vec![
+                ("a", SymbolKind::Variable, None, true),
+                ("add", SymbolKind::Function, None, true),
+                ("x", SymbolKind::Parameter, Some(1), false),
+                ("y", SymbolKind::Parameter, Some(1), false),
+                ("z", SymbolKind::Variable, Some(1), false),
+                ("A", SymbolKind::Class, None, true),
+                ("get", SymbolKind::Method, Some(5), false),
+                ("later", SymbolKind::Variable, None, true),
+            ]
+        );
+        let add = &symbols.declarations()[1];
+        assert_eq!(add.parameters, vec!["x", "y"]);
+        assert_eq!(
+            &source[add.extent.start..add.extent.end],
+            "add(x, y) {\n  var z = x + y;\n  return z + a;\n}"
+        );
+
+        // Every use, but the one of the undeclared B
+        let name_at = |text: &str, nth: usize| {
+            let offset = source.match_indices(text).nth(nth).unwrap().0;
+            symbols.symbol_at(offset).map(|(span, declaration)| {
+                (&source[span.start..span.end], declaration.name.as_str())
+            })
+        };
+        assert_eq!(symbols.references().len(), 8);
+        assert_eq!(name_at("x + y", 0), Some(("x", "x")));
+        assert_eq!(name_at("z + a", 0), Some(("z", "z")));
+        assert_eq!(name_at("a;", 0), Some(("a", "a")));
+        assert_eq!(name_at("add(1", 0), Some(("add", "add")));
+        assert_eq!(name_at("later)", 0), Some(("later", "later")));
+        assert_eq!(name_at("B {", 0), None);
+        assert_eq!(name_at("print", 0), None);
+    }
+
+    #[test]
+    fn too_much_nesting() {
+        for source in [
+            format!("{}{}", "{".repeat(300), "}".repeat(300)),
+            format!("print {}1{};", "(".repeat(300), ")".repeat(300)),
+            format!("{}print 1 +;{}", "{".repeat(300), "}".repeat(300)),
+        ] {
+            let mut interner = Interner::new();
+            let diagnostics = Compiler::from_source(&source, &mut interner)
+                .compile()
+                .expect_err("This source should have failed to compile");
+            let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+            assert_eq!(messages, ["Too much nesting."]);
+        }
+    }
+
+    #[test]
+    fn too_many_locals() {
+        let source = String::from("one_too_many = 1;");
+        let mut interner = Interner::new();
+        let mut compiler = Compiler::from_source(&source, &mut interner);
+        let mut frame = ObjFunction::new("script");
+        compiler.begin_scope();
+        for i in 0..super::MAX_BYTE_OPERAND {
+            assert!(compiler.declare_local(format!("l{}", i), None));
+            compiler.mark_initialized(&frame);
+        }
+        // Still in its initializer
+        assert!(compiler.declare_local(String::from("last"), None));
+
+        compiler.advance();
+        compiler.local_var_declaration(&mut frame);
+
+        // The local that didn't fit isn't compiled, nor marks the one before it as initialized
+        assert_eq!(compiler.diagnostics[0].message, "Too many local variables in function.");
+        assert_eq!(compiler.context().locals.last().map(|local| local.depth), Some(-1));
+        assert_eq!(frame.chunk.len(), 0);
+    }
+
+    fn assert_compile_error(source: &str, message: &str) {
+        let source = String::from(source);
+        let mut interner = Interner::new();
+        let diagnostics = Compiler::from_source(&source, &mut interner)
+            .compile()
+            .expect_err("This source should have failed to compile");
+        assert_eq!(diagnostics[0].message, message);
+    }
+
     fn assert_expression(source: &str, mut operations: Vec<Operation>, constants: Vec<Value>) {
         operations.push(Operation::Pop);
         assert_chunk(source, operations, constants);
@@ -1311,16 +2159,17 @@ print fact(5);
         operations.push(Operation::Nil);
         operations.push(Operation::Return);
 
-        let mut compiler = Compiler::from_source(&source2);
-        let frame = compiler.compile();
-
-        assert!(
-            !compiler.had_error,
-            "\nCOMPILER ERROR for source: {}",
-            source
-        );
+        let mut interner = Interner::new();
+        let mut compiler = Compiler::from_source(&source2, &mut interner);
+        let frame = compiler
+            .compile()
+            .unwrap_or_else(|errors| panic!("\nCOMPILER ERROR for source: {}\n{:?}", source, errors));
+        let constants: Vec<Value> = constants
+            .into_iter()
+            .map(|constant| intern_constant(constant, &mut interner))
+            .collect();
         assert_eq!(
-            frame.chunk.code, operations,
+            frame.chunk.operations(), operations,
             "\nOPERATIONS failed for source: {}",
             source
         );
@@ -1330,4 +2179,22 @@ print fact(5);
             source
         );
     }
+
+    // Strings are compared by pointer, so the expected ones must be the interned copies
+    fn intern_constant(constant: Value, interner: &mut Interner) -> Value {
+        match constant {
+            Value::String(string) => Value::String(interner.intern_rc(string)),
+            Value::Function(function) => {
+                let mut function = (*function).clone();
+                function.chunk.constants = function
+                    .chunk
+                    .constants
+                    .into_iter()
+                    .map(|constant| intern_constant(constant, interner))
+                    .collect();
+                Value::Function(Rc::from(function))
+            }
+            constant => constant,
+        }
+    }
 }
@@ -0,0 +1,424 @@
+//! Which lines of a script ran, and which way its conditions went, as recorded by the VM while
+//! it runs with coverage on, see [`VM::set_coverage`](crate::vm::VM::set_coverage).
+//!
+//! Coverage is read and written as LCOV tracefiles, which coverage viewers display, so the
+//! results of several runs can be merged into the same file.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{chunk::Operation, object::ObjFunction, value::Value};
+
+/// What ran of a script.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    // Times each line with code was started
+    lines: BTreeMap<u32, u64>,
+    // Times each branch was taken, by line, condition on that line and branch: 0 when the
+    // condition held and 1 when it didn't. Nothing if the condition was never evaluated.
+    branches: BTreeMap<(u32, u32, u32), Option<u64>>,
+}
+
+impl Coverage {
+    /// Times each line with code was started, including the ones never run.
+    pub fn lines(&self) -> &BTreeMap<u32, u64> {
+        &self.lines
+    }
+
+    /// Lines with code that never ran.
+    pub fn missed_lines(&self) -> Vec<u32> {
+        self.lines
+            .iter()
+            .filter(|(_, hits)| **hits == 0)
+            .map(|(line, _)| *line)
+            .collect()
+    }
+
+    /// Times each branch of the conditions was taken, keyed by the line of the condition, its
+    /// index among the conditions of that line, and 0 for the branch run when it holds or 1
+    /// for the other one. Nothing for the conditions never evaluated.
+    pub fn branches(&self) -> &BTreeMap<(u32, u32, u32), Option<u64>> {
+        &self.branches
+    }
+
+    /// Adds what ran in `other`, like from another run of the same script.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (line, hits) in &other.lines {
+            *self.lines.entry(*line).or_default() += hits;
+        }
+        for (branch, taken) in &other.branches {
+            let merged = self.branches.entry(*branch).or_default();
+            if let Some(taken) = taken {
+                *merged = Some(merged.unwrap_or(0) + taken);
+            }
+        }
+    }
+}
+
+/// The line a frame is running.
+#[derive(Debug, Clone, Copy)]
+struct LineRun {
+    line: u32,
+    // Offset of the operation the line was counted at
+    start: usize,
+    // Offset of the previous operation run
+    previous: usize,
+}
+
+/// Records the coverage of the scripts a VM runs.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    coverage: Coverage,
+    // Line and index on that line of the condition of each `JumpIfFalse`, by the address of
+    // its function and its offset
+    conditions: HashMap<(*const ObjFunction, usize), (u32, u32)>,
+    // Keeps the functions alive, so their addresses keep identifying them
+    alive: Vec<Rc<ObjFunction>>,
+    // What each frame is running, from the outermost
+    last: Vec<LineRun>,
+}
+
+impl Recorder {
+    pub(crate) fn coverage(&self) -> &Coverage {
+        &self.coverage
+    }
+
+    /// Finds the lines and conditions of a script about to run, and the functions declared in
+    /// it, so the ones that don't run show up too.
+    pub(crate) fn load(&mut self, script: &Rc<ObjFunction>) {
+        self.last.clear();
+        let mut functions = vec![Rc::clone(script)];
+        let mut conditions_on_line: HashMap<u32, u32> = HashMap::new();
+        while let Some(function) = functions.pop() {
+            let chunk = &function.chunk;
+            let mut instructions: Vec<(usize, Operation)> = chunk.instructions().collect();
+            // The return ending the script is on the line of the end of the file, which is
+            // past the source when it ends with a newline
+            if let [.., (_, Operation::Nil), (_, Operation::Return)] = instructions.as_slice() {
+                if Rc::ptr_eq(&function, script) {
+                    instructions.truncate(instructions.len() - 2);
+                }
+            }
+
+            for (offset, op) in instructions {
+                let line = chunk.line(offset);
+                if line == 0 {
+                    continue;
+                }
+                self.coverage.lines.entry(line).or_default();
+                if let Operation::JumpIfFalse(_) = op {
+                    let index = conditions_on_line.entry(line).or_default();
+                    self.conditions
+                        .insert((Rc::as_ptr(&function), offset), (line, *index));
+                    for branch in 0..2 {
+                        self.coverage
+                            .branches
+                            .entry((line, *index, branch))
+                            .or_default();
+                    }
+                    *index += 1;
+                }
+            }
+            for constant in &chunk.constants {
+                if let Value::Function(nested) = constant {
+                    functions.push(Rc::clone(nested));
+                }
+            }
+            self.alive.push(function);
+        }
+    }
+
+    /// Counts the line of an operation about to run, with `depth` frames on the call stack,
+    /// if the operation starts it.
+    ///
+    /// Lines are counted when the code gets to them from another line, and when a loop within
+    /// the line goes around. `for` loops jump back twice per iteration, to the increment and
+    /// then to the condition before it, so only jumps to code at or after where the line was
+    /// last counted count. That way a loop's line is counted once, plus once per iteration.
+    #[inline]
+    pub(crate) fn operation(&mut self, depth: usize, ip: usize, line: u32) {
+        self.last.truncate(depth);
+        let run = LineRun {
+            line,
+            start: ip,
+            previous: ip,
+        };
+        let started = match self.last.get_mut(depth.wrapping_sub(1)) {
+            Some(last) => {
+                let looped = ip <= last.previous && ip >= last.start;
+                let started = last.line != line || looped;
+                if started {
+                    *last = run;
+                } else {
+                    last.previous = ip;
+                }
+                started
+            }
+            None => {
+                self.last.resize(depth, run);
+                true
+            }
+        };
+        if started {
+            if let Some(hits) = self.coverage.lines.get_mut(&line) {
+                *hits += 1;
+            }
+        }
+    }
+
+    /// Counts the branch taken by the `JumpIfFalse` at `ip`, depending on whether its
+    /// condition `held`.
+    #[cold]
+    pub(crate) fn condition(&mut self, function: &Rc<ObjFunction>, ip: usize, held: bool) {
+        let (line, index) = match self.conditions.get(&(Rc::as_ptr(function), ip)) {
+            Some(condition) => *condition,
+            None => return,
+        };
+        let taken = if held { 0 } else { 1 };
+        for branch in 0..2 {
+            let count = self
+                .coverage
+                .branches
+                .entry((line, index, branch))
+                .or_default();
+            *count = Some(count.unwrap_or(0) + u64::from(branch == taken));
+        }
+    }
+}
+
+/// The coverage of several scripts, by file name, as in an LCOV tracefile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lcov {
+    files: BTreeMap<String, Coverage>,
+}
+
+impl Lcov {
+    /// Reads a tracefile. Only the lines and branches are kept, as the totals are worked out
+    /// again when writing it.
+    pub fn parse(text: &str) -> Result<Lcov, String> {
+        let mut lcov = Lcov::default();
+        let mut file: Option<(String, Coverage)> = None;
+        for (number, record) in text.lines().enumerate() {
+            let invalid = || format!("Invalid record on line {}: {}", number + 1, record);
+            let record = record.trim();
+            let (kind, values) = record.split_once(':').unwrap_or((record, ""));
+            let values: Vec<&str> = values.split(',').collect();
+            match (kind, file.as_mut()) {
+                ("SF", None) => file = Some((values.join(","), Coverage::default())),
+                ("DA", Some((_, coverage))) => {
+                    let (line, hits): (u32, u64) = match values.as_slice() {
+                        // A checksum of the line may follow
+                        [line, hits, ..] => line.parse().ok().zip(hits.parse().ok()),
+                        _ => None,
+                    }
+                    .ok_or_else(invalid)?;
+                    *coverage.lines.entry(line).or_default() += hits;
+                }
+                ("BRDA", Some((_, coverage))) => {
+                    let (key, taken) = match values.as_slice() {
+                        [line, index, branch, taken] => parse_branch(line, index, branch, taken),
+                        _ => None,
+                    }
+                    .ok_or_else(invalid)?;
+                    let mut branch = Coverage::default();
+                    branch.branches.insert(key, taken);
+                    coverage.merge(&branch);
+                }
+                ("end_of_record", Some(_)) => {
+                    if let Some((name, coverage)) = file.take() {
+                        lcov.add(&name, &coverage);
+                    }
+                }
+                ("SF" | "DA" | "BRDA" | "end_of_record", _) => return Err(invalid()),
+                // Test names, functions and totals
+                _ => {}
+            }
+        }
+        match file {
+            Some(_) => Err(String::from("The last file has no end_of_record")),
+            None => Ok(lcov),
+        }
+    }
+
+    pub fn file(&self, name: &str) -> Option<&Coverage> {
+        self.files.get(name)
+    }
+
+    /// Adds the coverage of a run of the script in the file `name`.
+    pub fn add(&mut self, name: &str, coverage: &Coverage) {
+        self.files
+            .entry(name.to_string())
+            .or_default()
+            .merge(coverage);
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        for (name, coverage) in &self.files {
+            writeln!(output, "TN:")?;
+            writeln!(output, "SF:{}", name)?;
+            for ((line, index, branch), taken) in &coverage.branches {
+                match taken {
+                    Some(taken) => {
+                        writeln!(output, "BRDA:{},{},{},{}", line, index, branch, taken)?
+                    }
+                    None => writeln!(output, "BRDA:{},{},{},-", line, index, branch)?,
+                }
+            }
+            let branches_hit = coverage
+                .branches
+                .values()
+                .filter(|taken| taken.unwrap_or(0) > 0)
+                .count();
+            writeln!(output, "BRF:{}", coverage.branches.len())?;
+            writeln!(output, "BRH:{}", branches_hit)?;
+            for (line, hits) in &coverage.lines {
+                writeln!(output, "DA:{},{}", line, hits)?;
+            }
+            let lines_hit = coverage.lines.values().filter(|hits| **hits > 0).count();
+            writeln!(output, "LF:{}", coverage.lines.len())?;
+            writeln!(output, "LH:{}", lines_hit)?;
+            writeln!(output, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+/// The key and count of a branch record, where `-` means its condition never ran.
+fn parse_branch(
+    line: &str,
+    index: &str,
+    branch: &str,
+    taken: &str,
+) -> Option<((u32, u32, u32), Option<u64>)> {
+    let key = (
+        line.parse().ok()?,
+        index.parse().ok()?,
+        branch.parse().ok()?,
+    );
+    let taken = match taken {
+        "-" => None,
+        taken => Some(taken.parse().ok()?),
+    };
+    Some((key, taken))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Coverage, Lcov};
+    use crate::interpreter::Interpreter;
+
+    const SOURCE: &str = "\
+fun check(n) {
+  if (n > 0) {
+    return \"positive\";
+  }
+  return \"other\";
+}
+for (var i = 0; i < 3; i = i + 1) check(i);
+var unused = false and check(1);
+";
+
+    fn coverage(source: &str) -> Coverage {
+        let mut interpreter = Interpreter::new(vec![]);
+        interpreter.vm_mut().set_coverage(true);
+        interpreter.interpret(source).unwrap();
+        interpreter.vm().coverage().unwrap().clone()
+    }
+
+    #[test]
+    fn lines() {
+        let covered = coverage(SOURCE);
+        let lines: Vec<(u32, u64)> = covered.lines().iter().map(|(l, h)| (*l, *h)).collect();
+        // The function is only declared once its body is compiled, on line 6, and the loop's
+        // line is counted when reached and each time the loop goes around
+        assert_eq!(
+            lines,
+            [(2, 3), (3, 2), (4, 1), (5, 1), (6, 1), (7, 4), (8, 1)]
+        );
+        assert!(covered.missed_lines().is_empty());
+
+        // Loops count the same whether they span several lines or not. Leaving the `for` loop
+        // pops its condition on the body's last line, so that line is reached once more
+        let loops = coverage(
+            "var i = 0;\nwhile (i < 3) i = i + 1;\nfor (var j = 0;\n j < 3;\n j = j + 1)\n print j;\n",
+        );
+        let lines: Vec<(u32, u64)> = loops.lines().iter().map(|(l, h)| (*l, *h)).collect();
+        assert_eq!(lines, [(1, 1), (2, 4), (3, 1), (4, 4), (5, 3), (6, 4)]);
+
+        let coverage = Coverage {
+            lines: [(1, 0), (2, 4)].into_iter().collect(),
+            ..Default::default()
+        };
+        assert_eq!(coverage.missed_lines(), [1]);
+    }
+
+    #[test]
+    fn branches() {
+        let coverage = coverage(SOURCE);
+        let branches: Vec<_> = coverage.branches().iter().map(|(k, t)| (*k, *t)).collect();
+        assert_eq!(
+            branches,
+            [
+                ((2, 0, 0), Some(2)),
+                ((2, 0, 1), Some(1)),
+                ((7, 0, 0), Some(3)),
+                ((7, 0, 1), Some(1)),
+                ((8, 0, 0), Some(0)),
+                ((8, 0, 1), Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn merging_runs() {
+        let mut lcov = Lcov::default();
+        lcov.add(
+            "rules.lox",
+            &coverage("if (true) print 1;\nelse print 2;\n"),
+        );
+        let mut text = vec![];
+        lcov.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(
+            text,
+            "\
+TN:
+SF:rules.lox
+BRDA:1,0,0,1
+BRDA:1,0,1,0
+BRF:2
+BRH:1
+DA:1,1
+DA:2,0
+LF:2
+LH:1
+end_of_record
+"
+        );
+
+        let mut lcov = Lcov::parse(&text).unwrap();
+        lcov.add(
+            "rules.lox",
+            &coverage("if (false) print 1;\nelse print 2;\n"),
+        );
+        let rules = lcov.file("rules.lox").unwrap();
+        let lines: Vec<(u32, u64)> = rules.lines().iter().map(|(l, h)| (*l, *h)).collect();
+        assert_eq!(lines, [(1, 2), (2, 1)]);
+        let taken: Vec<Option<u64>> = rules.branches().values().copied().collect();
+        assert_eq!(taken, [Some(1), Some(1)]);
+    }
+
+    #[test]
+    fn invalid_tracefiles() {
+        assert!(Lcov::parse("SF:a.lox\nDA:1\nend_of_record\n").is_err());
+        assert!(Lcov::parse("SF:a.lox\nBRDA:1,0,0,x\nend_of_record\n").is_err());
+        assert!(Lcov::parse("SF:a.lox\nDA:1,1\n").is_err());
+        assert!(Lcov::parse("end_of_record\n").is_err());
+        assert!(Lcov::parse("DA:1,1\n").is_err());
+        let lcov = Lcov::parse("TN:\nSF:a.lox\nFN:1,f\nDA:1,2,abc\nLF:1\nend_of_record\n");
+        assert_eq!(lcov.unwrap().file("a.lox").unwrap().lines().len(), 1);
+    }
+}
@@ -0,0 +1,622 @@
+//! Debugging scripts from editors, with the Debug Adapter Protocol over stdin and stdout.
+//!
+//! The editor configures the session and launches a script, which runs with a [`Debugger`]
+//! attached. Whenever the VM stops, that debugger answers the editor's requests until told to
+//! go on. The script can't be paused while running, as nobody reads requests then.
+
+use std::{cell::RefCell, collections::BTreeSet, fs, io, rc::Rc};
+
+use serde_json::{json, Value};
+
+use crate::{
+    debugger::{Debugger, PauseReason, Resume},
+    interpreter::{InterpretError, Interpreter},
+    loxc::LOXC_MAGIC,
+    object::ObjFunction,
+    protocol::{read_message, write_message},
+    vm::VM,
+};
+
+// Scripts have a single thread of execution
+const THREAD_ID: u64 = 1;
+// Variables are listed by reference: the globals, then the locals of each frame, innermost
+// first
+const GLOBALS_REFERENCE: u64 = 1;
+const FIRST_LOCALS_REFERENCE: u64 = 2;
+
+// Exit codes, as the command line gives them
+const EXIT_DATA: u64 = 65;
+const EXIT_SOFTWARE: u64 = 70;
+const EXIT_IO: u64 = 74;
+
+/// Answers the requests read from `input` until the editor disconnects, or the input ends.
+pub fn serve<R: io::BufRead + 'static, W: io::Write + 'static>(
+    input: R,
+    output: W,
+) -> io::Result<()> {
+    let session = Rc::new(RefCell::new(Session::new(input, output)));
+    let mut launched = false;
+    loop {
+        let request = match session.borrow_mut().read()? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        session.borrow_mut().handle(&request, None)?;
+
+        let ready = {
+            let session = session.borrow();
+            if session.disconnected {
+                return Ok(());
+            }
+            session.program.is_some() && session.configured
+        };
+        // Once the editor is done setting the breakpoints
+        if ready && !launched {
+            launched = true;
+            launch(&session)?;
+            if session.borrow().disconnected {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Runs the program being debugged, and tells the editor how it ended.
+fn launch<R: io::BufRead + 'static, W: io::Write + 'static>(
+    session: &Rc<RefCell<Session<R, W>>>,
+) -> io::Result<()> {
+    let (program, stop_on_entry) = {
+        let mut session = session.borrow_mut();
+        session.entry = session.stop_on_entry;
+        let program = session
+            .program
+            .clone()
+            .expect("Only launched with a program");
+        (program, session.stop_on_entry)
+    };
+
+    let output = io::LineWriter::new(Output(session.clone()));
+    let mut interpreter = Interpreter::new(output);
+    interpreter.set_file_name(&program);
+    let vm = interpreter.vm_mut();
+    for &line in &session.borrow().breakpoints {
+        vm.set_breakpoint(line);
+    }
+    let resume = if stop_on_entry {
+        Resume::StepIn
+    } else {
+        Resume::Continue
+    };
+    vm.attach_debugger(Box::new(Adapter(session.clone())), resume);
+
+    let exit_code = match fs::read(&program) {
+        Err(error) => {
+            let message = format!("Could not read '{}': {}\n", program, error);
+            session.borrow_mut().output("stderr", &message)?;
+            EXIT_IO
+        }
+        Ok(bytes) if bytes.starts_with(LOXC_MAGIC) => {
+            match ObjFunction::deserialize(&bytes, interpreter.vm_mut().interner_mut()) {
+                Ok(function) => exit_code(interpreter.run(&function)),
+                Err(error) => {
+                    let message = format!("Could not load '{}': {}\n", program, error);
+                    session.borrow_mut().output("stderr", &message)?;
+                    EXIT_DATA
+                }
+            }
+        }
+        Ok(bytes) => exit_code(interpreter.interpret(&String::from_utf8_lossy(&bytes))),
+    };
+    // Sends what is left of the output, and lets go of the session
+    drop(interpreter);
+
+    let mut session = session.borrow_mut();
+    if !session.disconnected {
+        session.event("exited", json!({ "exitCode": exit_code }))?;
+        session.event("terminated", json!({}))?;
+    }
+    Ok(())
+}
+
+fn exit_code(result: Result<(), InterpretError>) -> u64 {
+    match result {
+        Ok(()) => 0,
+        Err(InterpretError::Compile(_)) => EXIT_DATA,
+        Err(InterpretError::Runtime(_)) => EXIT_SOFTWARE,
+        Err(InterpretError::Io(_)) => EXIT_IO,
+    }
+}
+
+struct Session<R, W> {
+    input: R,
+    output: W,
+    // Of the last message sent
+    seq: u64,
+    program: Option<String>,
+    stop_on_entry: bool,
+    // Whether the editor is done configuring, so the program can start
+    configured: bool,
+    breakpoints: BTreeSet<u32>,
+    // Whether the next stop is the one at the first line, asked for when launching
+    entry: bool,
+    disconnected: bool,
+}
+
+impl<R: io::BufRead, W: io::Write> Session<R, W> {
+    fn new(input: R, output: W) -> Self {
+        Session {
+            input,
+            output,
+            seq: 0,
+            program: None,
+            stop_on_entry: false,
+            configured: false,
+            breakpoints: BTreeSet::new(),
+            entry: false,
+            disconnected: false,
+        }
+    }
+
+    /// Reads the next request, skipping anything else the editor sends.
+    fn read(&mut self) -> io::Result<Option<Value>> {
+        while let Some(message) = read_message(&mut self.input)? {
+            if message["type"] == "request" {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.output, &message)
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn output(&mut self, category: &str, text: &str) -> io::Result<()> {
+        self.event("output", json!({ "category": category, "output": text }))
+    }
+
+    /// Answers a request, with the VM if the script is stopped. Returns how to go on if the
+    /// request was to resume it.
+    fn handle(&mut self, request: &Value, vm: Option<&mut VM>) -> io::Result<Option<Resume>> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+        let stopped = vm.is_some();
+        let mut resume = None;
+
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsEvaluateForHovers": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => match arguments["program"].as_str() {
+                Some(program) => {
+                    self.program = Some(program.to_string());
+                    self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                    Ok(Value::Null)
+                }
+                None => Err(String::from("The launch configuration needs a program")),
+            },
+            "configurationDone" => {
+                self.configured = true;
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => Ok(self.set_breakpoints(arguments, vm)),
+            // There are no exceptions to break on, but editors set them anyway
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" | "scopes" | "variables" | "evaluate" => match vm {
+                Some(vm) => self.inspect(command, arguments, vm),
+                None => Err(String::from("The script isn't stopped")),
+            },
+            "continue" | "next" | "stepIn" | "stepOut" if !stopped => {
+                Err(String::from("The script isn't stopped"))
+            }
+            "continue" => {
+                resume = Some(Resume::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                resume = Some(Resume::StepOver);
+                Ok(Value::Null)
+            }
+            "stepIn" => {
+                resume = Some(Resume::StepIn);
+                Ok(Value::Null)
+            }
+            "stepOut" => {
+                resume = Some(Resume::StepOut);
+                Ok(Value::Null)
+            }
+            "disconnect" | "terminate" => {
+                resume = Some(Resume::Stop);
+                Ok(Value::Null)
+            }
+            _ => Err(format!("Unsupported request '{}'", command)),
+        };
+
+        self.respond(request, result)?;
+        match command {
+            "initialize" => self.event("initialized", json!({}))?,
+            "disconnect" | "terminate" => self.disconnected = true,
+            _ => {}
+        }
+        Ok(resume)
+    }
+
+    /// Replaces the breakpoints with the ones given. Every file gets them, as scripts are a
+    /// single one.
+    fn set_breakpoints(&mut self, arguments: &Value, vm: Option<&mut VM>) -> Value {
+        let lines: Vec<u32> = arguments["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+            .map(|line| line as u32)
+            .collect();
+        self.breakpoints = lines.iter().copied().collect();
+        if let Some(vm) = vm {
+            vm.clear_breakpoints();
+            for &line in &lines {
+                vm.set_breakpoint(line);
+            }
+        }
+
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .map(|line| json!({ "verified": true, "line": line }))
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// Answers the requests looking at the stopped VM.
+    fn inspect(&self, command: &str, arguments: &Value, vm: &VM) -> Result<Value, String> {
+        let variable = |name: &str, value: &crate::value::Value| {
+            json!({
+                "name": name,
+                "value": value.display(vm.heap()).to_string(),
+                "variablesReference": 0,
+            })
+        };
+
+        match command {
+            "stackTrace" => {
+                let frames: Vec<Value> = vm
+                    .call_stack()
+                    .iter()
+                    .enumerate()
+                    .map(|(depth, frame)| {
+                        json!({
+                            "id": depth,
+                            "name": frame.function,
+                            "line": frame.location.line,
+                            "column": frame.location.column,
+                            "source": { "path": self.program },
+                        })
+                    })
+                    .collect();
+                Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+            }
+            "scopes" => {
+                let depth = arguments["frameId"].as_u64().unwrap_or(0);
+                Ok(json!({ "scopes": [
+                    {
+                        "name": "Locals",
+                        "variablesReference": FIRST_LOCALS_REFERENCE + depth,
+                        "expensive": false,
+                    },
+                    {
+                        "name": "Globals",
+                        "variablesReference": GLOBALS_REFERENCE,
+                        "expensive": false,
+                    },
+                ]}))
+            }
+            "variables" => {
+                let variables = match arguments["variablesReference"].as_u64() {
+                    Some(GLOBALS_REFERENCE) => vm.globals(),
+                    Some(reference) if reference >= FIRST_LOCALS_REFERENCE => {
+                        vm.frame_locals((reference - FIRST_LOCALS_REFERENCE) as usize)
+                    }
+                    _ => vec![],
+                };
+                let variables: Vec<Value> = variables
+                    .iter()
+                    .map(|(name, value)| variable(name, value))
+                    .collect();
+                Ok(json!({ "variables": variables }))
+            }
+            // Only variables can be evaluated, as running code could change the program
+            "evaluate" => {
+                let name = arguments["expression"].as_str().unwrap_or_default().trim();
+                match vm.variable(name) {
+                    Some(value) => Ok(json!({
+                        "result": value.display(vm.heap()).to_string(),
+                        "variablesReference": 0,
+                    })),
+                    None => Err(format!("There is no variable '{}' here", name)),
+                }
+            }
+            _ => unreachable!("Only called for inspecting requests"),
+        }
+    }
+
+    /// Tells the editor the script stopped, and answers it until told to go on.
+    fn stopped(&mut self, vm: &mut VM, reason: PauseReason) -> io::Result<Resume> {
+        let reason = match reason {
+            _ if self.entry => "entry",
+            PauseReason::Breakpoint => "breakpoint",
+            PauseReason::Step => "step",
+        };
+        self.entry = false;
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )?;
+
+        loop {
+            let request = match self.read()? {
+                Some(request) => request,
+                // The editor is gone
+                None => {
+                    self.disconnected = true;
+                    return Ok(Resume::Stop);
+                }
+            };
+            if let Some(resume) = self.handle(&request, Some(vm))? {
+                return Ok(resume);
+            }
+        }
+    }
+}
+
+/// The debugger attached to the VM running the program.
+struct Adapter<R, W>(Rc<RefCell<Session<R, W>>>);
+
+impl<R: io::BufRead, W: io::Write> Debugger for Adapter<R, W> {
+    fn paused(&mut self, vm: &mut VM, reason: PauseReason) -> Resume {
+        let mut session = self.0.borrow_mut();
+        session.stopped(vm, reason).unwrap_or_else(|_| {
+            // Without a way to talk to the editor, there is no point in going on
+            session.disconnected = true;
+            Resume::Stop
+        })
+    }
+}
+
+/// Sends what the program prints to the editor.
+struct Output<R, W>(Rc<RefCell<Session<R, W>>>);
+
+impl<R: io::BufRead, W: io::Write> io::Write for Output<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.0.borrow_mut();
+        // Like the error of the script stopping after the editor left
+        if !session.disconnected {
+            session.output("stdout", &String::from_utf8_lossy(buf))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, env, fs, io::Write, rc::Rc};
+
+    use serde_json::{json, Value};
+
+    use super::serve;
+    use crate::protocol::{read_message, write_message};
+
+    const SOURCE: &str = "\
+var a = 1;
+fun f(x) {
+  var y = x * 2;
+  return y;
+}
+var b = f(a);
+print b;";
+
+    /// Output the test can still read once the server is done with it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Sends the requests, made of a command and its arguments, to a server debugging
+    /// `source`. Returns the messages it sent back.
+    fn session(name: &str, source: &str, requests: &[(&str, Value)]) -> Vec<Value> {
+        let path = env::temp_dir().join(format!("rlox_vm_dap_{}_{}", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+        let program = path.to_str().unwrap();
+
+        let mut input = vec![];
+        for (seq, (command, arguments)) in requests.iter().enumerate() {
+            let mut arguments = arguments.clone();
+            if *command == "launch" {
+                arguments["program"] = json!(program);
+            }
+            let request = json!({
+                "seq": seq + 1,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            write_message(&mut input, &request).unwrap();
+        }
+
+        let output = Shared::default();
+        serve(std::io::Cursor::new(input), output.clone()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let bytes = output.0.borrow().clone();
+        let mut bytes = bytes.as_slice();
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut bytes).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    /// The body of the response to the request with that sequence number.
+    fn body(messages: &[Value], request_seq: u64) -> &Value {
+        &messages
+            .iter()
+            .find(|message| message["request_seq"] == request_seq)
+            .expect("Every request gets a response")["body"]
+    }
+
+    /// The events sent, with the reason of the stops.
+    fn events(messages: &[Value]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|message| message["type"] == "event")
+            .map(|message| match message["event"].as_str().unwrap() {
+                "stopped" => format!("stopped {}", message["body"]["reason"].as_str().unwrap()),
+                "output" => format!("output {}", message["body"]["output"]),
+                event => event.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn breakpoints_and_variables() {
+        let messages = session(
+            "breakpoints.lox",
+            SOURCE,
+            &[
+                ("initialize", json!({ "adapterID": "lox" })),
+                ("launch", json!({})),
+                ("setBreakpoints", json!({ "breakpoints": [{ "line": 4 }] })),
+                ("configurationDone", json!({})),
+                ("stackTrace", json!({ "threadId": 1 })),
+                ("scopes", json!({ "frameId": 0 })),
+                ("variables", json!({ "variablesReference": 2 })),
+                ("variables", json!({ "variablesReference": 1 })),
+                ("evaluate", json!({ "expression": "a" })),
+                ("next", json!({ "threadId": 1 })),
+                ("stackTrace", json!({ "threadId": 1 })),
+                ("continue", json!({ "threadId": 1 })),
+                ("disconnect", json!({})),
+            ],
+        );
+
+        assert_eq!(
+            events(&messages),
+            vec![
+                "initialized",
+                "stopped breakpoint",
+                "stopped step",
+                "output \"2\\n\"",
+                "exited",
+                "terminated"
+            ]
+        );
+        assert!(messages
+            .iter()
+            .filter(|message| message["type"] == "response")
+            .all(|message| message["success"] == true));
+        assert_eq!(
+            body(&messages, 3)["breakpoints"],
+            json!([{ "verified": true, "line": 4 }])
+        );
+
+        let frames = &body(&messages, 5)["stackFrames"];
+        assert_eq!(frames[0]["name"], "f");
+        assert_eq!(frames[0]["line"], 4);
+        assert_eq!(frames[1]["name"], "script");
+        assert_eq!(frames[1]["line"], 6);
+        assert_eq!(body(&messages, 6)["scopes"][0]["variablesReference"], 2);
+        assert_eq!(
+            body(&messages, 7)["variables"],
+            json!([
+                { "name": "x", "value": "1", "variablesReference": 0 },
+                { "name": "y", "value": "2", "variablesReference": 0 },
+            ])
+        );
+        assert_eq!(body(&messages, 8)["variables"][0]["name"], "a");
+        assert_eq!(body(&messages, 9)["result"], "1");
+        // Stepping over the return ends the line calling
+        assert_eq!(body(&messages, 11)["stackFrames"][0]["line"], 7);
+    }
+
+    #[test]
+    fn stopping_on_entry() {
+        let messages = session(
+            "entry.lox",
+            "print 1 +\n  nil;",
+            &[
+                ("initialize", json!({})),
+                ("launch", json!({ "stopOnEntry": true })),
+                ("configurationDone", json!({})),
+                ("evaluate", json!({ "expression": "nope" })),
+                ("continue", json!({ "threadId": 1 })),
+            ],
+        );
+        let failed: Vec<&Value> = messages
+            .iter()
+            .filter(|message| message["success"] == false)
+            .collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0]["message"], "There is no variable 'nope' here");
+
+        let events = events(&messages);
+        assert_eq!(events[1], "stopped entry");
+        assert!(events[2].starts_with("output \"error: Operands must be"));
+        let exited = messages
+            .iter()
+            .find(|message| message["event"] == "exited")
+            .unwrap();
+        assert_eq!(exited["body"]["exitCode"], 70);
+    }
+
+    #[test]
+    fn disconnecting_while_stopped() {
+        let messages = session(
+            "disconnect.lox",
+            SOURCE,
+            &[
+                ("initialize", json!({})),
+                ("launch", json!({ "stopOnEntry": true })),
+                ("configurationDone", json!({})),
+                ("disconnect", json!({})),
+                ("threads", json!({})),
+            ],
+        );
+        // Nothing runs after, nor is answered
+        assert_eq!(events(&messages), vec!["initialized", "stopped entry"]);
+        assert_eq!(messages.last().unwrap()["command"], "disconnect");
+    }
+}
@@ -0,0 +1,300 @@
+//! Stopping a running script to step through it and look at its variables.
+//!
+//! The [`VM`] decides when to stop, at line breakpoints or after a step, and hands control to
+//! the [`Debugger`] attached to it. [`Console`] is the interactive front end of `--debug`.
+
+use std::io::{BufRead, Write};
+
+use crate::vm::VM;
+
+/// Why the VM stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseReason {
+    /// A step finished, or the script is starting if the debugger was attached stepping in.
+    Step,
+    Breakpoint,
+}
+
+/// How to go on after stopping. Steps stop at the start of a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Run until the next breakpoint.
+    Continue,
+    /// Stop at the next line, even if it's inside a function being called.
+    StepIn,
+    /// Stop at the next line of the current function, or of its callers.
+    StepOver,
+    /// Stop at the next line started after the current function returns.
+    StepOut,
+    /// Abort the script with a runtime error.
+    Stop,
+}
+
+/// Front end of a debugger, called by the VM whenever it stops.
+pub trait Debugger {
+    /// Inspects the stopped VM, which is about to run the line it stopped at, and decides how to
+    /// go on. It can change the breakpoints, but must not run code on it.
+    fn paused(&mut self, vm: &mut VM, reason: PauseReason) -> Resume;
+}
+
+const HELP: &str = "\
+Commands:
+    c, continue       Run until the next breakpoint
+    s, step           Step to the next line, entering calls
+    n, next           Step to the next line, over calls
+    o, out            Step out of the current function
+    b, break [line]   Set a breakpoint, or list them without a line
+    d, delete <line>  Remove a breakpoint
+    bt, backtrace     Show the calls being run
+    l, locals [n]     Show the locals of the current function, or of the nth caller
+    g, globals        Show the global variables
+    p, print <name>   Show a local of the current function, or a global
+    q, quit           Stop the script
+    h, help           Show this help";
+
+/// Debugger reading commands from `input`, like a terminal, and writing to `output`.
+pub struct Console<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    // To show the line being stopped at, if known
+    source: Option<String>,
+}
+
+impl<R: BufRead, W: Write> Console<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Console {
+            input,
+            output,
+            source: None,
+        }
+    }
+
+    pub fn with_source(input: R, output: W, source: &str) -> Self {
+        Console {
+            input,
+            output,
+            source: Some(source.to_string()),
+        }
+    }
+
+    /// Runs a command, returning how to resume if it's one of the commands doing so.
+    fn command(&mut self, vm: &mut VM, line: &str) -> std::io::Result<Option<Resume>> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let argument = words.next();
+        let line_argument = argument.and_then(|line| line.parse::<u32>().ok());
+
+        match command {
+            "c" | "continue" => return Ok(Some(Resume::Continue)),
+            "s" | "step" => return Ok(Some(Resume::StepIn)),
+            "n" | "next" => return Ok(Some(Resume::StepOver)),
+            "o" | "out" => return Ok(Some(Resume::StepOut)),
+            "q" | "quit" => return Ok(Some(Resume::Stop)),
+            "b" | "break" => match (argument, line_argument) {
+                (None, _) => {
+                    let lines: Vec<String> = vm.breakpoints().iter().map(u32::to_string).collect();
+                    writeln!(self.output, "Breakpoints: {}", lines.join(", "))?;
+                }
+                (_, Some(line)) => {
+                    vm.set_breakpoint(line);
+                    writeln!(self.output, "Breakpoint at line {}", line)?;
+                }
+                _ => writeln!(self.output, "Expected a line number")?,
+            },
+            "d" | "delete" => match line_argument {
+                Some(line) if vm.clear_breakpoint(line) => {
+                    writeln!(self.output, "Removed the breakpoint at line {}", line)?
+                }
+                Some(line) => writeln!(self.output, "There is no breakpoint at line {}", line)?,
+                None => writeln!(self.output, "Expected a line number")?,
+            },
+            "bt" | "backtrace" => {
+                for (depth, frame) in vm.call_stack().iter().enumerate() {
+                    writeln!(
+                        self.output,
+                        "#{} {} at line {}",
+                        depth, frame.function, frame.location.line
+                    )?;
+                }
+            }
+            "l" | "locals" => {
+                let depth = argument.and_then(|depth| depth.parse().ok()).unwrap_or(0);
+                for (name, value) in vm.frame_locals(depth) {
+                    writeln!(self.output, "{} = {}", name, value.display(vm.heap()))?;
+                }
+            }
+            "g" | "globals" => {
+                for (name, value) in vm.globals() {
+                    writeln!(self.output, "{} = {}", name, value.display(vm.heap()))?;
+                }
+            }
+            "p" | "print" => match argument {
+                Some(name) => match vm.variable(name) {
+                    Some(value) => {
+                        writeln!(self.output, "{} = {}", name, value.display(vm.heap()))?
+                    }
+                    None => writeln!(self.output, "There is no variable '{}' here", name)?,
+                },
+                None => writeln!(self.output, "Expected a variable name")?,
+            },
+            "h" | "help" => writeln!(self.output, "{}", HELP)?,
+            _ => writeln!(self.output, "Unknown command '{}', try 'help'", command)?,
+        }
+        Ok(None)
+    }
+
+    fn prompt(&mut self, vm: &mut VM, reason: PauseReason) -> std::io::Result<Resume> {
+        if let Some(frame) = vm.call_stack().first() {
+            let line = frame.location.line;
+            let label = match reason {
+                PauseReason::Breakpoint => "Breakpoint",
+                PauseReason::Step => "Stopped",
+            };
+            write!(
+                self.output,
+                "{} at line {} in {}",
+                label, line, frame.function
+            )?;
+            let text = self
+                .source
+                .as_ref()
+                .and_then(|source| source.lines().nth((line as usize).checked_sub(1)?));
+            match text {
+                Some(text) => writeln!(self.output, ": {}", text.trim())?,
+                None => writeln!(self.output)?,
+            }
+        }
+
+        loop {
+            write!(self.output, "(debug) ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                // Nobody is left to give commands
+                writeln!(self.output)?;
+                return Ok(Resume::Continue);
+            }
+            if let Some(resume) = self.command(vm, &line)? {
+                return Ok(resume);
+            }
+        }
+    }
+}
+
+impl<R: BufRead, W: Write> Debugger for Console<R, W> {
+    fn paused(&mut self, vm: &mut VM, reason: PauseReason) -> Resume {
+        // Without a way to talk to the user, there is no point in stopping
+        self.prompt(vm, reason).unwrap_or(Resume::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use super::{Console, Resume};
+    use crate::{
+        interpreter::{InterpretError, Interpreter},
+        vm::VM,
+    };
+
+    const SOURCE: &str = "\
+var a = 1;
+fun f(x) {
+  var y = x * 2;
+  return y;
+}
+var b = f(a);
+print b;";
+
+    /// Output the test can still read once the console is given to the VM.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs the source with a console typing `commands`. Returns what the console printed, and
+    /// how the script ended.
+    fn debug(
+        commands: &'static str,
+        resume: Resume,
+        setup: impl FnOnce(&mut VM),
+    ) -> (String, Result<(), InterpretError>) {
+        let output = Shared::default();
+        let console = Console::with_source(commands.as_bytes(), output.clone(), SOURCE);
+        let mut interpreter = Interpreter::new(vec![]);
+        let vm = interpreter.vm_mut();
+        setup(vm);
+        vm.attach_debugger(Box::new(console), resume);
+        let result = interpreter.interpret(SOURCE);
+        let printed = output.0.borrow().clone();
+        (String::from_utf8(printed).unwrap(), result)
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (output, result) = debug("l\np y\np a\ng\nbt\nc\n", Resume::Continue, |vm| {
+            vm.set_breakpoint(4)
+        });
+        assert!(result.is_ok());
+        assert_eq!(
+            output,
+            "\
+Breakpoint at line 4 in f: return y;
+(debug) x = 1
+y = 2
+(debug) y = 2
+(debug) a = 1
+(debug) a = 1
+clock = <native 'clock'>
+f = <fn 'f'>
+(debug) #0 f at line 4
+#1 script at line 6
+(debug) "
+        );
+    }
+
+    #[test]
+    fn stepping() {
+        let (output, result) = debug("n\ns\ns\nl\no\nb 2\nb\nd 2\nc\n", Resume::StepIn, |_| {});
+        assert!(result.is_ok());
+        // Declaring a function runs at its closing brace, and stepping out ends the line calling
+        assert_eq!(
+            output,
+            "\
+Stopped at line 1 in script: var a = 1;
+(debug) Stopped at line 5 in script: }
+(debug) Stopped at line 6 in script: var b = f(a);
+(debug) Stopped at line 3 in f: var y = x * 2;
+(debug) x = 1
+(debug) Stopped at line 7 in script: print b;
+(debug) Breakpoint at line 2
+(debug) Breakpoints: 2
+(debug) Removed the breakpoint at line 2
+(debug) "
+        );
+    }
+
+    #[test]
+    fn quitting() {
+        let (output, result) = debug("q\n", Resume::StepIn, |_| {});
+        assert_eq!(output, "Stopped at line 1 in script: var a = 1;\n(debug) ");
+        match result {
+            Err(InterpretError::Runtime(error)) => {
+                assert_eq!(error.message(), "Stopped by the debugger.")
+            }
+            result => panic!("Expected the script to stop, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Human readable listings of compiled code, showing for each operation its offset, source
+//! line and operands, with constants, global names and jump targets resolved.
+
+use std::io::{Result, Write};
+
+use crate::{
+    chunk::{Chunk, OpCode, Operation},
+    interner::Interner,
+    object::ObjFunction,
+    value::Value,
+};
+
+impl ObjFunction {
+    /// Writes the listing of the function, followed by the ones of the functions it declares.
+    pub fn disassemble<W: Write>(&self, interner: &Interner, output: &mut W) -> Result<()> {
+        self.chunk.disassemble(&self.name, interner, output)?;
+        for constant in &self.chunk.constants {
+            if let Value::Function(function) = constant {
+                writeln!(output)?;
+                function.disassemble(interner, output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Chunk {
+    /// Writes the listing of every operation in the chunk, under a header with its name.
+    pub fn disassemble<W: Write>(
+        &self,
+        name: &str,
+        interner: &Interner,
+        output: &mut W,
+    ) -> Result<()> {
+        writeln!(output, "== {} ==", name)?;
+        let mut offset = 0;
+        while offset < self.len() {
+            offset = self.disassemble_instruction(offset, interner, output)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the operation at `offset` in a single line, or a few for closures capturing
+    /// variables. Returns the offset of the next one.
+    ///
+    /// Bytes that aren't a valid operation are listed on their own, so a damaged chunk can
+    /// still be read.
+    pub fn disassemble_instruction<W: Write>(
+        &self,
+        offset: usize,
+        interner: &Interner,
+        output: &mut W,
+    ) -> Result<usize> {
+        write!(output, "{:04} ", offset)?;
+        let line = self.line(offset);
+        if offset > 0 && line == self.line(offset - 1) {
+            write!(output, "   | ")?;
+        } else {
+            write!(output, "{:4} ", line)?;
+        }
+
+        let byte = match self.read_byte(offset) {
+            Some(byte) => byte,
+            None => {
+                writeln!(output, "<end of chunk>")?;
+                return Ok(offset + 1);
+            }
+        };
+        let (opcode, (op, next)) = match (OpCode::from_byte(byte), self.decode(offset)) {
+            (Some(opcode), Some(decoded)) => (opcode, decoded),
+            (Some(opcode), None) => {
+                writeln!(output, "{:?} <truncated>", opcode)?;
+                return Ok(self.len());
+            }
+            (None, _) => {
+                writeln!(output, "<unknown operation code {}>", byte)?;
+                return Ok(offset + 1);
+            }
+        };
+        let name = format!("{:?}", opcode);
+
+        match op {
+            Operation::Constant(index) => writeln!(
+                output,
+                "{:<16} {:4} {}",
+                name,
+                index,
+                self.constant_text(index)
+            )?,
+            Operation::GetGlobal(symbol)
+            | Operation::DefineGlobal(symbol)
+            | Operation::SetGlobal(symbol) => {
+                let global = interner.symbol_name(symbol).unwrap_or("?");
+                writeln!(output, "{:<16} {:4} '{}'", name, symbol, global)?
+            }
+            Operation::GetLocal(slot)
+            | Operation::SetLocal(slot)
+            | Operation::GetUpvalue(slot)
+            | Operation::SetUpvalue(slot) => writeln!(output, "{:<16} {:4}", name, slot)?,
+            Operation::Call(arg_count) => writeln!(output, "{:<16} {:4}", name, arg_count)?,
+            Operation::Jump(distance) | Operation::JumpIfFalse(distance) => writeln!(
+                output,
+                "{:<16} {:4} -> {:04}",
+                name,
+                distance,
+                next + distance
+            )?,
+            Operation::Loop(distance) => {
+                let target = next.wrapping_sub(distance);
+                writeln!(output, "{:<16} {:4} -> {:04}", name, distance, target)?
+            }
+            Operation::Closure(index, upvalues) => {
+                writeln!(
+                    output,
+                    "{:<16} {:4} {}",
+                    name,
+                    index,
+                    self.constant_text(index)
+                )?;
+                let mut at = offset + 4;
+                for upvalue in upvalues {
+                    let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                    writeln!(
+                        output,
+                        "{:04}    |                     {} {}",
+                        at, kind, upvalue.index
+                    )?;
+                    at += 2;
+                }
+            }
+            Operation::Class(index)
+            | Operation::GetProperty(index)
+            | Operation::SetProperty(index)
+            | Operation::Method(index)
+            | Operation::GetSuper(index) => writeln!(
+                output,
+                "{:<16} {:4} {}",
+                name,
+                index,
+                self.constant_text(index)
+            )?,
+            Operation::Invoke(index, arg_count) | Operation::SuperInvoke(index, arg_count) => {
+                writeln!(
+                    output,
+                    "{:<16} {:4} {} ({} args)",
+                    name,
+                    index,
+                    self.constant_text(index),
+                    arg_count
+                )?
+            }
+            Operation::Nil
+            | Operation::True
+            | Operation::False
+            | Operation::Pop
+            | Operation::Equal
+            | Operation::Greater
+            | Operation::Less
+            | Operation::Add
+            | Operation::Substract
+            | Operation::Multiply
+            | Operation::Divide
+            | Operation::Not
+            | Operation::Negate
+            | Operation::Print
+            | Operation::CloseUpvalue
+            | Operation::Inherit
+            | Operation::Return => writeln!(output, "{}", name)?,
+        }
+        Ok(next)
+    }
+
+    fn constant_text(&self, index: usize) -> String {
+        match self.read_constant(index) {
+            Some(Value::String(string)) => format!("'{}'", string.value),
+            Some(Value::Function(function)) => format!("<fn {}>", function.name),
+            Some(constant) => format!("'{}'", constant),
+            None => String::from("<missing>"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::Compiler, interner::Interner};
+
+    fn disassemble(source: &str) -> String {
+        let source = source.to_string();
+        let mut interner = Interner::new();
+        let function = Compiler::from_source(&source, &mut interner)
+            .compile()
+            .expect("This script should compile");
+        let mut output = vec![];
+        function.disassemble(&interner, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn constants_and_globals() {
+        assert_eq!(
+            disassemble("var a = \"hi\";\nprint a + 1;"),
+            "\
+== main ==
+0000    1 Constant            0 'hi'
+0002    | DefineGlobal        0 'a'
+0005    2 GetGlobal           0 'a'
+0008    | Constant            1 '1'
+0010    | Add
+0011    | Print
+0012    | Nil
+0013    | Return
+"
+        );
+    }
+
+    #[test]
+    fn jumps() {
+        assert_eq!(
+            disassemble("while (false) {\n  print 1;\n}"),
+            "\
+== main ==
+0000    1 False
+0001    | JumpIfFalse         7 -> 0011
+0004    | Pop
+0005    2 Constant            0 '1'
+0007    | Print
+0008    3 Loop               11 -> 0000
+0011    | Pop
+0012    | Nil
+0013    | Return
+"
+        );
+    }
+
+    #[test]
+    fn nested_functions() {
+        let listing = disassemble(
+            "fun outer() {\n  var x = 1;\n  fun inner() {\n    return x;\n  }\n  return inner;\n}",
+        );
+        assert_eq!(
+            listing,
+            "\
+== main ==
+0000    7 Closure             0 <fn outer>
+0004    | DefineGlobal        0 'outer'
+0007    | Nil
+0008    | Return
+
+== outer ==
+0000    2 Constant            0 '1'
+0002    5 Closure             1 <fn inner>
+0006    |                     local 0
+0008    6 GetLocal            1
+0010    | Return
+0011    7 Nil
+0012    | Return
+
+== inner ==
+0000    4 GetUpvalue          0
+0002    | Return
+0003    5 Nil
+0004    | Return
+"
+        );
+    }
+
+    #[test]
+    fn damaged_chunks() {
+        let mut chunk = crate::chunk::Chunk::new();
+        chunk.code = vec![200, crate::chunk::OpCode::Constant as u8];
+        let mut output = vec![];
+        chunk
+            .disassemble("broken", &Interner::new(), &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+== broken ==
+0000    0 <unknown operation code 200>
+0001    | Constant <truncated>
+"
+        );
+    }
+}
@@ -0,0 +1,439 @@
+use std::{fmt::Debug, marker::PhantomData, mem::size_of};
+
+use crate::{
+    object::{ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjUpvalue},
+    value::Value,
+};
+
+/// Handle to an object owned by the [`Heap`]. It's just an index, so it's cheap to copy around,
+/// but it only means something for the heap that allocated it.
+pub struct GcRef<T> {
+    index: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T> GcRef<T> {
+    fn new(index: usize) -> Self {
+        GcRef {
+            index,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<T> Clone for GcRef<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for GcRef<T> {}
+
+impl<T> PartialEq for GcRef<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> Eq for GcRef<T> {}
+
+impl<T> Debug for GcRef<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GcRef({})", self.index)
+    }
+}
+
+/// Every kind of object the heap can own. These are the objects that can reference each other,
+/// and therefore form cycles. Strings and function prototypes never point to any of these, so
+/// they keep being reference counted.
+#[derive(Debug)]
+pub enum Object {
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+}
+
+impl Object {
+    fn references(&self, refs: &mut Vec<usize>) {
+        match self {
+            Object::Closure(closure) => closure.references(refs),
+            Object::Upvalue(upvalue) => upvalue.references(refs),
+            Object::Class(class) => class.references(refs),
+            Object::Instance(instance) => instance.references(refs),
+            Object::BoundMethod(bound) => bound.references(refs),
+        }
+    }
+
+    /// Rough amount of memory held by the object, used to decide when to collect.
+    fn size(&self) -> usize {
+        let dynamic = match self {
+            Object::Closure(closure) => closure.upvalues.len() * size_of::<GcRef<ObjUpvalue>>(),
+            Object::Upvalue(_) => 0,
+            Object::Class(class) => {
+                class.name.len()
+                    + class.methods.len() * (size_of::<String>() + size_of::<GcRef<ObjClosure>>())
+            }
+            Object::Instance(instance) => {
+                instance.fields.len() * (size_of::<String>() + size_of::<Value>())
+            }
+            Object::BoundMethod(_) => 0,
+        };
+        size_of::<HeapEntry>() + dynamic
+    }
+}
+
+/// Types that can be stored in the [`Heap`] and accessed back through a typed [`GcRef`].
+pub trait HeapObject: Trace + Sized {
+    fn into_object(self) -> Object;
+    fn from_object(object: &Object) -> Option<&Self>;
+    fn from_object_mut(object: &mut Object) -> Option<&mut Self>;
+}
+
+macro_rules! heap_object {
+    ($type:ty, $variant:ident) => {
+        impl HeapObject for $type {
+            fn into_object(self) -> Object {
+                Object::$variant(self)
+            }
+
+            fn from_object(object: &Object) -> Option<&Self> {
+                match object {
+                    Object::$variant(obj) => Some(obj),
+                    _ => None,
+                }
+            }
+
+            fn from_object_mut(object: &mut Object) -> Option<&mut Self> {
+                match object {
+                    Object::$variant(obj) => Some(obj),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+heap_object!(ObjClosure, Closure);
+heap_object!(ObjUpvalue, Upvalue);
+heap_object!(ObjClass, Class);
+heap_object!(ObjInstance, Instance);
+heap_object!(ObjBoundMethod, BoundMethod);
+
+/// Objects that point to other objects in the heap, and need to keep them alive.
+pub trait Trace {
+    /// Pushes the heap index of every object directly referenced by this one.
+    fn references(&self, refs: &mut Vec<usize>);
+}
+
+impl Trace for ObjClosure {
+    fn references(&self, refs: &mut Vec<usize>) {
+        refs.extend(self.upvalues.iter().map(GcRef::index));
+    }
+}
+
+impl Trace for ObjUpvalue {
+    fn references(&self, refs: &mut Vec<usize>) {
+        if let ObjUpvalue::Closed(value) = self {
+            refs.extend(value.heap_index());
+        }
+    }
+}
+
+impl Trace for ObjClass {
+    fn references(&self, refs: &mut Vec<usize>) {
+        refs.extend(self.methods.values().map(GcRef::index));
+    }
+}
+
+impl Trace for ObjInstance {
+    fn references(&self, refs: &mut Vec<usize>) {
+        refs.push(self.class.index());
+        refs.extend(self.fields.values().filter_map(Value::heap_index));
+    }
+}
+
+impl Trace for ObjBoundMethod {
+    fn references(&self, refs: &mut Vec<usize>) {
+        refs.extend(self.receiver.heap_index());
+        refs.push(self.method.index());
+    }
+}
+
+/// Memory settings of the garbage collector.
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    /// Bytes the heap can hold before the first collection.
+    pub initial_threshold: usize,
+    /// After a collection, the heap can grow up to the surviving bytes times this factor before
+    /// collecting again. A factor of 0 collects on every allocation, handy to flush out objects
+    /// that are not properly rooted.
+    pub growth_factor: usize,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            initial_threshold: 1024 * 1024,
+            growth_factor: 2,
+        }
+    }
+}
+
+impl GcConfig {
+    pub fn stress() -> Self {
+        GcConfig {
+            initial_threshold: 0,
+            growth_factor: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HeapEntry {
+    object: Object,
+    marked: bool,
+    // What the object accounted for when allocated, objects can grow afterwards
+    size: usize,
+}
+
+/// Owner of every object that can take part in a reference cycle. Objects are freed by a
+/// mark-and-sweep collection: whoever owns the roots (the VM) marks them, and everything not
+/// reachable from them gets swept.
+#[derive(Debug)]
+pub struct Heap {
+    entries: Vec<Option<HeapEntry>>,
+    free_slots: Vec<usize>,
+    gray: Vec<usize>,
+
+    config: GcConfig,
+    bytes_allocated: usize,
+    // Memory held outside the heap that counts toward the next collection, see
+    // `set_external_bytes`
+    external_bytes: usize,
+    next_gc: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new(GcConfig::default())
+    }
+}
+
+impl Heap {
+    pub fn new(config: GcConfig) -> Self {
+        Heap {
+            entries: vec![],
+            free_slots: vec![],
+            gray: vec![],
+            config,
+            bytes_allocated: 0,
+            external_bytes: 0,
+            next_gc: config.initial_threshold,
+        }
+    }
+
+    pub fn alloc<T: HeapObject>(&mut self, object: T) -> GcRef<T> {
+        let object = object.into_object();
+        let size = object.size();
+        self.bytes_allocated += size;
+        let entry = Some(HeapEntry {
+            object,
+            marked: false,
+            size,
+        });
+
+        let index = match self.free_slots.pop() {
+            Some(index) => {
+                self.entries[index] = entry;
+                index
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
+        };
+        GcRef::new(index)
+    }
+
+    pub fn get<T: HeapObject>(&self, reference: GcRef<T>) -> &T {
+        self.entries
+            .get(reference.index)
+            .and_then(Option::as_ref)
+            .and_then(|entry| T::from_object(&entry.object))
+            .expect("Dangling reference to a heap object")
+    }
+
+    pub fn get_mut<T: HeapObject>(&mut self, reference: GcRef<T>) -> &mut T {
+        self.entries
+            .get_mut(reference.index)
+            .and_then(Option::as_mut)
+            .and_then(|entry| T::from_object_mut(&mut entry.object))
+            .expect("Dangling reference to a heap object")
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.bytes_allocated + self.external_bytes > self.next_gc || self.config.growth_factor == 0
+    }
+
+    /// Memory held outside the heap but freed by its collections, like the interned strings,
+    /// so it counts toward when to collect too.
+    pub fn set_external_bytes(&mut self, bytes: usize) {
+        self.external_bytes = bytes;
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.entries.len() - self.free_slots.len()
+    }
+
+    /// Marks the object a value points to, if any, as reachable.
+    pub fn mark_value(&mut self, value: &Value) {
+        if let Some(index) = value.heap_index() {
+            self.mark_index(index);
+        }
+    }
+
+    pub fn mark<T>(&mut self, reference: GcRef<T>) {
+        self.mark_index(reference.index);
+    }
+
+    /// Marks everything referenced by an object that is not in the heap yet.
+    pub fn mark_references<T: HeapObject>(&mut self, object: &T) {
+        let mut refs = vec![];
+        object.references(&mut refs);
+        for index in refs {
+            self.mark_index(index);
+        }
+    }
+
+    fn mark_index(&mut self, index: usize) {
+        if let Some(Some(entry)) = self.entries.get_mut(index) {
+            if !entry.marked {
+                entry.marked = true;
+                self.gray.push(index);
+            }
+        }
+    }
+
+    /// Finishes a collection once the roots were marked: traces everything reachable from them
+    /// and frees the rest. Returns the amount of bytes freed.
+    pub fn collect(&mut self) -> usize {
+        let mut refs = vec![];
+        while let Some(index) = self.gray.pop() {
+            if let Some(Some(entry)) = self.entries.get(index) {
+                entry.object.references(&mut refs);
+            }
+            for reference in refs.drain(..) {
+                self.mark_index(reference);
+            }
+        }
+
+        let before = self.bytes_allocated;
+        for (index, slot) in self.entries.iter_mut().enumerate() {
+            match slot {
+                Some(entry) if entry.marked => entry.marked = false,
+                Some(entry) => {
+                    self.bytes_allocated -= entry.size;
+                    *slot = None;
+                    self.free_slots.push(index);
+                }
+                None => {}
+            }
+        }
+
+        self.next_gc = ((self.bytes_allocated + self.external_bytes) * self.config.growth_factor)
+            .max(self.config.initial_threshold);
+        before - self.bytes_allocated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GcConfig, Heap};
+    use crate::{
+        object::{ObjClass, ObjInstance, ObjUpvalue},
+        value::Value,
+    };
+
+    #[test]
+    fn unreachable_objects_are_freed() {
+        let mut heap = Heap::new(GcConfig::default());
+        let kept = heap.alloc(ObjClass::new("Kept"));
+        heap.alloc(ObjClass::new("Dropped"));
+        assert_eq!(heap.object_count(), 2);
+
+        heap.mark(kept);
+        heap.collect();
+
+        assert_eq!(heap.object_count(), 1);
+        assert_eq!(heap.get(kept).name, "Kept");
+    }
+
+    #[test]
+    fn references_are_traced() {
+        let mut heap = Heap::new(GcConfig::default());
+        let class = heap.alloc(ObjClass::new("Point"));
+        let instance = heap.alloc(ObjInstance::new(class));
+        let upvalue = heap.alloc(ObjUpvalue::Closed(Value::Instance(instance)));
+
+        heap.mark(upvalue);
+        heap.collect();
+
+        assert_eq!(heap.object_count(), 3);
+    }
+
+    #[test]
+    fn cycles_are_freed() {
+        let mut heap = Heap::new(GcConfig::default());
+        let class = heap.alloc(ObjClass::new("Node"));
+        let a = heap.alloc(ObjInstance::new(class));
+        let b = heap.alloc(ObjInstance::new(class));
+        heap.get_mut(a).fields.insert("next".to_string(), Value::Instance(b));
+        heap.get_mut(b).fields.insert("next".to_string(), Value::Instance(a));
+
+        let freed = heap.collect();
+
+        assert_eq!(heap.object_count(), 0);
+        assert_eq!(heap.bytes_allocated(), 0);
+        assert!(freed > 0);
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut heap = Heap::new(GcConfig::default());
+        heap.alloc(ObjClass::new("Dropped"));
+        heap.collect();
+
+        let class = heap.alloc(ObjClass::new("New"));
+
+        assert_eq!(class.index(), 0);
+        assert_eq!(heap.object_count(), 1);
+    }
+
+    #[test]
+    fn threshold() {
+        let mut heap = Heap::new(GcConfig {
+            initial_threshold: 1024,
+            growth_factor: 2,
+        });
+        assert!(!heap.should_collect());
+
+        while heap.bytes_allocated() <= 1024 {
+            heap.alloc(ObjClass::new("Filler"));
+        }
+        assert!(heap.should_collect());
+
+        heap.collect();
+        assert!(!heap.should_collect());
+        assert!(Heap::new(GcConfig::stress()).should_collect());
+    }
+}
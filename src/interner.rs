@@ -0,0 +1,176 @@
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+    mem::size_of,
+    rc::Rc,
+};
+
+use crate::object::ObjString;
+
+/// Slot of a global variable, shared by every chunk compiled with the same [`Interner`].
+pub type SymbolId = usize;
+
+// Lets the set of strings be looked up by their contents
+#[derive(Debug)]
+struct Interned(Rc<ObjString>);
+
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.value.hash(state)
+    }
+}
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.value == other.0.value
+    }
+}
+
+impl Eq for Interned {}
+
+impl Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        &self.0.value
+    }
+}
+
+/// Keeps a single copy of every string, so strings can be compared by pointer. It also gives
+/// each global variable name a slot, so globals are accessed by index instead of by name.
+///
+/// The compiler and the VM running its output must use the same interner.
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Interned>,
+    symbols: Vec<Rc<ObjString>>,
+    symbol_ids: HashMap<Interned, SymbolId>,
+    // Memory taken by the strings, see `bytes`
+    bytes: usize,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, value: &str) -> Rc<ObjString> {
+        match self.strings.get(value) {
+            Some(interned) => Rc::clone(&interned.0),
+            None => self.insert(Rc::new(ObjString::from(value))),
+        }
+    }
+
+    pub fn intern_owned(&mut self, value: String) -> Rc<ObjString> {
+        match self.strings.get(value.as_str()) {
+            Some(interned) => Rc::clone(&interned.0),
+            None => self.insert(Rc::new(ObjString::from_owned(value))),
+        }
+    }
+
+    /// Returns the interned copy of a string that may have been created elsewhere.
+    pub fn intern_rc(&mut self, string: Rc<ObjString>) -> Rc<ObjString> {
+        match self.strings.get(string.value.as_str()) {
+            Some(interned) => Rc::clone(&interned.0),
+            None => self.insert(string),
+        }
+    }
+
+    fn insert(&mut self, string: Rc<ObjString>) -> Rc<ObjString> {
+        self.bytes += Self::size(&string);
+        self.strings.insert(Interned(Rc::clone(&string)));
+        string
+    }
+
+    /// Slot of the global variable called `name`, assigning a new one the first time.
+    pub fn symbol(&mut self, name: &str) -> SymbolId {
+        if let Some(id) = self.symbol_ids.get(name) {
+            return *id;
+        }
+
+        let name = self.intern(name);
+        let id = self.symbols.len();
+        self.symbols.push(Rc::clone(&name));
+        self.symbol_ids.insert(Interned(name), id);
+        id
+    }
+
+    pub fn symbol_name(&self, id: SymbolId) -> Option<&str> {
+        self.symbols.get(id).map(|name| name.value.as_str())
+    }
+
+    pub fn symbol_count(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn string_count(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Rough amount of memory held by the interned strings, so the garbage collector can
+    /// count it.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn size(string: &ObjString) -> usize {
+        size_of::<ObjString>() + string.value.len()
+    }
+
+    /// Forgets the strings nobody else is using anymore.
+    pub fn sweep(&mut self) {
+        let bytes = &mut self.bytes;
+        self.strings.retain(|interned| {
+            let used = Rc::strong_count(&interned.0) > 1;
+            if !used {
+                *bytes -= Self::size(&interned.0);
+            }
+            used
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::Interner;
+
+    #[test]
+    fn strings_are_shared() {
+        let mut interner = Interner::new();
+        let a = interner.intern("hello");
+        let b = interner.intern_owned(String::from("hello"));
+        let c = interner.intern("world");
+
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &c));
+        assert_eq!(interner.string_count(), 2);
+    }
+
+    #[test]
+    fn symbols() {
+        let mut interner = Interner::new();
+        let a = interner.symbol("a");
+        let b = interner.symbol("b");
+
+        assert_eq!(interner.symbol("a"), a);
+        assert_ne!(a, b);
+        assert_eq!(interner.symbol_name(b), Some("b"));
+        assert_eq!(interner.symbol_name(2), None);
+    }
+
+    #[test]
+    fn unused_strings_are_swept() {
+        let mut interner = Interner::new();
+        let kept = interner.intern("kept");
+        interner.intern("dropped");
+        interner.symbol("global");
+
+        let bytes = interner.bytes();
+        interner.sweep();
+
+        assert_eq!(interner.string_count(), 2);
+        assert!(interner.bytes() < bytes);
+        assert!(Rc::ptr_eq(&kept, &interner.intern("kept")));
+    }
+}
@@ -1,34 +1,130 @@
-use std::io::Write;
+use std::{fmt::Display, io::Write};
 
-use crate::{compiler::Compiler, vm::VM};
+use crate::{
+    compiler::Compiler,
+    diagnostic::Diagnostic,
+    object::ObjFunction,
+    render::{Renderer, Style},
+    vm::{RuntimeError, VM},
+};
+
+/// Why running some code failed. The interpreter already reported it to its error output.
+#[derive(Debug)]
+pub enum InterpretError {
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+    /// The error couldn't be reported, as writing to the output failed.
+    Io(std::io::Error),
+}
+
+impl Display for InterpretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InterpretError::Compile(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            InterpretError::Runtime(error) => write!(f, "{}", error),
+            InterpretError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<std::io::Error> for InterpretError {
+    fn from(error: std::io::Error) -> Self {
+        InterpretError::Io(error)
+    }
+}
 
 pub struct Interpreter<W: Write> {
     vm: VM,
     output: W,
+    // How errors are reported, and where, if not along with the output
+    file_name: String,
+    style: Style,
+    errors: Option<Box<dyn Write>>,
 }
 
 impl<W: Write> Interpreter<W> {
     pub fn new(output: W) -> Self {
+        Self::with_style(output, Style::Plain)
+    }
+
+    pub fn with_style(output: W, style: Style) -> Self {
         Self {
             vm: VM::new(),
             output,
+            file_name: String::from("<script>"),
+            style,
+            errors: None,
         }
     }
 
-    pub fn interpret(&mut self, raw_source: &str) {
+    /// Name of the file being run, shown when reporting errors.
+    pub fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_string();
+    }
+
+    /// Reports errors to `errors` instead of the output, keeping them apart from what the
+    /// script prints.
+    pub fn set_error_output(&mut self, errors: Box<dyn Write>) {
+        self.errors = Some(errors);
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    /// Runs an already compiled script, like one loaded from a `.loxc` file. Its strings must
+    /// have been interned by this interpreter's VM.
+    pub fn run(&mut self, function: &ObjFunction) -> Result<(), InterpretError> {
+        // There is no source to point to
+        self.run_compiled(function, "")
+    }
+
+    /// Compiles and runs the source, reporting any error to the error output.
+    pub fn interpret(&mut self, raw_source: &str) -> Result<(), InterpretError> {
         let source = String::from(raw_source);
-        let mut compiler = Compiler::from_source(&source);
-        let function = compiler.compile();
-
-        if !compiler.had_error {
-            match self.vm.run_main(&function, &mut self.output) {
-                Ok(_ret) => (), // I do nothing for now
-                Err(msg) => {
-                    writeln!(self.output, "[Runime Error] {}", msg).unwrap();
-                }
-            };
-        } else {
-            writeln!(self.output, "Compiler error!").unwrap();
+        let mut compiler = Compiler::from_source(&source, self.vm.interner_mut());
+
+        match compiler.compile() {
+            Ok(function) => self.run_compiled(&function, raw_source),
+            Err(diagnostics) => {
+                let renderer = Renderer::new(&self.file_name, raw_source, self.style);
+                let rendered: String = diagnostics
+                    .iter()
+                    .map(|diagnostic| renderer.diagnostic(diagnostic))
+                    .collect();
+                self.report(&rendered)?;
+                Err(InterpretError::Compile(diagnostics))
+            }
+        }
+    }
+
+    fn run_compiled(&mut self, function: &ObjFunction, source: &str) -> Result<(), InterpretError> {
+        match self.vm.run_main(function, &mut self.output) {
+            Ok(()) => Ok(()),
+            Err(error) => {
+                let renderer = Renderer::new(&self.file_name, source, self.style);
+                self.report(&renderer.runtime_error(&error))?;
+                Err(InterpretError::Runtime(error))
+            }
+        }
+    }
+
+    fn report(&mut self, error: &str) -> std::io::Result<()> {
+        match self.errors.as_mut() {
+            Some(errors) => errors.write_all(error.as_bytes()),
+            None => self.output.write_all(error.as_bytes()),
         }
     }
 }
@@ -1,5 +1,6 @@
 pub mod chunk;
 pub mod compiler;
+pub mod diagnostic;
 pub mod scanner;
 pub mod token;
 pub mod vm;
@@ -7,4 +8,18 @@ pub mod value;
 pub mod stack;
 pub mod object;
 pub mod native;
+pub mod interner;
 pub mod interpreter;
+pub mod render;
+pub mod heap;
+pub mod loxc;
+pub mod disassembler;
+pub mod assembler;
+pub mod verifier;
+pub mod debugger;
+pub mod protocol;
+pub mod dap;
+pub mod symbols;
+pub mod lsp;
+pub mod profiler;
+pub mod coverage;
@@ -0,0 +1,466 @@
+//! Compiled scripts stored as `.loxc` files, so they can be run without compiling them again.
+//!
+//! Every number is big-endian, and strings are a `u32` length followed by their UTF-8 bytes.
+//!
+//! ```text
+//! file     := "LOXC" version:u16 symbol_count:u32 string* function
+//! function := name:string arity:u8 upvalue_count:u8
+//!             code_len:u32 byte*
+//!             run_count:u32 (length:u32 line:u32 column:u32)*
+//!             constant_count:u32 constant*
+//! constant := 0 (nil) | 1 u8 (boolean) | 2 f64 (number) | 3 string | 4 function
+//! ```
+//!
+//! Globals are referred by symbol, which only means something for the interner the code was
+//! compiled with. The file carries the names of the symbols it uses, and the code refers to
+//! them by their position there. Loading the file maps them back to the interner of the VM.
+
+use std::{collections::HashMap, fmt::Display, rc::Rc};
+
+use crate::{
+    chunk::{Chunk, SourceLocation},
+    interner::{Interner, SymbolId},
+    object::ObjFunction,
+    value::Value,
+};
+
+pub const LOXC_MAGIC: &[u8; 4] = b"LOXC";
+/// Version of the format written by [`ObjFunction::serialize`]. Files of other versions are
+/// rejected, as the meaning of the code can change between them.
+pub const LOXC_VERSION: u16 = 1;
+
+/// How deep functions can be declared inside each other in a file, so loading a malicious one
+/// can't exhaust the native stack.
+const MAX_FUNCTION_DEPTH: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOLEAN: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_FUNCTION: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoxcError {
+    /// The data doesn't start like a `.loxc` file.
+    NotLoxc,
+    UnsupportedVersion(u16),
+    /// The data ended in the middle of something.
+    Truncated,
+    Malformed(String),
+    /// The function holds a value that only exists while running, like a closure.
+    Unserializable(String),
+}
+
+impl Display for LoxcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoxcError::NotLoxc => f.write_str("Not a compiled Lox file."),
+            LoxcError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported compiled Lox version {}, expected {}.",
+                version, LOXC_VERSION
+            ),
+            LoxcError::Truncated => f.write_str("The compiled Lox file is truncated."),
+            LoxcError::Malformed(message) => write!(f, "Malformed compiled Lox file: {}", message),
+            LoxcError::Unserializable(value) => {
+                write!(f, "Can't store {} in a compiled file.", value)
+            }
+        }
+    }
+}
+
+fn malformed(message: &str) -> LoxcError {
+    LoxcError::Malformed(message.to_string())
+}
+
+impl ObjFunction {
+    /// Encodes the function, and every function declared inside it, as a `.loxc` file. The
+    /// `interner` must be the one the function was compiled with.
+    pub fn serialize(&self, interner: &Interner) -> Result<Vec<u8>, LoxcError> {
+        let mut writer = Writer::default();
+        let mut symbols = SymbolTable::default();
+        let mut body = Writer::default();
+        body.function(self, interner, &mut symbols)?;
+
+        writer.bytes(LOXC_MAGIC);
+        writer.u16(LOXC_VERSION);
+        writer.u32(symbols.names.len());
+        for name in &symbols.names {
+            writer.string(name);
+        }
+        writer.bytes(&body.output);
+        Ok(writer.output)
+    }
+
+    /// Loads a function written by [`ObjFunction::serialize`], interning its strings and
+    /// globals into `interner`, which must be the one of the VM that will run it. The code is
+    /// checked to be well formed, so a damaged file is reported instead of running.
+    pub fn deserialize(bytes: &[u8], interner: &mut Interner) -> Result<ObjFunction, LoxcError> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader
+            .take(LOXC_MAGIC.len())
+            .map_err(|_| LoxcError::NotLoxc)?
+            != LOXC_MAGIC
+        {
+            return Err(LoxcError::NotLoxc);
+        }
+        let version = reader.u16()?;
+        if version != LOXC_VERSION {
+            return Err(LoxcError::UnsupportedVersion(version));
+        }
+
+        let symbol_count = reader.u32()?;
+        let mut symbols = vec![];
+        for _ in 0..symbol_count {
+            let name = reader.string()?;
+            symbols.push(interner.symbol(&name));
+        }
+
+        let function = reader.function(interner, &symbols, 0)?;
+        if reader.position != bytes.len() {
+            return Err(malformed("unexpected data after the script."));
+        }
+        function
+            .verify()
+            .map_err(|error| LoxcError::Malformed(error.to_string()))?;
+        Ok(function)
+    }
+}
+
+/// Symbols used by the functions being written, numbered in the order they are found.
+#[derive(Default)]
+struct SymbolTable {
+    names: Vec<String>,
+    indexes: HashMap<SymbolId, SymbolId>,
+}
+
+#[derive(Default)]
+struct Writer {
+    output: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.output.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: usize) {
+        self.bytes(&(value as u32).to_be_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len());
+        self.bytes(value.as_bytes());
+    }
+
+    fn function(
+        &mut self,
+        function: &ObjFunction,
+        interner: &Interner,
+        symbols: &mut SymbolTable,
+    ) -> Result<(), LoxcError> {
+        let mut chunk = function.chunk.clone();
+        chunk
+            .remap_globals(|symbol| {
+                if let Some(index) = symbols.indexes.get(&symbol) {
+                    return Some(*index);
+                }
+                let name = interner.symbol_name(symbol)?;
+                symbols.names.push(name.to_string());
+                symbols.indexes.insert(symbol, symbols.names.len() - 1);
+                Some(symbols.names.len() - 1)
+            })
+            .ok_or_else(|| LoxcError::Unserializable(format!("the code of '{}'", function.name)))?;
+
+        self.string(&function.name);
+        self.u8(function.arity);
+        self.u8(function.upvalue_count as u8);
+
+        self.u32(chunk.code.len());
+        self.bytes(&chunk.code);
+
+        let runs = location_runs(chunk.locations());
+        self.u32(runs.len());
+        for (length, location) in runs {
+            self.u32(length);
+            self.u32(location.line as usize);
+            self.u32(location.column as usize);
+        }
+
+        self.u32(chunk.constants.len());
+        for constant in &chunk.constants {
+            match constant {
+                Value::Nil => self.u8(TAG_NIL),
+                Value::Boolean(b) => {
+                    self.u8(TAG_BOOLEAN);
+                    self.u8(*b as u8);
+                }
+                Value::Number(n) => {
+                    self.u8(TAG_NUMBER);
+                    self.bytes(&n.to_be_bytes());
+                }
+                Value::String(s) => {
+                    self.u8(TAG_STRING);
+                    self.string(&s.value);
+                }
+                Value::Function(nested) => {
+                    self.u8(TAG_FUNCTION);
+                    self.function(nested, interner, symbols)?;
+                }
+                other => return Err(LoxcError::Unserializable(other.to_string())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Groups consecutive bytes coming from the same place, as most operations span a few bytes.
+fn location_runs(locations: &[SourceLocation]) -> Vec<(usize, SourceLocation)> {
+    let mut runs: Vec<(usize, SourceLocation)> = vec![];
+    for location in locations {
+        match runs.last_mut() {
+            Some((length, last)) if last == location => *length += 1,
+            _ => runs.push((1, *location)),
+        }
+    }
+    runs
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], LoxcError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .ok_or(LoxcError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(LoxcError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoxcError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoxcError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize, LoxcError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, LoxcError> {
+        let length = self.u32()?;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| malformed("a string is not valid UTF-8."))
+    }
+
+    fn function(
+        &mut self,
+        interner: &mut Interner,
+        symbols: &[SymbolId],
+        depth: usize,
+    ) -> Result<ObjFunction, LoxcError> {
+        if depth > MAX_FUNCTION_DEPTH {
+            return Err(malformed("functions are nested too deep."));
+        }
+
+        let mut function = ObjFunction::new(&self.string()?);
+        function.arity = self.u8()?;
+        function.upvalue_count = self.u8()? as usize;
+
+        let code_len = self.u32()?;
+        let code = self.take(code_len)?.to_vec();
+
+        let run_count = self.u32()?;
+        let mut locations = vec![];
+        for _ in 0..run_count {
+            let length = self.u32()?;
+            let location = SourceLocation {
+                line: self.u32()? as u32,
+                column: self.u32()? as u32,
+            };
+            if locations.len() + length > code.len() {
+                return Err(malformed("there are more locations than code."));
+            }
+            locations.resize(locations.len() + length, location);
+        }
+        if locations.len() != code.len() {
+            return Err(malformed("some code has no location."));
+        }
+
+        // Each constant takes at least a byte, don't trust the count to allocate
+        let constant_count = self.u32()?;
+        let mut constants = vec![];
+        for _ in 0..constant_count {
+            let constant = match self.u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_BOOLEAN => Value::Boolean(self.u8()? != 0),
+                TAG_NUMBER => {
+                    let bytes = self.take(8)?;
+                    let mut number = [0; 8];
+                    number.copy_from_slice(bytes);
+                    Value::Number(f64::from_be_bytes(number))
+                }
+                TAG_STRING => Value::String(interner.intern_owned(self.string()?)),
+                TAG_FUNCTION => {
+                    Value::Function(Rc::new(self.function(interner, symbols, depth + 1)?))
+                }
+                tag => {
+                    return Err(LoxcError::Malformed(format!(
+                        "unknown constant kind {}.",
+                        tag
+                    )))
+                }
+            };
+            constants.push(constant);
+        }
+
+        function.chunk = Chunk::from_parts(code, constants, locations);
+        function
+            .chunk
+            .remap_globals(|index| symbols.get(index).copied())
+            .ok_or_else(|| malformed("invalid code, or an unknown global variable."))?;
+        Ok(function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoxcError, LOXC_VERSION};
+    use crate::{
+        chunk::Operation, compiler::Compiler, interner::Interner, object::ObjFunction, value::Value,
+    };
+
+    fn compile(source: &str, interner: &mut Interner) -> ObjFunction {
+        let source = source.to_string();
+        Compiler::from_source(&source, interner).compile().unwrap()
+    }
+
+    const SCRIPT: &str = "
+var greeting = \"hi\";
+fun outer(a) {
+    var b = 2;
+    fun inner() { return a + b; }
+    return inner;
+}
+class Point {
+    init(x) { this.x = x; }
+    get() { return this.x; }
+}
+for (var i = 0; i < 3; i = i + 1) print outer(i)();
+print Point(1.5).get();";
+
+    #[test]
+    fn round_trip() {
+        let mut interner = Interner::new();
+        let function = compile(SCRIPT, &mut interner);
+        let bytes = function.serialize(&interner).unwrap();
+
+        let loaded = ObjFunction::deserialize(&bytes, &mut interner).unwrap();
+
+        assert_eq!(loaded.chunk, function.chunk);
+        assert_eq!(loaded.chunk.locations(), function.chunk.locations());
+        assert_eq!(loaded.serialize(&interner).unwrap(), bytes);
+    }
+
+    #[test]
+    fn globals_are_mapped_to_the_new_interner() {
+        let mut interner = Interner::new();
+        let function = compile("var a = 1; print a;", &mut interner);
+        let bytes = function.serialize(&interner).unwrap();
+
+        let mut other = Interner::new();
+        other.symbol("taken");
+        let loaded = ObjFunction::deserialize(&bytes, &mut other).unwrap();
+
+        assert_eq!(other.symbol_name(1), Some("a"));
+        assert_eq!(loaded.chunk.operations()[1], Operation::DefineGlobal(1));
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let mut interner = Interner::new();
+        let bytes = compile(SCRIPT, &mut interner).serialize(&interner).unwrap();
+
+        for length in 0..bytes.len() {
+            let result = ObjFunction::deserialize(&bytes[..length], &mut interner);
+            assert!(result.is_err(), "Loaded a file truncated at {}", length);
+        }
+    }
+
+    #[test]
+    fn invalid_files_are_rejected() {
+        let mut interner = Interner::new();
+        let bytes = compile("print 1;", &mut interner)
+            .serialize(&interner)
+            .unwrap();
+
+        assert_eq!(
+            ObjFunction::deserialize(b"print 1;", &mut interner).unwrap_err(),
+            LoxcError::NotLoxc
+        );
+
+        let mut version = bytes.clone();
+        version[5] += 1;
+        assert_eq!(
+            ObjFunction::deserialize(&version, &mut interner).unwrap_err(),
+            LoxcError::UnsupportedVersion(LOXC_VERSION + 1)
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(ObjFunction::deserialize(&trailing, &mut interner).is_err());
+    }
+
+    #[test]
+    fn malformed_code_is_rejected() {
+        let mut interner = Interner::new();
+        let mut function = ObjFunction::from_operations(
+            "main",
+            &mut vec![Operation::Jump(10), Operation::Nil, Operation::Return],
+        );
+        let bytes = function.serialize(&interner).unwrap();
+        assert!(matches!(
+            ObjFunction::deserialize(&bytes, &mut interner),
+            Err(LoxcError::Malformed(_))
+        ));
+
+        function = ObjFunction::from_operations(
+            "main",
+            &mut vec![Operation::Constant(3), Operation::Return],
+        );
+        function.chunk.add_constant(Value::Nil);
+        let bytes = function.serialize(&interner).unwrap();
+        assert!(matches!(
+            ObjFunction::deserialize(&bytes, &mut interner),
+            Err(LoxcError::Malformed(_))
+        ));
+
+        // Well formed operations can still misuse the stack
+        function = ObjFunction::from_operations("main", &mut vec![Operation::Add, Operation::Return]);
+        let bytes = function.serialize(&interner).unwrap();
+        assert_eq!(
+            ObjFunction::deserialize(&bytes, &mut interner).unwrap_err().to_string(),
+            "Malformed compiled Lox file: Stack underflow at 0000 in 'main'."
+        );
+    }
+}
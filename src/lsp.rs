@@ -0,0 +1,460 @@
+//! Language server for editing Lox scripts, speaking the Language Server Protocol over stdin
+//! and stdout.
+//!
+//! Every open document is compiled whenever it changes, to report its errors, and whenever the
+//! editor asks about it, to find out where its names are declared from the
+//! [symbol index](crate::symbols).
+
+use std::{collections::HashMap, io};
+
+use serde_json::{json, Value};
+
+use crate::{
+    compiler::Compiler,
+    diagnostic::{Diagnostic, Severity, Span},
+    interner::Interner,
+    protocol::{read_message, write_message},
+    symbols::{Declaration, SymbolIndex, SymbolKind},
+};
+
+// Error codes of JSON-RPC
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Answers the requests read from `input` until the editor asks to exit, or the input ends.
+pub fn serve<R: io::BufRead, W: io::Write>(input: R, output: W) -> io::Result<()> {
+    let mut server = Server {
+        input,
+        output,
+        documents: HashMap::new(),
+    };
+    while let Some(message) = read_message(&mut server.input)? {
+        if message["method"] == "exit" {
+            break;
+        }
+        server.handle(&message)?;
+    }
+    Ok(())
+}
+
+struct Server<R, W> {
+    input: R,
+    output: W,
+    // Text of each open document, by URI
+    documents: HashMap<String, String>,
+}
+
+impl<R: io::BufRead, W: io::Write> Server<R, W> {
+    fn handle(&mut self, message: &Value) -> io::Result<()> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+
+        // Notifications have no id, and get no response
+        if message.get("id").is_none() {
+            match method {
+                "textDocument/didOpen" => {
+                    let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                    self.update(uri, text.to_string())?;
+                }
+                // Documents are always synchronized whole, so the last change has all the text
+                "textDocument/didChange" => {
+                    let changes = params["contentChanges"].as_array();
+                    let text = changes.and_then(|changes| changes.last()?["text"].as_str());
+                    if let Some(text) = text {
+                        self.update(uri, text.to_string())?;
+                    }
+                }
+                "textDocument/didClose" => {
+                    self.documents.remove(uri);
+                    self.publish_diagnostics(uri, vec![])?;
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "lox", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/definition" | "textDocument/hover" | "textDocument/documentSymbol" => {
+                match self.documents.get(uri) {
+                    Some(source) => Ok(answer(method, uri, source, params)),
+                    None => Err((INVALID_PARAMS, format!("Unknown document '{}'", uri))),
+                }
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported method '{}'", method))),
+        };
+
+        let mut response = json!({ "jsonrpc": "2.0", "id": message["id"] });
+        match result {
+            Ok(result) => response["result"] = result,
+            Err((code, message)) => response["error"] = json!({ "code": code, "message": message }),
+        }
+        write_message(&mut self.output, &response)
+    }
+
+    fn update(&mut self, uri: &str, text: String) -> io::Result<()> {
+        let (diagnostics, _) = analyze(&text);
+        let diagnostics = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                json!({
+                    "range": range(&text, diagnostic.span),
+                    "severity": match diagnostic.severity {
+                        Severity::Error => 1,
+                        Severity::Warning => 2,
+                    },
+                    "source": "lox",
+                    "message": diagnostic.message,
+                })
+            })
+            .collect();
+        self.documents.insert(uri.to_string(), text);
+        self.publish_diagnostics(uri, diagnostics)
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str, diagnostics: Vec<Value>) -> io::Result<()> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        });
+        write_message(&mut self.output, &notification)
+    }
+}
+
+/// Compiles the source, to find its problems and its names.
+fn analyze(source: &str) -> (Vec<Diagnostic>, SymbolIndex) {
+    let source = source.to_string();
+    let mut interner = Interner::new();
+    let mut compiler = Compiler::from_source(&source, &mut interner);
+    let diagnostics = compiler.compile().err().unwrap_or_default();
+    (diagnostics, compiler.symbols().clone())
+}
+
+/// Answers the requests about a document.
+fn answer(method: &str, uri: &str, source: &str, params: &Value) -> Value {
+    let (_, symbols) = analyze(source);
+    let position = &params["position"];
+    let offset = offset(
+        source,
+        position["line"].as_u64().unwrap_or_default(),
+        position["character"].as_u64().unwrap_or_default(),
+    );
+
+    match method {
+        "textDocument/definition" => match symbols.symbol_at(offset) {
+            Some((_, declaration)) => json!({
+                "uri": uri,
+                "range": range(source, declaration.span),
+            }),
+            None => Value::Null,
+        },
+        "textDocument/hover" => match symbols.symbol_at(offset) {
+            Some((span, declaration)) => json!({
+                "contents": { "kind": "markdown", "value": describe(declaration) },
+                "range": range(source, span),
+            }),
+            None => Value::Null,
+        },
+        "textDocument/documentSymbol" => json!(document_symbols(source, &symbols, None)),
+        _ => unreachable!("Only called for requests about documents"),
+    }
+}
+
+fn describe(declaration: &Declaration) -> String {
+    let name = &declaration.name;
+    let signature = match declaration.kind {
+        SymbolKind::Variable => format!("var {}", name),
+        SymbolKind::Parameter => format!("parameter {}", name),
+        SymbolKind::Class => format!("class {}", name),
+        SymbolKind::Function | SymbolKind::Method => {
+            format!("fun {}({})", name, declaration.parameters.join(", "))
+        }
+    };
+    let mut description = format!("```lox\n{}\n```", signature);
+    if let SymbolKind::Function | SymbolKind::Method = declaration.kind {
+        let arity = declaration.parameters.len();
+        let plural = if arity == 1 { "" } else { "s" };
+        description.push_str(&format!("\nTakes {} argument{}.", arity, plural));
+    }
+    description
+}
+
+/// The functions and classes declared in `parent`, along with what they declare themselves.
+fn document_symbols(source: &str, symbols: &SymbolIndex, parent: Option<usize>) -> Vec<Value> {
+    symbols
+        .declarations()
+        .iter()
+        .enumerate()
+        .filter(|(_, declaration)| declaration.parent == parent)
+        .filter_map(|(index, declaration)| {
+            // As numbered by the protocol
+            let kind = match declaration.kind {
+                SymbolKind::Class => 5,
+                SymbolKind::Method => 6,
+                SymbolKind::Function => 12,
+                SymbolKind::Variable | SymbolKind::Parameter => return None,
+            };
+            Some(json!({
+                "name": declaration.name,
+                "kind": kind,
+                "detail": describe_signature(declaration),
+                "range": range(source, declaration.extent),
+                "selectionRange": range(source, declaration.span),
+                "children": document_symbols(source, symbols, Some(index)),
+            }))
+        })
+        .collect()
+}
+
+fn describe_signature(declaration: &Declaration) -> String {
+    match declaration.kind {
+        SymbolKind::Function | SymbolKind::Method => {
+            format!("({})", declaration.parameters.join(", "))
+        }
+        _ => String::new(),
+    }
+}
+
+/// Positions of the protocol count lines from 0, and characters in UTF-16 code units.
+fn position(source: &str, offset: usize) -> Value {
+    let offset = offset.min(source.len());
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+fn range(source: &str, span: Span) -> Value {
+    json!({ "start": position(source, span.start), "end": position(source, span.end) })
+}
+
+/// Byte offset of a position of the protocol. Positions past the end of a line are at its end.
+fn offset(source: &str, line: u64, character: u64) -> usize {
+    let line_start: usize = source
+        .split_inclusive('\n')
+        .take(line as usize)
+        .map(str::len)
+        .sum();
+    let mut units = 0;
+    for (index, char) in source[line_start..].char_indices() {
+        if units >= character || char == '\n' {
+            return line_start + index;
+        }
+        units += char.len_utf16() as u64;
+    }
+    source.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{offset, position, serve};
+    use crate::protocol::{read_message, write_message};
+
+    const URI: &str = "file:///test.lox";
+
+    /// Sends the messages, made of a method and its parameters, to the server. Requests are
+    /// the ones with an id. Returns the messages it sent back.
+    fn session(messages: &[(Option<u64>, &str, Value)]) -> Vec<Value> {
+        let mut input = vec![];
+        for (id, method, params) in messages {
+            let mut message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+            if let Some(id) = id {
+                message["id"] = json!(id);
+            }
+            write_message(&mut input, &message).unwrap();
+        }
+
+        let mut output = vec![];
+        serve(input.as_slice(), &mut output).unwrap();
+        let mut output = output.as_slice();
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn open(text: &str) -> (Option<u64>, &'static str, Value) {
+        let document = json!({ "uri": URI, "languageId": "lox", "version": 1, "text": text });
+        (
+            None,
+            "textDocument/didOpen",
+            json!({ "textDocument": document }),
+        )
+    }
+
+    fn at(
+        id: u64,
+        method: &'static str,
+        line: u64,
+        character: u64,
+    ) -> (Option<u64>, &'static str, Value) {
+        let params = json!({
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+        });
+        (Some(id), method, params)
+    }
+
+    fn result(messages: &[Value], id: u64) -> &Value {
+        &messages
+            .iter()
+            .find(|message| message["id"] == id)
+            .expect("Every request gets a response")["result"]
+    }
+
+    fn range(start: (u64, u64), end: (u64, u64)) -> Value {
+        json!({
+            "start": { "line": start.0, "character": start.1 },
+            "end": { "line": end.0, "character": end.1 },
+        })
+    }
+
+    #[test]
+    fn diagnostics() {
+        let change = json!({
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [{ "text": "print 1;" }],
+        });
+        let messages = session(&[
+            (Some(1), "initialize", json!({ "capabilities": {} })),
+            (None, "initialized", json!({})),
+            open("var a = 1;\nprint a +;"),
+            (None, "textDocument/didChange", change),
+            (Some(2), "shutdown", Value::Null),
+            (None, "exit", Value::Null),
+            (Some(3), "shutdown", Value::Null),
+        ]);
+
+        assert_eq!(messages.len(), 4);
+        assert_eq!(result(&messages, 1)["capabilities"]["hoverProvider"], true);
+        let diagnostics = &messages[1]["params"]["diagnostics"];
+        assert_eq!(
+            diagnostics,
+            &json!([{
+                "range": range((1, 9), (1, 10)),
+                "severity": 1,
+                "source": "lox",
+                "message": "Expect expression.",
+            }])
+        );
+        assert_eq!(messages[2]["params"]["diagnostics"], json!([]));
+        // Nothing is answered after exiting
+        assert_eq!(messages[3]["id"], 2);
+    }
+
+    #[test]
+    fn definitions_and_hovers() {
+        let source = "\
+fun add(a, b) {
+  return a + b;
+}
+{
+  var x = add(1, 2);
+  print x;
+}
+print undefined;";
+        let messages = session(&[
+            open(source),
+            at(1, "textDocument/definition", 4, 11),
+            at(2, "textDocument/definition", 1, 13),
+            at(3, "textDocument/definition", 5, 8),
+            at(4, "textDocument/definition", 7, 8),
+            at(5, "textDocument/hover", 4, 10),
+            at(6, "textDocument/hover", 5, 8),
+        ]);
+
+        assert_eq!(
+            result(&messages, 1),
+            &json!({ "uri": URI, "range": range((0, 4), (0, 7)) })
+        );
+        assert_eq!(result(&messages, 2)["range"], range((0, 11), (0, 12)));
+        assert_eq!(result(&messages, 3)["range"], range((4, 6), (4, 7)));
+        assert_eq!(result(&messages, 4), &Value::Null);
+        assert_eq!(
+            result(&messages, 5),
+            &json!({
+                "contents": {
+                    "kind": "markdown",
+                    "value": "```lox\nfun add(a, b)\n```\nTakes 2 arguments.",
+                },
+                "range": range((4, 10), (4, 13)),
+            })
+        );
+        assert_eq!(
+            result(&messages, 6)["contents"]["value"],
+            "```lox\nvar x\n```"
+        );
+    }
+
+    #[test]
+    fn document_symbols() {
+        let source = "\
+fun outer() {
+  fun inner(n) {}
+}
+class A {
+  m() {}
+}
+var a = 1;";
+        let messages = session(&[
+            open(source),
+            (
+                Some(1),
+                "textDocument/documentSymbol",
+                json!({ "textDocument": { "uri": URI } }),
+            ),
+            (
+                Some(2),
+                "textDocument/documentSymbol",
+                json!({ "textDocument": { "uri": "file:///unknown.lox" } }),
+            ),
+        ]);
+
+        let symbols = result(&messages, 1);
+        let names = |symbols: &Value| -> Vec<String> {
+            symbols
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|symbol| format!("{} {}", symbol["kind"], symbol["name"].as_str().unwrap()))
+                .collect()
+        };
+        assert_eq!(names(symbols), vec!["12 outer", "5 A"]);
+        assert_eq!(names(&symbols[0]["children"]), vec!["12 inner"]);
+        assert_eq!(names(&symbols[1]["children"]), vec!["6 m"]);
+        assert_eq!(symbols[0]["range"], range((0, 4), (2, 1)));
+        assert_eq!(symbols[0]["selectionRange"], range((0, 4), (0, 9)));
+        assert_eq!(symbols[0]["children"][0]["detail"], "(n)");
+
+        let error = &messages[2]["error"];
+        assert_eq!(error["message"], "Unknown document 'file:///unknown.lox'");
+    }
+
+    #[test]
+    fn positions() {
+        let source = "añb\n𝄞x\n";
+        assert_eq!(position(source, 3), json!({ "line": 0, "character": 2 }));
+        assert_eq!(position(source, 9), json!({ "line": 1, "character": 2 }));
+        assert_eq!(offset(source, 0, 2), 3);
+        assert_eq!(offset(source, 1, 2), 9);
+        // Past the end of the line
+        assert_eq!(offset(source, 0, 10), 4);
+        assert_eq!(offset(source, 5, 0), source.len());
+    }
+}
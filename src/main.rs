@@ -1,56 +1,574 @@
 extern crate rlox_vm;
 
-use rlox_vm::{ interpreter::Interpreter};
-use std::{io::{self, Write}, env, fs};
+use rlox_vm::{
+    compiler::Compiler,
+    coverage::{Coverage, Lcov},
+    dap,
+    debugger::{Console, Resume},
+    interner::Interner,
+    interpreter::{InterpretError, Interpreter},
+    loxc::LOXC_MAGIC,
+    lsp,
+    object::ObjFunction,
+    render::{Renderer, Style},
+};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process,
+    time::Instant,
+};
+
+const USAGE: &str = "\
+Usage: lox [options] [command]
+
+Commands:
+    run <file>                  Run a script, either source or compiled to .loxc
+    <file>                      Same as run <file>
+    repl                        Start an interactive session, the default
+    -e <source>                 Run the given source, printing its value if it's an expression
+    compile <file> [-o <out>]   Compile a script to .loxc, next to it unless -o is given
+    disasm <file>               List the operations a script compiles to
+    check <file>                Report the errors in a script without running it
+    dap                         Serve the Debug Adapter Protocol over stdin and stdout
+    lsp                         Serve the Language Server Protocol over stdin and stdout
+
+Options:
+    --trace                     Print the stack and each operation before running it
+    --stats                     Print what the VM did to stderr once it's done
+    --debug                     Stop at the first line of the script to debug it, with run or -e
+    --profile                   Print where the time went to stderr once it's done, with run or -e
+    --folded <file>             Write the time spent in each call stack, for flamegraph tools
+    --coverage <file>           Add the lines and branches run to an LCOV file, with run
+    --color, --no-color         Whether to color errors, by default only on a terminal
+    -h, --help                  Print this help";
+
+// Exit codes, as in sysexits.h
+const EXIT_USAGE: i32 = 64;
+const EXIT_DATA: i32 = 65;
+const EXIT_SOFTWARE: i32 = 70;
+const EXIT_IO: i32 = 74;
+
+/// Why a command failed, which decides the exit code.
+#[derive(Debug)]
+enum Failure {
+    /// The script doesn't compile, or the compiled file is broken. Without a message, the
+    /// diagnostics were already reported.
+    Data(Option<String>),
+    /// The script failed while running, and the error was already reported.
+    Runtime,
+    Io(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Data(_) => EXIT_DATA,
+            Failure::Runtime => EXIT_SOFTWARE,
+            Failure::Io(_) => EXIT_IO,
+        }
+    }
+}
+
+impl From<InterpretError> for Failure {
+    fn from(error: InterpretError) -> Self {
+        match error {
+            InterpretError::Compile(_) => Failure::Data(None),
+            InterpretError::Runtime(_) => Failure::Runtime,
+            InterpretError::Io(error) => {
+                Failure::Io(format!("Could not write the output: {}", error))
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Run(String),
+    Repl,
+    Eval(String),
+    Compile {
+        input: String,
+        output: Option<String>,
+    },
+    Disasm(String),
+    Check(String),
+    Dap,
+    Lsp,
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    style: Option<Style>,
+    trace: bool,
+    stats: bool,
+    debug: bool,
+    profile: bool,
+    // Where to write the folded call stacks, which also turns on profiling
+    folded: Option<String>,
+    // LCOV file to add the coverage of the script to
+    coverage: Option<String>,
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => repl(),
-        2 => run_file(args.get(1).unwrap()),
-        _ => panic!("Too many arguments passed: {:?}", args),
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, options) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    // Errors are colored only when someone is looking at them, unless told otherwise
+    let style = options.style.unwrap_or_else(|| {
+        if io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none() {
+            Style::Ansi
+        } else {
+            Style::Plain
+        }
+    });
+
+    let result = match command {
+        Command::Run(path) => run_file(&path, style, &options),
+        Command::Repl => repl(style, &options),
+        Command::Eval(source) => eval(&source, style, &options),
+        Command::Compile { input, output } => compile(&input, output.as_deref(), style),
+        Command::Disasm(path) => disasm(&path, style),
+        Command::Check(path) => check(&path, style),
+        Command::Dap => dap::serve(io::stdin().lock(), io::stdout())
+            .map_err(|error| Failure::Io(format!("The debug session failed: {}", error))),
+        Command::Lsp => lsp::serve(io::stdin().lock(), io::stdout().lock())
+            .map_err(|error| Failure::Io(format!("The language server failed: {}", error))),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    };
+    if let Err(failure) = result {
+        if let Failure::Data(Some(message)) | Failure::Io(message) = &failure {
+            eprintln!("error: {}", message);
+        }
+        process::exit(failure.exit_code());
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<(Command, Options), String> {
+    let mut options = Options {
+        style: None,
+        trace: false,
+        stats: false,
+        debug: false,
+        profile: false,
+        folded: None,
+        coverage: None,
+    };
+    let mut positional = vec![];
+    let mut output = None;
+    let mut eval = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => options.trace = true,
+            "--stats" => options.stats = true,
+            "--debug" => options.debug = true,
+            "--profile" => options.profile = true,
+            "--folded" => {
+                options.folded = Some(args.next().ok_or("--folded needs a file name")?.clone())
+            }
+            "--coverage" => {
+                options.coverage = Some(args.next().ok_or("--coverage needs a file name")?.clone())
+            }
+            "--color" => options.style = Some(Style::Ansi),
+            "--no-color" => options.style = Some(Style::Plain),
+            "-h" | "--help" => return Ok((Command::Help, options)),
+            "-o" => output = Some(args.next().ok_or("-o needs a file name")?.clone()),
+            "-e" => eval = Some(args.next().ok_or("-e needs the source to run")?.clone()),
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("Unknown option '{}'", flag))
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    if let Some(source) = eval {
+        if !positional.is_empty() {
+            return Err(String::from("-e can't be used along with a command"));
+        }
+        if options.coverage.is_some() {
+            return Err(String::from("--coverage can only be used with run"));
+        }
+        return Ok((Command::Eval(source), options));
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+        None | Some("repl") => Command::Repl,
+        Some("run") => Command::Run(file_argument(&mut positional, "run")?),
+        Some("compile") => Command::Compile {
+            input: file_argument(&mut positional, "compile")?,
+            output: output.take(),
+        },
+        Some("disasm") => Command::Disasm(file_argument(&mut positional, "disasm")?),
+        Some("check") => Command::Check(file_argument(&mut positional, "check")?),
+        Some("dap") => Command::Dap,
+        Some("lsp") => Command::Lsp,
+        Some(path) => Command::Run(path.to_string()),
+    };
+
+    if let Some(extra) = positional.next() {
+        return Err(format!("Unexpected argument '{}'", extra));
+    }
+    if output.is_some() {
+        return Err(String::from("-o can only be used with compile"));
     }
+    if !matches!(command, Command::Run(_)) {
+        let running_only = [
+            ("--debug", options.debug),
+            ("--profile", options.profile),
+            ("--folded", options.folded.is_some()),
+        ];
+        if let Some((flag, _)) = running_only.iter().find(|(_, used)| *used) {
+            return Err(format!("{} can only be used with run or -e", flag));
+        }
+        if options.coverage.is_some() {
+            return Err(String::from("--coverage can only be used with run"));
+        }
+    }
+    Ok((command, options))
+}
+
+fn file_argument<I: Iterator<Item = String>>(
+    args: &mut I,
+    command: &str,
+) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{} needs a file name", command))
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Failure> {
+    fs::read(path).map_err(|error| Failure::Io(format!("Could not read '{}': {}", path, error)))
 }
 
-fn run_file(path: &str) {
-    println!("Running script at {}", path);
-    let contents = fs::read_to_string(path).expect("Something went wrong reading the file");
-    run_source(&contents);
+fn is_loxc(bytes: &[u8]) -> bool {
+    bytes.starts_with(LOXC_MAGIC)
 }
 
-fn run_source(raw_source: &str) {
-    let mut interpreter = Interpreter::new(io::stdout());
-    interpreter.interpret(raw_source);
+fn source_text(path: &str, bytes: Vec<u8>) -> Result<String, Failure> {
+    String::from_utf8(bytes)
+        .map_err(|_| Failure::Data(Some(format!("'{}' is not valid UTF-8", path))))
 }
 
-fn repl() {
+fn deserialize(path: &str, bytes: &[u8], interner: &mut Interner) -> Result<ObjFunction, Failure> {
+    ObjFunction::deserialize(bytes, interner)
+        .map_err(|error| Failure::Data(Some(format!("Could not load '{}': {}", path, error))))
+}
+
+/// Compiles a script, or loads it if it's already compiled. Compile errors are reported here.
+fn load(path: &str, interner: &mut Interner, style: Style) -> Result<ObjFunction, Failure> {
+    let bytes = read_file(path)?;
+    if is_loxc(&bytes) {
+        return deserialize(path, &bytes, interner);
+    }
+
+    let source = source_text(path, bytes)?;
+    let mut compiler = Compiler::from_source(&source, interner);
+    compiler.compile().map_err(|diagnostics| {
+        let renderer = Renderer::new(path, &source, style);
+        for diagnostic in diagnostics {
+            eprint!("{}", renderer.diagnostic(&diagnostic));
+        }
+        Failure::Data(None)
+    })
+}
+
+fn interpreter(path: &str, style: Style, options: &Options) -> Interpreter<io::Stdout> {
+    let mut interpreter = Interpreter::with_style(io::stdout(), style);
+    interpreter.set_error_output(Box::new(io::stderr()));
+    interpreter.set_file_name(path);
+    interpreter.vm_mut().set_trace(options.trace);
+    interpreter
+        .vm_mut()
+        .set_profiling(options.profile || options.folded.is_some());
+    interpreter.vm_mut().set_coverage(options.coverage.is_some());
+    interpreter
+}
+
+/// Takes commands from the terminal, stopping at the first line. The source, if there is one,
+/// is shown along with the line stopped at.
+fn attach_console<W: Write>(interpreter: &mut Interpreter<W>, source: Option<&str>) {
+    let (input, output) = (io::stdin().lock(), io::stdout());
+    let console = match source {
+        Some(source) => Console::with_source(input, output, source),
+        None => Console::new(input, output),
+    };
+    interpreter
+        .vm_mut()
+        .attach_debugger(Box::new(console), Resume::StepIn);
+}
+
+fn run_file(path: &str, style: Style, options: &Options) -> Result<(), Failure> {
+    let bytes = read_file(path)?;
+    let mut interpreter = interpreter(path, style, options);
+    let start = Instant::now();
+
+    let result = if is_loxc(&bytes) {
+        let function = deserialize(path, &bytes, interpreter.vm_mut().interner_mut())?;
+        if options.debug {
+            attach_console(&mut interpreter, None);
+        }
+        interpreter.run(&function)
+    } else {
+        let source = source_text(path, bytes)?;
+        if options.debug {
+            attach_console(&mut interpreter, Some(&source));
+        }
+        interpreter.interpret(&source)
+    };
+
+    if options.stats {
+        print_stats(&interpreter, start);
+    }
+    report_profile(&interpreter, options)?;
+    if let (Some(lcov), Some(coverage)) = (&options.coverage, interpreter.vm().coverage()) {
+        add_coverage(lcov, path, coverage)?;
+    }
+    Ok(result?)
+}
+
+/// Runs source given on the command line. A lone expression gets its value printed.
+fn eval(source: &str, style: Style, options: &Options) -> Result<(), Failure> {
+    let trimmed = source.trim_end();
+    let source = if trimmed.ends_with(';') || trimmed.ends_with('}') {
+        source.to_string()
+    } else {
+        format!("print {};", trimmed)
+    };
+
+    let mut interpreter = interpreter("<eval>", style, options);
+    if options.debug {
+        attach_console(&mut interpreter, Some(&source));
+    }
+    let start = Instant::now();
+    let result = interpreter.interpret(&source);
+    if options.stats {
+        print_stats(&interpreter, start);
+    }
+    report_profile(&interpreter, options)?;
+    Ok(result?)
+}
+
+fn compile(input: &str, output: Option<&str>, style: Style) -> Result<(), Failure> {
+    let mut interner = Interner::new();
+    let function = load(input, &mut interner, style)?;
+    let bytes = function.serialize(&interner).map_err(|error| {
+        Failure::Data(Some(format!("Could not compile '{}': {}", input, error)))
+    })?;
+
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(input).with_extension("loxc"),
+    };
+    fs::write(&output, bytes)
+        .map_err(|error| Failure::Io(format!("Could not write '{}': {}", output.display(), error)))
+}
+
+fn disasm(path: &str, style: Style) -> Result<(), Failure> {
+    let mut interner = Interner::new();
+    let function = load(path, &mut interner, style)?;
+    let mut stdout = io::stdout().lock();
+    function
+        .disassemble(&interner, &mut stdout)
+        .map_err(|error| Failure::Io(format!("Could not write the listing: {}", error)))
+}
+
+fn check(path: &str, style: Style) -> Result<(), Failure> {
+    load(path, &mut Interner::new(), style).map(|_| ())
+}
+
+fn print_stats<W: Write>(interpreter: &Interpreter<W>, start: Instant) {
+    let elapsed = start.elapsed();
+    let vm = interpreter.vm();
+    let stats = vm.stats();
+    eprintln!("instructions:   {}", stats.instructions);
+    eprintln!("max call depth: {}", stats.max_frames);
+    eprintln!(
+        "collections:    {} ({} bytes freed)",
+        stats.collections, stats.bytes_freed
+    );
+    eprintln!(
+        "heap:           {} objects, {} bytes",
+        vm.heap().object_count(),
+        vm.heap().bytes_allocated()
+    );
+    eprintln!("time:           {:.3?}", elapsed);
+}
+
+/// Reports where the time went, if profiling.
+fn report_profile<W: Write>(interpreter: &Interpreter<W>, options: &Options) -> Result<(), Failure> {
+    let profile = match interpreter.vm().profile() {
+        Some(profile) => profile,
+        None => return Ok(()),
+    };
+    if options.profile {
+        profile
+            .write_report(&mut io::stderr().lock())
+            .map_err(|error| Failure::Io(format!("Could not write the profile: {}", error)))?;
+    }
+    if let Some(path) = &options.folded {
+        let write_error = |error| Failure::Io(format!("Could not write '{}': {}", path, error));
+        let mut file = BufWriter::new(File::create(path).map_err(write_error)?);
+        profile
+            .write_folded(&mut file)
+            .and_then(|()| file.flush())
+            .map_err(write_error)?;
+    }
+    Ok(())
+}
+
+/// Adds the coverage of the script at `path` to the LCOV file, creating it if needed, so it
+/// adds up the coverage of every run.
+fn add_coverage(lcov_path: &str, path: &str, coverage: &Coverage) -> Result<(), Failure> {
+    let mut lcov = match fs::read_to_string(lcov_path) {
+        Ok(text) => Lcov::parse(&text).map_err(|error| {
+            Failure::Data(Some(format!("Could not read '{}': {}", lcov_path, error)))
+        })?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Lcov::default(),
+        Err(error) => {
+            return Err(Failure::Io(format!("Could not read '{}': {}", lcov_path, error)))
+        }
+    };
+    lcov.add(path, coverage);
+
+    let write_error = |error| Failure::Io(format!("Could not write '{}': {}", lcov_path, error));
+    let mut file = BufWriter::new(File::create(lcov_path).map_err(write_error)?);
+    lcov.write(&mut file)
+        .and_then(|()| file.flush())
+        .map_err(write_error)
+}
+
+/// Runs each chunk of lines typed, up to a blank one. Errors in them are reported but don't
+/// end the session.
+fn repl(style: Style, options: &Options) -> Result<(), Failure> {
     let stdin = io::stdin();
-    let mut interpreter = Interpreter::new(io::stdout());
+    let mut interpreter = interpreter("<repl>", style, options);
 
     loop {
         print!("> ");
-        io::stdout().flush().expect("Failed flushing to stdout");
+        io::stdout()
+            .flush()
+            .map_err(|error| Failure::Io(format!("Could not write the prompt: {}", error)))?;
 
         let mut source = String::new();
 
         loop {
             let mut input = String::new();
             match stdin.read_line(&mut input) {
+                // End of input
+                Ok(0) if source.is_empty() => {
+                    println!();
+                    return Ok(());
+                }
                 Ok(_) => {
-                    input = input.to_string();
                     if input.trim().is_empty() {
                         break;
                     } else {
-                        source.push_str(&input.trim_end());
+                        source.push_str(input.trim_end());
                     }
                 }
                 Err(error) => {
-                    println!("error: {}", error);
-                    break;
+                    return Err(Failure::Io(format!("Could not read the input: {}", error)));
                 }
             }
         }
 
-        interpreter.interpret(&source);
+        if let Err(InterpretError::Io(error)) = interpreter.interpret(&source) {
+            return Err(InterpretError::Io(error).into());
+        }
+        if options.stats {
+            eprintln!("instructions: {}", interpreter.vm().stats().instructions);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Command, Options), String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse_args(&args)
+    }
+
+    fn command(args: &[&str]) -> Command {
+        parse(args).unwrap().0
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(command(&[]), Command::Repl);
+        assert_eq!(command(&["repl"]), Command::Repl);
+        assert_eq!(command(&["a.lox"]), Command::Run("a.lox".into()));
+        assert_eq!(command(&["run", "a.lox"]), Command::Run("a.lox".into()));
+        assert_eq!(command(&["-e", "1 + 2"]), Command::Eval("1 + 2".into()));
+        assert_eq!(
+            command(&["disasm", "a.lox"]),
+            Command::Disasm("a.lox".into())
+        );
+        assert_eq!(command(&["check", "a.lox"]), Command::Check("a.lox".into()));
+        assert_eq!(command(&["dap"]), Command::Dap);
+        assert_eq!(command(&["lsp"]), Command::Lsp);
+        assert_eq!(
+            command(&["compile", "a.lox", "-o", "b.loxc"]),
+            Command::Compile {
+                input: "a.lox".into(),
+                output: Some("b.loxc".into())
+            }
+        );
+        assert_eq!(
+            command(&["compile", "a.lox"]),
+            Command::Compile {
+                input: "a.lox".into(),
+                output: None
+            }
+        );
+    }
+
+    #[test]
+    fn options_go_anywhere() {
+        let (command, options) =
+            parse(&["--trace", "run", "a.lox", "--stats", "--no-color"]).unwrap();
+        assert_eq!(command, Command::Run("a.lox".into()));
+        assert_eq!(
+            options,
+            Options {
+                style: Some(Style::Plain),
+                trace: true,
+                stats: true,
+                debug: false,
+                profile: false,
+                folded: None,
+                coverage: None
+            }
+        );
+    }
+
+    #[test]
+    fn bad_command_lines() {
+        assert!(parse(&["run"]).is_err());
+        assert!(parse(&["a.lox", "b.lox"]).is_err());
+        assert!(parse(&["-e"]).is_err());
+        assert!(parse(&["-e", "1", "a.lox"]).is_err());
+        assert!(parse(&["run", "a.lox", "-o", "b.loxc"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+        assert!(parse(&["--debug", "disasm", "a.lox"]).is_err());
+        assert!(parse(&["--folded"]).is_err());
+        assert!(parse(&["compile", "a.lox", "--profile"]).is_err());
+        assert!(parse(&["--coverage", "a.info", "-e", "1"]).is_err());
+        assert!(parse(&["--coverage", "a.info", "a.lox"]).is_ok());
     }
 }
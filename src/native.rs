@@ -1,8 +1,14 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn clock() -> f64 {
-	    SystemTime::now()
+use crate::{
+    value::Value,
+    vm::{InterpretResult, RuntimeError, VM},
+};
+
+/// Milliseconds since the Unix epoch.
+pub fn clock(_vm: &mut VM, _args: &[Value]) -> InterpretResult<Value> {
+    let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as f64
+        .map_err(|_| RuntimeError::new("The system clock is set before the Unix epoch."))?;
+    Ok(Value::Number(now.as_millis() as f64))
 }
@@ -1,8 +1,16 @@
-use crate::chunk::{Chunk, Operation};
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    chunk::{Chunk, Operation},
+    heap::GcRef,
+    value::Value,
+    vm::{InterpretResult, VM},
+};
 
 #[derive(Debug, Clone)]
 pub struct ObjFunction {
     pub arity: u8,
+    pub upvalue_count: usize,
     pub chunk: Chunk,
     pub name: String,
 }
@@ -11,6 +19,7 @@ impl ObjFunction {
     pub fn new(name: &str) -> Self {
         Self {
             arity: 0,
+            upvalue_count: 0,
             chunk: Chunk::new(),
             name: String::from(name),
         }
@@ -21,19 +30,101 @@ impl ObjFunction {
         function.chunk.emit_many(operations);
         function
     }
+
+    /// Whether `function` is this one or is declared inside it, at any depth.
+    pub fn declares(&self, function: &ObjFunction) -> bool {
+        std::ptr::eq(self, function)
+            || self.chunk.constants.iter().any(|constant| match constant {
+                Value::Function(declared) => declared.declares(function),
+                _ => false,
+            })
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: Rc<ObjFunction>,
+    pub upvalues: Vec<GcRef<ObjUpvalue>>,
+}
+
+impl ObjClosure {
+    pub fn new(function: Rc<ObjFunction>, upvalues: Vec<GcRef<ObjUpvalue>>) -> Self {
+        Self { function, upvalues }
+    }
+}
+
+/// A variable captured by a closure. It points to a stack slot while the variable is still
+/// alive there, and owns the value once the slot gets popped.
+#[derive(Debug)]
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: String,
+    pub methods: HashMap<String, GcRef<ObjClosure>>,
+}
+
+impl ObjClass {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn find_method(&self, name: &str) -> Option<GcRef<ObjClosure>> {
+        self.methods.get(name).copied()
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: GcRef<ObjClass>,
+    pub fields: HashMap<String, Value>,
+}
+
+impl ObjInstance {
+    pub fn new(class: GcRef<ObjClass>) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
 }
 
+/// A method accessed through an instance, which remembers that instance so it can be
+/// bound to `this` when it's eventually called.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: GcRef<ObjClosure>,
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: Value, method: GcRef<ObjClosure>) -> Self {
+        Self { receiver, method }
+    }
+}
+
+/// Signature of the host functions callable from Lox. They get the arguments of the call,
+/// already checked against the declared arity, and the VM that is running them.
+pub type NativeFn = fn(&mut VM, &[Value]) -> InterpretResult<Value>;
 
 #[derive(Debug, Clone)]
 pub struct ObjNative {
     pub name: String,
-    pub function: fn() -> f64,
+    pub arity: u8,
+    pub function: NativeFn,
 }
 
 impl ObjNative {
-    pub fn new(name: &str, function: fn() -> f64) -> Self {
+    pub fn new(name: &str, arity: u8, function: NativeFn) -> Self {
         Self {
             name: String::from(name),
+            arity,
             function,
         }
     }
@@ -52,10 +143,19 @@ impl ObjString {
     }
 
     pub fn from_owned(value: String) -> Self {
-        ObjString { value: value }
+        ObjString { value }
     }
 
     pub fn value(&self) -> &String {
         &self.value
     }
 }
+
+/// Strings are interned, see [`crate::interner::Interner`], so equal strings are the same object.
+impl PartialEq for ObjString {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for ObjString {}
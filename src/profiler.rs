@@ -0,0 +1,320 @@
+//! Where a script spends its time, measured by the VM while it runs with profiling on, see
+//! [`VM::set_profiling`](crate::vm::VM::set_profiling).
+//!
+//! Every operation run is counted, along with the line it comes from. Calls are noticed when
+//! the call stack changes between operations, and timed so each function gets the time spent
+//! running it and the functions it calls.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io::{self, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{chunk::OpCode, object::ObjFunction};
+
+/// What was measured of a function.
+#[derive(Debug, Clone)]
+pub struct FunctionProfile {
+    /// The name of the function and the line its code starts at, to tell apart functions with
+    /// the same name.
+    pub name: String,
+    pub calls: u64,
+    /// Time spent running the function, but not the ones it called.
+    pub self_time: Duration,
+    /// Time from being called until returning. Recursive calls aren't counted twice.
+    pub total_time: Duration,
+}
+
+/// A function being run.
+#[derive(Debug)]
+struct Call {
+    function: usize,
+    start: Instant,
+    // Time spent in the functions it called
+    callees: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    // Operations run, indexed by their code
+    operations: Vec<u64>,
+    // Operations run, indexed by the line they come from
+    lines: Vec<u64>,
+    functions: Vec<FunctionProfile>,
+    // Index in `functions` of each function, by address
+    ids: HashMap<*const ObjFunction, usize>,
+    // Keeps the functions alive, so their addresses keep identifying them
+    alive: Vec<Rc<ObjFunction>>,
+    // Functions being run, innermost last
+    calls: Vec<Call>,
+    // Self time of each call stack, as the indexes of its functions from the outermost
+    stacks: HashMap<Vec<usize>, Duration>,
+}
+
+impl Profile {
+    /// Counts an operation of `function` about to run, with `depth` frames on the call stack.
+    #[inline]
+    pub(crate) fn operation(
+        &mut self,
+        function: &Rc<ObjFunction>,
+        depth: usize,
+        code: u8,
+        line: u32,
+    ) {
+        if self.calls.len() != depth {
+            self.call_stack_changed(function, depth);
+        }
+        count(&mut self.operations, code as usize);
+        count(&mut self.lines, line as usize);
+    }
+
+    /// Since the previous operation, at most one function can have been called or returned
+    /// from, as that's what the operation did.
+    #[cold]
+    fn call_stack_changed(&mut self, function: &Rc<ObjFunction>, depth: usize) {
+        let now = Instant::now();
+        while self.calls.len() > depth {
+            self.returned(now);
+        }
+        if self.calls.len() < depth {
+            let index = self.function_index(function, depth == 1);
+            self.functions[index].calls += 1;
+            self.calls.push(Call {
+                function: index,
+                start: now,
+                callees: Duration::ZERO,
+            });
+        }
+    }
+
+    fn function_index(&mut self, function: &Rc<ObjFunction>, script: bool) -> usize {
+        if let Some(&index) = self.ids.get(&Rc::as_ptr(function)) {
+            return index;
+        }
+        let name = if script {
+            String::from("script")
+        } else {
+            format!("{}:{}", function.name, function.chunk.line(0))
+        };
+        self.functions.push(FunctionProfile {
+            name,
+            calls: 0,
+            self_time: Duration::ZERO,
+            total_time: Duration::ZERO,
+        });
+        self.alive.push(Rc::clone(function));
+        self.ids
+            .insert(Rc::as_ptr(function), self.functions.len() - 1);
+        self.functions.len() - 1
+    }
+
+    fn returned(&mut self, now: Instant) {
+        let call = match self.calls.pop() {
+            Some(call) => call,
+            None => return,
+        };
+        let elapsed = now.duration_since(call.start);
+        let self_time = elapsed.saturating_sub(call.callees);
+
+        let function = &mut self.functions[call.function];
+        function.self_time += self_time;
+        if !self
+            .calls
+            .iter()
+            .any(|outer| outer.function == call.function)
+        {
+            function.total_time += elapsed;
+        }
+        let mut stack: Vec<usize> = self.calls.iter().map(|outer| outer.function).collect();
+        stack.push(call.function);
+        *self.stacks.entry(stack).or_default() += self_time;
+        if let Some(caller) = self.calls.last_mut() {
+            caller.callees += elapsed;
+        }
+    }
+
+    /// Stops timing the functions still being run, once the script is done or failed.
+    pub(crate) fn finish(&mut self) {
+        let now = Instant::now();
+        while !self.calls.is_empty() {
+            self.returned(now);
+        }
+    }
+
+    pub fn functions(&self) -> &[FunctionProfile] {
+        &self.functions
+    }
+
+    /// How many times the operation was run.
+    pub fn operation_count(&self, op: OpCode) -> u64 {
+        self.operations.get(op as usize).copied().unwrap_or(0)
+    }
+
+    /// How many operations were run from each line, for the lines with any.
+    pub fn line_counts(&self) -> Vec<(u32, u64)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(line, count)| (line as u32, *count))
+            .collect()
+    }
+
+    /// Writes the functions, operations and lines the script spent the most on, first.
+    pub fn write_report<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let mut functions: Vec<&FunctionProfile> = self.functions.iter().collect();
+        functions.sort_by_key(|function| Reverse(function.self_time));
+        writeln!(output, "Functions, by self time:")?;
+        writeln!(
+            output,
+            "{:>12} {:>12} {:>10}  function",
+            "self", "total", "calls"
+        )?;
+        for function in functions {
+            writeln!(
+                output,
+                "{:>12} {:>12} {:>10}  {}",
+                format!("{:.3?}", function.self_time),
+                format!("{:.3?}", function.total_time),
+                function.calls,
+                function.name
+            )?;
+        }
+
+        let mut operations: Vec<(OpCode, u64)> = OpCode::ALL
+            .iter()
+            .map(|op| (*op, self.operation_count(*op)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        operations.sort_by_key(|(_, count)| Reverse(*count));
+        writeln!(output, "\nOperations, by count:")?;
+        writeln!(output, "{:>12}  operation", "count")?;
+        for (op, count) in operations {
+            writeln!(output, "{:>12}  {:?}", count, op)?;
+        }
+
+        let mut lines = self.line_counts();
+        lines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        writeln!(output, "\nLines, by operations run:")?;
+        writeln!(output, "{:>12}  line", "operations")?;
+        for (line, count) in lines {
+            writeln!(output, "{:>12}  {}", count, line)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the self time of each call stack, in microseconds, in the folded format of
+    /// flamegraph tools: the functions from the outermost, separated by semicolons, and the
+    /// time.
+    pub fn write_folded<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let mut stacks: Vec<(String, u128)> = self
+            .stacks
+            .iter()
+            .map(|(stack, time)| {
+                let names: Vec<&str> = stack
+                    .iter()
+                    .map(|function| self.functions[*function].name.as_str())
+                    .collect();
+                (names.join(";"), time.as_micros())
+            })
+            .collect();
+        stacks.sort();
+        for (stack, time) in stacks {
+            writeln!(output, "{} {}", stack, time)?;
+        }
+        Ok(())
+    }
+}
+
+fn count(counts: &mut Vec<u64>, index: usize) {
+    if index >= counts.len() {
+        counts.resize(index + 1, 0);
+    }
+    counts[index] += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profile;
+    use crate::{chunk::OpCode, interpreter::Interpreter};
+
+    const SOURCE: &str = "\
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+print fib(5);
+";
+
+    fn profile(check: impl FnOnce(&Profile)) {
+        let mut interpreter = Interpreter::new(vec![]);
+        interpreter.vm_mut().set_profiling(true);
+        interpreter.interpret(SOURCE).unwrap();
+        check(interpreter.vm().profile().unwrap());
+    }
+
+    #[test]
+    fn counts() {
+        profile(|profile| {
+            let calls: Vec<(&str, u64)> = profile
+                .functions()
+                .iter()
+                .map(|function| (function.name.as_str(), function.calls))
+                .collect();
+            assert_eq!(calls, [("script", 1), ("fib:2", 15)]);
+            assert_eq!(profile.operation_count(OpCode::Call), 15);
+            assert_eq!(profile.operation_count(OpCode::Print), 1);
+
+            let lines: Vec<u32> = profile
+                .line_counts()
+                .iter()
+                .map(|(line, _)| *line)
+                .collect();
+            assert_eq!(lines, [2, 3, 4, 5, 6]);
+            let line_3 = profile.line_counts()[1].1;
+            let line_5 = profile.line_counts()[3].1;
+            assert!(line_3 > line_5);
+
+            let script = &profile.functions()[0];
+            assert!(script.total_time >= profile.functions()[1].total_time);
+            assert!(script.total_time >= script.self_time);
+        });
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let mut folded = vec![];
+        profile(|profile| profile.write_folded(&mut folded).unwrap());
+        let stacks: Vec<String> = String::from_utf8(folded)
+            .unwrap()
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0.to_string())
+            .collect();
+        assert_eq!(
+            stacks,
+            [
+                "script",
+                "script;fib:2",
+                "script;fib:2;fib:2",
+                "script;fib:2;fib:2;fib:2",
+                "script;fib:2;fib:2;fib:2;fib:2",
+                "script;fib:2;fib:2;fib:2;fib:2;fib:2",
+            ]
+        );
+    }
+
+    #[test]
+    fn report() {
+        let mut report = vec![];
+        profile(|profile| profile.write_report(&mut report).unwrap());
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("Functions, by self time:\n"));
+        assert!(report.contains("         15  fib:2\n"));
+        assert!(report.contains("\nOperations, by count:\n"));
+        assert!(report.contains("          15  Call\n"));
+        assert!(report.contains("\nLines, by operations run:\n"));
+    }
+}
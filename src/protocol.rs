@@ -0,0 +1,81 @@
+//! Framing of the messages editors exchange with the debug adapter and the language server:
+//! each is some JSON preceded by a header giving its length, like in HTTP.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+/// Reads the next message, or nothing if the input ended before it.
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return match length {
+                None => Ok(None),
+                Some(_) => Err(invalid_data("The input ended in a header")),
+            };
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        // Other headers, like the content type, have a single possible value
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                let value = value.trim().parse::<usize>();
+                length = Some(value.map_err(|_| invalid_data("Invalid Content-Length"))?);
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| invalid_data("Missing Content-Length"))?;
+    let mut content = vec![0; length];
+    input.read_exact(&mut content)?;
+    serde_json::from_slice(&content)
+        .map(Some)
+        .map_err(|error| invalid_data(&format!("Invalid message: {}", error)))
+}
+
+pub fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let content = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+    output.flush()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{read_message, write_message};
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = vec![];
+        write_message(&mut bytes, &json!({"seq": 1, "text": "ñ"})).unwrap();
+        write_message(&mut bytes, &json!([])).unwrap();
+        assert!(bytes.starts_with(b"Content-Length: 21\r\n\r\n{"));
+
+        let mut input = bytes.as_slice();
+        assert_eq!(
+            read_message(&mut input).unwrap(),
+            Some(json!({"seq": 1, "text": "ñ"}))
+        );
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!([])));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn broken_messages() {
+        let mut input = &b"Content-Type: json\r\n\r\n{}"[..];
+        assert!(read_message(&mut input).is_err());
+        let mut input = &b"Content-Length: 10\r\n\r\n{}"[..];
+        assert!(read_message(&mut input).is_err());
+        let mut input = &b"Content-Length: 2\r\n\r\n{]"[..];
+        assert!(read_message(&mut input).is_err());
+    }
+}
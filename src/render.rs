@@ -0,0 +1,242 @@
+use crate::{
+    diagnostic::{Diagnostic, Severity},
+    vm::RuntimeError,
+};
+
+/// How the rendered reports are decorated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Plain,
+    /// Colored with ANSI escape codes, for terminals.
+    Ansi,
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Renders errors the way rustc does: the message, where it happened, and the offending source
+/// line with the problem underlined.
+///
+/// ```text
+/// error: Expect ';' after value.
+///  --> script.lox:3:1
+///   |
+/// 3 | var a = 2;
+///   | ^^^
+/// ```
+pub struct Renderer<'a> {
+    file_name: &'a str,
+    source: &'a str,
+    style: Style,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(file_name: &'a str, source: &'a str, style: Style) -> Self {
+        Renderer {
+            file_name,
+            source,
+            style,
+        }
+    }
+
+    pub fn diagnostic(&self, diagnostic: &Diagnostic) -> String {
+        let (label, color) = match diagnostic.severity {
+            Severity::Error => ("error", RED),
+            Severity::Warning => ("warning", YELLOW),
+        };
+        let mut ret = self.header(label, color, &diagnostic.message);
+        let length = self
+            .source
+            .get(diagnostic.span.start..diagnostic.span.end)
+            .map_or(diagnostic.span.len(), |text| text.chars().count());
+        self.snippet(&mut ret, diagnostic.line, diagnostic.column, length, color);
+        ret
+    }
+
+    /// Renders a runtime error pointing to where it happened, followed by its stack trace.
+    pub fn runtime_error(&self, error: &RuntimeError) -> String {
+        let mut ret = self.header("error", RED, &error.message());
+
+        let trace = error.trace();
+        if let Some(innermost) = trace.first() {
+            let location = innermost.location;
+            // Otherwise the line would be looked up in the wrong source, the trace still tells
+            // where the error happened
+            if innermost.in_script {
+                self.snippet(&mut ret, location.line, location.column, 1, RED);
+            }
+
+            let gutter = " ".repeat(Self::gutter_width(location.line));
+            for line in error.trace_lines() {
+                ret.push_str(&format!("{} {} note: {}\n", gutter, self.paint("=", BLUE), line));
+            }
+        }
+        ret
+    }
+
+    fn header(&self, label: &str, color: &str, message: &str) -> String {
+        format!(
+            "{}{}\n",
+            self.paint(label, color),
+            self.paint(&format!(": {}", message), BOLD)
+        )
+    }
+
+    fn snippet(&self, ret: &mut String, line: u32, column: u32, length: usize, color: &str) {
+        let gutter = " ".repeat(Self::gutter_width(line));
+        ret.push_str(&format!(
+            "{}{} {}:{}:{}\n",
+            gutter,
+            self.paint("-->", BLUE),
+            self.file_name,
+            line,
+            column
+        ));
+
+        // The location can be unknown, or past the end of the source when it's at the end of it
+        let text = match (line as usize).checked_sub(1) {
+            Some(index) => self.source.lines().nth(index),
+            None => None,
+        };
+        let text = match text {
+            Some(text) => text,
+            None => return,
+        };
+
+        let bar = self.paint("|", BLUE);
+        ret.push_str(&format!("{} {}\n", gutter, bar));
+        ret.push_str(&format!(
+            "{} {} {}\n",
+            self.paint(&line.to_string(), BLUE),
+            bar,
+            text
+        ));
+
+        // Keep the tabs before the column so the underline is aligned with the text above it
+        let before: String = text
+            .chars()
+            .take((column as usize).saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let available = text.chars().count().saturating_sub(before.chars().count());
+        let underline = "^".repeat(length.min(available).max(1));
+        ret.push_str(&format!(
+            "{} {} {}{}\n",
+            gutter,
+            bar,
+            before,
+            self.paint(&underline, color)
+        ));
+    }
+
+    fn gutter_width(line: u32) -> usize {
+        line.to_string().len()
+    }
+
+    fn paint(&self, text: &str, color: &str) -> String {
+        match self.style {
+            Style::Plain => text.to_string(),
+            Style::Ansi => format!("{}{}{}", color, text, RESET),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Renderer, Style};
+    use crate::{
+        chunk::SourceLocation,
+        diagnostic::{Diagnostic, Span},
+        vm::{RuntimeError, TraceFrame},
+    };
+
+    const SOURCE: &str = "print 1\nvar a = 2;\n\tprint a + nil;";
+
+    #[test]
+    fn diagnostic() {
+        let renderer = Renderer::new("script.lox", SOURCE, Style::Plain);
+        let diagnostic = Diagnostic::error("Expect ';' after value.", 2, 1, Span::new(8, 11));
+
+        assert_eq!(
+            renderer.diagnostic(&diagnostic),
+            "error: Expect ';' after value.
+ --> script.lox:2:1
+  |
+2 | var a = 2;
+  | ^^^
+"
+        );
+    }
+
+    #[test]
+    fn runtime_error() {
+        let renderer = Renderer::new("script.lox", SOURCE, Style::Plain);
+        let error = RuntimeError::Traced(
+            Box::new(RuntimeError::new("Operands must be two numbers or two strings.")),
+            vec![TraceFrame {
+                function: String::from("script"),
+                location: SourceLocation::new(3, 10),
+                in_script: true,
+            }],
+        );
+
+        assert_eq!(
+            renderer.runtime_error(&error),
+            "error: Operands must be two numbers or two strings.
+ --> script.lox:3:10
+  |
+3 | \tprint a + nil;
+  | \t        ^
+  = note: [line 3] in script
+"
+        );
+
+        // Code from some other source, like an earlier line on the REPL
+        let error = RuntimeError::Traced(
+            Box::new(RuntimeError::new("Operands must be two numbers or two strings.")),
+            vec![
+                TraceFrame {
+                    function: String::from("f"),
+                    location: SourceLocation::new(1, 20),
+                    in_script: false,
+                },
+                TraceFrame {
+                    function: String::from("script"),
+                    location: SourceLocation::new(1, 2),
+                    in_script: true,
+                },
+            ],
+        );
+        assert_eq!(
+            renderer.runtime_error(&error),
+            "error: Operands must be two numbers or two strings.
+  = note: [line 1] in f
+  = note: [line 1] in script
+"
+        );
+    }
+
+    #[test]
+    fn unknown_location() {
+        let renderer = Renderer::new("script.lox", SOURCE, Style::Plain);
+        let diagnostic = Diagnostic::error("Expect expression.", 4, 1, Span::new(34, 34));
+
+        assert_eq!(
+            renderer.diagnostic(&diagnostic),
+            "error: Expect expression.\n --> script.lox:4:1\n"
+        );
+    }
+
+    #[test]
+    fn ansi() {
+        let renderer = Renderer::new("script.lox", SOURCE, Style::Ansi);
+        let diagnostic = Diagnostic::error("Expect ';' after value.", 2, 1, Span::new(8, 11));
+
+        let rendered = renderer.diagnostic(&diagnostic);
+        assert!(rendered.starts_with("\x1b[1;31merror\x1b[0m"));
+        assert!(rendered.contains("\x1b[1;31m^^^\x1b[0m"));
+    }
+}
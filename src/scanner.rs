@@ -1,6 +1,9 @@
 use peekmore::{PeekMore, PeekMoreIterator};
 
-use crate::token::{Token, TokenResult, TokenType};
+use crate::{
+    diagnostic::Span,
+    token::{Token, TokenResult, TokenType},
+};
 use std::str::Chars;
 
 #[derive(Debug)]
@@ -10,6 +13,11 @@ pub struct Scanner<'a> {
     start: usize,
     current: usize,
     line: i32,
+    // Where the current line begins, to know the column of each token
+    line_start: usize,
+    // Line and column of the token being scanned, which can span multiple lines
+    start_line: i32,
+    start_column: i32,
 }
 
 impl<'a> Scanner<'a> {
@@ -20,12 +28,17 @@ impl<'a> Scanner<'a> {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
     pub fn scan_token(&mut self) -> TokenResult<'a> {
         self.skip_whitespaces();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.source[self.line_start..self.start].chars().count() as i32 + 1;
         match self.advance() {
             Some(c) => match c {
                 _ if Scanner::is_alpha(c) => self.identifier(),
@@ -62,6 +75,11 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    /// Span of the last scanned token.
+    pub fn token_span(&self) -> Span {
+        Span::new(self.start, self.current)
+    }
+
     fn make_token_if_matches(
         &mut self,
         expected: &char,
@@ -77,7 +95,8 @@ impl<'a> Scanner<'a> {
 
     fn make_token(&self, token_type: TokenType) -> TokenResult<'a> {
         TokenResult {
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
             token_type,
             data: Ok(Token {
                 start: self.start,
@@ -113,7 +132,8 @@ impl<'a> Scanner<'a> {
 
     fn make_eof(&self) -> TokenResult<'a> {
         TokenResult {
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
             token_type: TokenType::Eof,
             data: Ok(Token {
                 start: self.start,
@@ -125,15 +145,23 @@ impl<'a> Scanner<'a> {
 
     fn token_error(&self, message: &str) -> TokenResult<'a> {
         TokenResult {
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
             token_type: TokenType::Error,
             data: Err(message.to_string()),
         }
     }
 
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn advance(&mut self) -> Option<char> {
-        self.current += 1;
-        self.chars.next()
+        // Offsets are in bytes, so lexemes can be sliced from the source
+        let c = self.chars.next()?;
+        self.current += c.len_utf8();
+        Some(c)
     }
 
     fn peek(&mut self) -> Option<&char> {
@@ -152,7 +180,7 @@ impl<'a> Scanner<'a> {
     }
 
     fn is_eof(&mut self) -> bool {
-        self.peek() == None
+        self.peek().is_none()
     }
 
     fn peek_next_matches(&mut self, expected: &char) -> bool {
@@ -163,16 +191,11 @@ impl<'a> Scanner<'a> {
     }
 
     fn matches(&mut self, expected: &char) -> bool {
-        match self.peek() {
-            Some(c) => {
-                if c == expected {
-                    self.advance();
-                    true
-                } else {
-                    false
-                }
-            }
-            None => false,
+        if self.peek_matches(expected) {
+            self.advance();
+            true
+        } else {
+            false
         }
     }
 
@@ -183,8 +206,8 @@ impl<'a> Scanner<'a> {
                     self.advance();
                 }
                 Some('\n') => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
                 Some('/') => {
                     if self.peek_next_matches(&'/') {
@@ -212,14 +235,17 @@ impl<'a> Scanner<'a> {
         self.start += 1;
 
         while !self.peek_matches(&'"') && !self.is_eof() {
-            if self.peek_matches(&'\n') {
-                self.line += 1;
-            }
+            let is_new_line = self.peek_matches(&'\n');
             self.advance();
+            if is_new_line {
+                self.new_line();
+            }
         }
 
         if self.is_eof() {
-            self.token_error(&format!("Unterminated string. Token so far: {:?}", self.make_token(TokenType::String)))
+            // Point to the whole string, from its opening "
+            self.start -= 1;
+            self.token_error("Unterminated string.")
         } else {
             let ret = self.make_token(TokenType::String);
             self.advance();
@@ -266,17 +292,17 @@ impl<'a> Scanner<'a> {
 
     fn is_digit(c: char) -> bool {
         // matches!(c, '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9')
-		c.is_digit(10)
+		c.is_ascii_digit()
     }
 
 	fn is_alpha(c: char) -> bool {
-		 c == '_' || ('A'..'z').contains(&c)
+		 c == '_' || c.is_ascii_alphabetic()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{scanner, token::TokenType};
+    use crate::{diagnostic::Span, scanner, token::TokenType};
 
     #[test]
     fn peek() {
@@ -293,6 +319,22 @@ mod tests {
         assert!(scanner.peek_next_matches(&'4'));
     }
 
+    #[test]
+    fn locations() {
+        let source = String::from("var a = 1;\n  print \"two\nlines\";\n// comment\n a");
+        let mut scanner = scanner::Scanner::new(&source);
+        let locations: Vec<(i32, i32)> = std::iter::from_fn(|| {
+            let token = scanner.scan_token();
+            (token.token_type != TokenType::Eof).then_some((token.line, token.column))
+        })
+        .collect();
+
+        assert_eq!(
+            locations,
+            vec![(1, 1), (1, 5), (1, 7), (1, 9), (1, 10), (2, 3), (2, 9), (3, 7), (5, 2)]
+        );
+    }
+
     #[test]
     fn empty_source() {
         assert_token(String::from(""), TokenType::Eof);
@@ -363,6 +405,18 @@ mod tests {
         assert_token_lexeme(String::from("\"\""), TokenType::String, "");
     }
 
+    #[test]
+    fn unterminated_string() {
+        let source = String::from("print \"abc");
+        let mut scanner = scanner::Scanner::new(&source);
+        scanner.scan_token();
+        let token = scanner.scan_token();
+        assert_eq!(token.token_type, TokenType::Error);
+        assert_eq!(token.data.unwrap_err(), "Unterminated string.");
+        assert_eq!((token.line, token.column), (1, 7));
+        assert_eq!(scanner.token_span(), Span::new(6, 10));
+    }
+
 	#[test]
 	fn numbers() {
 		assert_token_lexeme(String::from("0"), TokenType::Number, "0");
@@ -382,6 +436,8 @@ mod tests {
 		assert_token_lexeme(String::from("pepe"), TokenType::Identifier, "pepe");
 		assert_token_lexeme(String::from("for1"), TokenType::Identifier, "for1");
 		assert_token_lexeme(String::from("whiles"), TokenType::Identifier, "whiles");
+		assert_token_lexeme(String::from("z"), TokenType::Identifier, "z");
+		assert_token_lexeme(String::from("Zz_9"), TokenType::Identifier, "Zz_9");
 	}
 
     fn assert_token(source: String, expected: TokenType) {
@@ -1,10 +1,6 @@
-use std::{
-    fmt::{Display, Formatter, Write},
-    rc::Rc,
-};
+use std::fmt::{Display, Formatter};
 
 use crate::{
-    object::ObjString,
     value::Value,
     vm::{InterpretResult, RuntimeError},
 };
@@ -14,74 +10,109 @@ pub struct Stack {
     values: Vec<Value>,
 }
 
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Stack {
     pub fn new() -> Self {
         Stack { values: vec![] }
     }
 
+    #[inline]
     pub fn push(&mut self, value: Value) {
         self.values.push(value)
     }
 
+    #[inline]
     pub fn pop(&mut self) -> InterpretResult<Value> {
         self.values
             .pop()
-            .ok_or(RuntimeError::new("Tried to pop an empty stack."))
+            .ok_or_else(|| RuntimeError::new("Tried to pop an empty stack."))
     }
 
+    #[inline]
     pub fn pop_number(&mut self) -> InterpretResult<f64> {
         match self.pop()? {
             Value::Number(n) => Ok(n),
             v => Err(RuntimeError::new(&format!(
-                "Expected to pop a number but found '{}'.\n{}",
-                v, self
-            ))),
-        }
-    }
-
-    pub fn pop_string(&mut self) -> InterpretResult<Rc<ObjString>> {
-        match self.pop()? {
-            Value::String(s) => Ok(s),
-            v => Err(RuntimeError::new(&format!(
-                "Expected to pop a string but found '{}'.",
+                "Expected to pop a number but found '{}'.",
                 v
             ))),
         }
     }
 
+    #[inline]
     pub fn get(&self, index: usize) -> InterpretResult<&Value> {
-        self.values.get(index).ok_or(RuntimeError::new(&format!(
+        self.values.get(index).ok_or_else(|| RuntimeError::new(&format!(
             "No value found at index {}.",
             index
         )))
     }
 
-    pub fn set(&mut self, index: usize, value: Value) {
-        self.values[index] = value;
+    #[inline]
+    pub fn set(&mut self, index: usize, value: Value) -> InterpretResult<()> {
+        let slot = self.values.get_mut(index).ok_or_else(|| RuntimeError::new(&format!(
+            "No value found at index {}.",
+            index
+        )))?;
+        *slot = value;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, index: usize, value: Value) {
+        self.values.insert(index, value);
     }
 
+    #[inline]
     pub fn peek(&self) -> InterpretResult<&Value> {
         self.values
             .last()
-            .ok_or(RuntimeError::new("Tried to peek an empty stack"))
+            .ok_or_else(|| RuntimeError::new("Tried to peek an empty stack"))
     }
 
+    #[inline]
     pub fn peek_many(&self, count: usize) -> InterpretResult<&Value> {
-        self.get(self.values.len() - 1 - count)
+        self.get(self.slot_below(count)?)
+    }
+
+    /// Index of the value `count` places below the top of the stack.
+    #[inline]
+    pub fn slot_below(&self, count: usize) -> InterpretResult<usize> {
+        self.values
+            .len()
+            .checked_sub(count + 1)
+            .ok_or_else(|| RuntimeError::new(&format!(
+                "There are not {} values in the stack.",
+                count + 1
+            )))
     }
 
     pub fn contents(&self) -> &Vec<Value> {
         &self.values
     }
 
+    #[inline]
     pub fn len(&self) -> usize {
         self.values.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Drops every value above `len`, like when a function returns and its frame goes away.
+    #[inline]
+    pub fn truncate(&mut self, len: usize) {
+        self.values.truncate(len);
+    }
 }
 
 impl Display for Stack {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        if self.contents().len() == 0 {
+        if self.is_empty() {
             f.write_str("         <empty stack>\n")?;
         } else {
             for (i, val) in self.values.iter().enumerate() {
@@ -0,0 +1,88 @@
+//! Where the names of a script are declared and used, as found by the [compiler]. Editors use
+//! it to go to declarations and describe what a name is.
+//!
+//! [compiler]: crate::compiler::Compiler
+
+use crate::diagnostic::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Variable,
+    Parameter,
+    Function,
+    Class,
+    Method,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Declaration {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Where the name is declared.
+    pub span: Span,
+    /// The whole declaration, which goes up to the closing brace for functions and classes.
+    pub extent: Span,
+    /// Names of the parameters, for functions and methods.
+    pub parameters: Vec<String>,
+    /// The function or class it's declared in, as an index of [`SymbolIndex::declarations`].
+    pub parent: Option<usize>,
+    pub global: bool,
+}
+
+/// A use of a name, resolved to the declaration it refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference {
+    pub span: Span,
+    /// Index of [`SymbolIndex::declarations`].
+    pub declaration: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolIndex {
+    declarations: Vec<Declaration>,
+    references: Vec<Reference>,
+}
+
+impl SymbolIndex {
+    /// In the order they appear in the source.
+    pub fn declarations(&self) -> &[Declaration] {
+        &self.declarations
+    }
+
+    pub fn references(&self) -> &[Reference] {
+        &self.references
+    }
+
+    /// The name at `offset` of the source, whether it's declared or used there, along with its
+    /// declaration.
+    pub fn symbol_at(&self, offset: usize) -> Option<(Span, &Declaration)> {
+        let contains = |span: &Span| span.start <= offset && offset < span.end;
+        if let Some(declaration) = self.declarations.iter().find(|d| contains(&d.span)) {
+            return Some((declaration.span, declaration));
+        }
+        self.references
+            .iter()
+            .find(|reference| contains(&reference.span))
+            .map(|reference| (reference.span, &self.declarations[reference.declaration]))
+    }
+
+    pub(crate) fn declare(&mut self, declaration: Declaration) -> usize {
+        self.declarations.push(declaration);
+        self.declarations.len() - 1
+    }
+
+    pub(crate) fn declaration_mut(&mut self, index: usize) -> &mut Declaration {
+        &mut self.declarations[index]
+    }
+
+    pub(crate) fn refer(&mut self, span: Span, declaration: usize) {
+        self.references.push(Reference { span, declaration });
+    }
+
+    /// The first global declared with that name.
+    pub(crate) fn global(&self, name: &str) -> Option<usize> {
+        self.declarations
+            .iter()
+            .position(|declaration| declaration.global && declaration.name == name)
+    }
+}
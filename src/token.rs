@@ -1,13 +1,14 @@
 #[derive(Clone, Debug)]
 pub struct TokenResult<'a> {
     pub line: i32,
+    pub column: i32,
     pub token_type: TokenType,
     pub data: Result<Token<'a>, String>,
 }
 
 impl<'a> TokenResult<'a> {
     pub fn invalid() -> Self {
-        TokenResult{ line: -1, token_type: TokenType::Error, data: Err(String::from("Invalid")) }
+        TokenResult{ line: -1, column: -1, token_type: TokenType::Error, data: Err(String::from("Invalid")) }
     }
 }
 
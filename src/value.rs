@@ -1,5 +1,8 @@
 use std::{rc::Rc, fmt::{Display}};
-use crate::object::{ObjString, ObjFunction, ObjNative};
+use crate::{
+    heap::{GcRef, Heap},
+    object::{ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative, ObjString},
+};
 
 #[derive(Debug, Clone)]
 pub enum Value {
@@ -8,6 +11,10 @@ pub enum Value {
     Number(f64),
     String(Rc<ObjString>),
     Function(Rc<ObjFunction>),
+    Closure(GcRef<ObjClosure>),
+    Class(GcRef<ObjClass>),
+    Instance(GcRef<ObjInstance>),
+    BoundMethod(GcRef<ObjBoundMethod>),
     Native(ObjNative),
 }
 
@@ -17,21 +24,28 @@ impl PartialEq for Value {
             (Self::Nil, Self::Nil) => true,
             (Self::Boolean(l0), Self::Boolean(r0)) => l0 == r0,
             (Self::Number(l0), Self::Number(r0)) => l0 == r0,
-            (Self::String(l0), Self::String(r0)) => l0.value == r0.value,
+            (Self::String(l0), Self::String(r0)) => Rc::ptr_eq(l0, r0),
             (Self::Function(f1), Self::Function(f2)) => 
                 f1.chunk == f2.chunk && f1.arity == f2.arity && f1.name == f2.name,
+            (Self::Closure(c1), Self::Closure(c2)) => c1 == c2,
+            (Self::Class(c1), Self::Class(c2)) => c1 == c2,
+            (Self::Instance(i1), Self::Instance(i2)) => i1 == i2,
+            (Self::BoundMethod(m1), Self::BoundMethod(m2)) => m1 == m2,
             _ => false,
         }
     }
 }
 
 impl Value {
+	/// String value that is not interned, so it won't be equal to any other string. Use
+	/// [`crate::vm::VM::new_string`] to create strings for a running VM.
 	pub fn new_string(value: &str) -> Self {
 		Value::String(
 			Rc::from(ObjString::from(value))
 		)
 	}
 
+    #[inline]
     pub fn is_falsey(&self) -> bool {
         match self {
             Value::Boolean(b) => !b,
@@ -39,8 +53,26 @@ impl Value {
             _ => false,
         }
     }
+
+    /// Index of the heap object this value points to, if it points to any.
+    pub fn heap_index(&self) -> Option<usize> {
+        match self {
+            Value::Closure(closure) => Some(closure.index()),
+            Value::Class(class) => Some(class.index()),
+            Value::Instance(instance) => Some(instance.index()),
+            Value::BoundMethod(bound) => Some(bound.index()),
+            _ => None,
+        }
+    }
+
+    /// Formats the value the way `print` shows it, looking up the heap objects it points to.
+    pub fn display<'a>(&'a self, heap: &'a Heap) -> ValueDisplay<'a> {
+        ValueDisplay { value: self, heap }
+    }
 }
 
+/// Formats values without access to the heap, so objects living there are only shown by kind.
+/// Use [`Value::display`] to get their actual contents.
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -49,7 +81,37 @@ impl Display for Value {
             Value::Number(n) => f.write_str(&n.to_string()),
             Value::String(obj) => f.write_str(&obj.value),
             Value::Function(of) => f.write_str(&format!("<fn '{}'>", of.name)),
+            Value::Closure(_) => f.write_str("<closure>"),
+            Value::Class(_) => f.write_str("<class>"),
+            Value::Instance(_) => f.write_str("<instance>"),
+            Value::BoundMethod(_) => f.write_str("<bound method>"),
             Value::Native(native) => f.write_str(&format!("<native '{}'>", native.name)),
         }
     }
 }
+
+pub struct ValueDisplay<'a> {
+    value: &'a Value,
+    heap: &'a Heap,
+}
+
+impl Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let heap = self.heap;
+        match self.value {
+            Value::Closure(closure) => {
+                f.write_str(&format!("<fn '{}'>", heap.get(*closure).function.name))
+            }
+            Value::Class(class) => f.write_str(&heap.get(*class).name),
+            Value::Instance(instance) => {
+                let class = heap.get(heap.get(*instance).class);
+                f.write_str(&format!("{} instance", class.name))
+            }
+            Value::BoundMethod(bound) => {
+                let method = heap.get(heap.get(*bound).method);
+                f.write_str(&format!("<fn '{}'>", method.function.name))
+            }
+            other => other.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! Checks compiled code before trusting it, like code loaded from a `.loxc` file or written with
+//! the [assembler](crate::assembler).
+//!
+//! Every path through a function is followed while keeping track of how many values the
+//! function has on the stack. The VM can then run verified code without underflowing the
+//! stack, reading past the end of a chunk, or using constants, locals and upvalues that don't
+//! exist.
+
+use std::{collections::HashSet, fmt::Display};
+
+use crate::{
+    chunk::{Chunk, Operation},
+    object::ObjFunction,
+    value::Value,
+};
+
+/// Why some code was rejected, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub function: String,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at {:04} in '{}'.",
+            self.message, self.offset, self.function
+        )
+    }
+}
+
+impl ObjFunction {
+    /// Checks the function is safe to run, along with every function declared inside it. The
+    /// function is taken to be the script, or any other function called as such.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        self.verify_as(false)
+    }
+
+    /// Methods get the receiver on their first slot, before the arguments.
+    fn verify_as(&self, method: bool) -> Result<(), VerifyError> {
+        let verifier = Verifier {
+            function: self,
+            chunk: &self.chunk,
+        };
+        let methods = verifier.run(self.arity as usize + method as usize)?;
+
+        for (index, constant) in self.chunk.constants.iter().enumerate() {
+            if let Value::Function(function) = constant {
+                function.verify_as(methods.contains(&index))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Verifier<'a> {
+    function: &'a ObjFunction,
+    chunk: &'a Chunk,
+}
+
+impl Verifier<'_> {
+    fn error(&self, offset: usize, message: &str) -> VerifyError {
+        VerifyError {
+            function: self.function.name.clone(),
+            offset,
+            message: message.to_string(),
+        }
+    }
+
+    /// Follows every path from the start of the function, which has `arguments` values on
+    /// the stack. Returns the constants holding functions used as methods.
+    fn run(&self, arguments: usize) -> Result<HashSet<usize>, VerifyError> {
+        let decoded = self.decode()?;
+        let mut depths: Vec<Option<usize>> = vec![None; self.chunk.len()];
+        let mut methods = HashSet::new();
+        let mut pending = vec![(0, arguments, 0)];
+
+        while let Some((offset, depth, from)) = pending.pop() {
+            if offset >= self.chunk.len() {
+                return Err(self.error(from, "Running past the end of the code"));
+            }
+            let (op, next) = match &decoded[offset] {
+                Some(decoded) => decoded,
+                None => return Err(self.error(from, "Jumping to the middle of an operation")),
+            };
+            match depths[offset] {
+                Some(known) if known == depth => continue,
+                Some(known) => {
+                    return Err(self.error(
+                        offset,
+                        &format!(
+                            "Reaching with {} values on the stack, and also {}",
+                            known, depth
+                        ),
+                    ))
+                }
+                None => depths[offset] = Some(depth),
+            }
+
+            self.check(offset, op, depth)?;
+            let (inputs, outputs) = Self::stack_effect(op);
+            if depth < inputs {
+                return Err(self.error(offset, "Stack underflow"));
+            }
+            let after = depth - inputs + outputs;
+
+            match op {
+                Operation::Return => {}
+                Operation::Jump(distance) => pending.push((next + distance, after, offset)),
+                Operation::Loop(distance) => match next.checked_sub(*distance) {
+                    Some(target) => pending.push((target, after, offset)),
+                    None => return Err(self.error(offset, "Looping before the start of the code")),
+                },
+                Operation::JumpIfFalse(distance) => {
+                    pending.push((next + distance, after, offset));
+                    pending.push((*next, after, offset));
+                }
+                Operation::Closure(index, _) => {
+                    if let Some((Operation::Method(_), _)) =
+                        decoded.get(*next).and_then(Option::as_ref)
+                    {
+                        methods.insert(*index);
+                    }
+                    pending.push((*next, after, offset));
+                }
+                _ => pending.push((*next, after, offset)),
+            }
+        }
+        Ok(methods)
+    }
+
+    /// Decodes every operation, indexed by their offset. Offsets in the middle of an operation
+    /// have nothing.
+    fn decode(&self) -> Result<Vec<Option<(Operation, usize)>>, VerifyError> {
+        let mut decoded = vec![None; self.chunk.len()];
+        let mut offset = 0;
+        while offset < self.chunk.len() {
+            let (op, next) = self
+                .chunk
+                .decode(offset)
+                .ok_or_else(|| self.error(offset, "Invalid operation"))?;
+            decoded[offset] = Some((op, next));
+            offset = next;
+        }
+        Ok(decoded)
+    }
+
+    /// Checks the operands of the operation, run with `depth` values on the stack.
+    fn check(&self, offset: usize, op: &Operation, depth: usize) -> Result<(), VerifyError> {
+        let constant = |index: usize| self.chunk.read_constant(index);
+        let upvalue_count = self.function.upvalue_count;
+        let valid = match op {
+            Operation::Constant(index) => constant(*index).is_some(),
+            Operation::GetLocal(slot) | Operation::SetLocal(slot) => *slot < depth,
+            Operation::GetUpvalue(index) | Operation::SetUpvalue(index) => *index < upvalue_count,
+            Operation::Closure(index, upvalues) => {
+                let captures_exist = upvalues.iter().all(|upvalue| {
+                    if upvalue.is_local {
+                        upvalue.index < depth
+                    } else {
+                        upvalue.index < upvalue_count
+                    }
+                });
+                let is_function = matches!(
+                    constant(*index),
+                    Some(Value::Function(nested)) if nested.upvalue_count == upvalues.len()
+                );
+                captures_exist && is_function
+            }
+            Operation::Class(index)
+            | Operation::GetProperty(index)
+            | Operation::SetProperty(index)
+            | Operation::Method(index)
+            | Operation::Invoke(index, _)
+            | Operation::GetSuper(index)
+            | Operation::SuperInvoke(index, _) => {
+                matches!(constant(*index), Some(Value::String(_)))
+            }
+            _ => true,
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(self.error(offset, &format!("Invalid operand in {:?}", op)))
+        }
+    }
+
+    /// How many values the operation takes from the top of the stack, and how many it leaves
+    /// there instead.
+    fn stack_effect(op: &Operation) -> (usize, usize) {
+        match op {
+            Operation::Constant(_)
+            | Operation::Nil
+            | Operation::True
+            | Operation::False
+            | Operation::GetGlobal(_)
+            | Operation::GetLocal(_)
+            | Operation::GetUpvalue(_)
+            | Operation::Closure(_, _)
+            | Operation::Class(_) => (0, 1),
+            Operation::Pop
+            | Operation::DefineGlobal(_)
+            | Operation::Print
+            | Operation::CloseUpvalue
+            | Operation::Return => (1, 0),
+            Operation::SetGlobal(_)
+            | Operation::SetLocal(_)
+            | Operation::SetUpvalue(_)
+            | Operation::Not
+            | Operation::Negate
+            | Operation::GetProperty(_)
+            | Operation::JumpIfFalse(_) => (1, 1),
+            Operation::Equal
+            | Operation::Greater
+            | Operation::Less
+            | Operation::Add
+            | Operation::Substract
+            | Operation::Multiply
+            | Operation::Divide
+            | Operation::SetProperty(_)
+            | Operation::Method(_)
+            | Operation::Inherit
+            | Operation::GetSuper(_) => (2, 1),
+            // The callee, or the receiver, and the arguments become the result
+            Operation::Call(arg_count) | Operation::Invoke(_, arg_count) => {
+                (*arg_count as usize + 1, 1)
+            }
+            // Along with the superclass
+            Operation::SuperInvoke(_, arg_count) => (*arg_count as usize + 2, 1),
+            Operation::Jump(_) | Operation::Loop(_) => (0, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble, compiler::Compiler, interner::Interner};
+
+    fn verify(source: &str) -> Result<(), String> {
+        let function =
+            assemble(source, &mut Interner::new()).expect("The assembly should be valid");
+        function.verify().map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn compiled_code_verifies() {
+        let source = String::from(
+            "
+var a = 1;
+fun outer(x) {
+    var y = x and a or 2;
+    fun inner() { return x + y; }
+    return inner;
+}
+class A {
+    init(v) { this.v = v; }
+    get() { return this.v; }
+}
+class B < A {
+    get() { return super.get() * 2; }
+}
+for (var i = 0; i < 3; i = i + 1) {
+    if (i > 1) print outer(i)(); else print B(i).get();
+}",
+        );
+        let mut interner = Interner::new();
+        let function = Compiler::from_source(&source, &mut interner)
+            .compile()
+            .unwrap();
+        assert_eq!(function.verify(), Ok(()));
+    }
+
+    #[test]
+    fn valid_code() {
+        assert_eq!(
+            verify(
+                "
+fun add 2 {
+    GetLocal 0
+    GetLocal 1
+    Add
+    Return
+}
+    Closure add
+    Constant 1
+    Constant 2
+    Call 2
+    JumpIfFalse skip
+    Print
+    Jump end
+skip:
+    Pop
+end:
+    Nil
+    Return"
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn invalid_code() {
+        assert_eq!(
+            verify("Constant #3\nReturn"),
+            Err(String::from(
+                "Invalid operand in Constant(3) at 0000 in 'main'."
+            ))
+        );
+        assert_eq!(
+            verify("Nil\nGetLocal 1\nReturn"),
+            Err(String::from(
+                "Invalid operand in GetLocal(1) at 0001 in 'main'."
+            ))
+        );
+        assert_eq!(
+            verify("GetUpvalue 0\nReturn"),
+            Err(String::from(
+                "Invalid operand in GetUpvalue(0) at 0000 in 'main'."
+            ))
+        );
+        assert_eq!(
+            verify("Add\nReturn"),
+            Err(String::from("Stack underflow at 0000 in 'main'."))
+        );
+        assert_eq!(
+            verify("Nil"),
+            Err(String::from(
+                "Running past the end of the code at 0000 in 'main'."
+            ))
+        );
+        assert_eq!(
+            verify("Jump #1\nConstant 1\nReturn"),
+            Err(String::from(
+                "Jumping to the middle of an operation at 0000 in 'main'."
+            ))
+        );
+        assert_eq!(
+            verify("Loop #10\nReturn"),
+            Err(String::from(
+                "Looping before the start of the code at 0000 in 'main'."
+            ))
+        );
+        assert_eq!(
+            verify("True\nJumpIfFalse end\nNil\nend:\nReturn"),
+            Err(String::from(
+                "Reaching with 2 values on the stack, and also 1 at 0005 in 'main'."
+            ))
+        );
+        assert_eq!(
+            verify("Closure f\nReturn\nfun f {\nGetLocal 0\nReturn\n}"),
+            Err(String::from(
+                "Invalid operand in GetLocal(0) at 0000 in 'f'."
+            ))
+        );
+    }
+
+    #[test]
+    fn methods_get_the_receiver() {
+        assert_eq!(
+            verify(
+                "
+    Class A
+    Closure m
+    Method m
+    Return
+fun m {
+    GetLocal 0
+    Return
+}"
+            ),
+            Ok(())
+        );
+    }
+}
@@ -1,25 +1,89 @@
-use std::{collections::HashMap, fmt::Display, io::Write, rc::Rc};
+use std::{collections::BTreeSet, fmt::Display, io::Write, rc::Rc};
 
 use crate::{
-    chunk::{IdentifierName, Operation},
+    chunk::{Chunk, OpCode, SourceLocation},
+    coverage::{Coverage, Recorder},
+    debugger::{Debugger, PauseReason, Resume},
+    heap::{GcConfig, GcRef, Heap, HeapObject},
+    interner::{Interner, SymbolId},
     native::clock,
-    object::{ObjFunction, ObjNative, ObjString},
+    object::{
+        NativeFn, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative,
+        ObjString, ObjUpvalue,
+    },
+    profiler::Profile,
     stack::Stack,
     value::Value,
 };
 
+/// One of the calls that were being run when a runtime error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    pub function: String,
+    pub location: SourceLocation,
+    /// Whether the function comes from the script being run, and not from an earlier one like
+    /// a previous line on the REPL, whose source is gone.
+    pub in_script: bool,
+}
+
 #[derive(Debug)]
 pub enum RuntimeError {
     NoMoreOperations(usize),
     Other(String),
+    /// An error raised by the code being run, along with the calls that led to it, innermost
+    /// first.
+    Traced(Box<RuntimeError>, Vec<TraceFrame>),
 }
 
 impl RuntimeError {
     pub fn new(message: &str) -> Self {
         Self::Other(message.to_string())
     }
+
+    /// The error itself, without the stack trace.
+    pub fn message(&self) -> String {
+        match self {
+            RuntimeError::Traced(error, _) => error.message(),
+            error => error.to_string(),
+        }
+    }
+
+    pub fn trace(&self) -> &[TraceFrame] {
+        match self {
+            RuntimeError::Traced(_, trace) => trace,
+            _ => &[],
+        }
+    }
+
+    /// The stack trace as lines like `[line 3] in fib`, innermost first. Runs of the same
+    /// frame, like from deep recursion, are cut short with a line telling how many were left
+    /// out.
+    pub fn trace_lines(&self) -> Vec<String> {
+        let trace = self.trace();
+        let mut lines = vec![];
+        let mut start = 0;
+        while start < trace.len() {
+            let frame = &trace[start];
+            let repeated = trace[start..].iter().take_while(|other| *other == frame).count();
+            for _ in 0..repeated.min(MAX_REPEATED_FRAMES) {
+                lines.push(format!("[line {}] in {}", frame.location.line, frame.function));
+            }
+            if repeated > MAX_REPEATED_FRAMES {
+                lines.push(format!(
+                    "... {} more frames in {}",
+                    repeated - MAX_REPEATED_FRAMES,
+                    frame.function
+                ));
+            }
+            start += repeated;
+        }
+        lines
+    }
 }
 
+/// How many times in a row the same frame is shown in stack traces.
+const MAX_REPEATED_FRAMES: usize = 3;
+
 impl Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -28,103 +92,490 @@ impl Display for RuntimeError {
                 ip
             )),
             RuntimeError::Other(msg) => f.write_str(msg),
+            RuntimeError::Traced(error, _) => {
+                write!(f, "{}", error)?;
+                for line in self.trace_lines() {
+                    write!(f, "\n{}", line)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
 pub type InterpretResult<V> = Result<V, RuntimeError>;
 
-#[derive(Clone)]
-struct CallFrame<'a> {
-    function: &'a ObjFunction,
+#[derive(Clone, Copy)]
+struct CallFrame {
+    closure: GcRef<ObjClosure>,
     ip: usize,
     first_slot: usize,
 }
 
-impl<'a> CallFrame<'a> {
-    pub fn new(function: &'a ObjFunction, first_slot: usize) -> Self {
+impl CallFrame {
+    pub fn new(closure: GcRef<ObjClosure>, first_slot: usize) -> Self {
         CallFrame {
-            function,
+            closure,
             ip: 0,
             first_slot,
         }
     }
 }
 
+/// How many nested calls can be made before failing with a stack overflow.
+pub const DEFAULT_MAX_FRAMES: usize = 1024;
+
+/// What a VM did since it was created, added up over every run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub instructions: u64,
+    pub collections: usize,
+    pub bytes_freed: usize,
+    /// The deepest the call stack got, counting the script itself.
+    pub max_frames: usize,
+}
+
+/// The debugger attached to a VM, and what it asked for when it last resumed.
+struct DebugState {
+    debugger: Box<dyn Debugger>,
+    resume: Resume,
+    // How many frames there were when resuming, to know when a step is done
+    resumed_depth: usize,
+    // Line and offset of the last operation run by each frame, to know when a line starts
+    last: Vec<(u32, usize)>,
+}
+
 pub struct VM {
     stack: Stack,
-    globals: HashMap<IdentifierName, Value>,
-    call_stack: Vec<String>,
+    // Indexed by the symbol of their name, `None` until defined
+    globals: Vec<Option<Value>>,
+    interner: Interner,
+    frames: Vec<CallFrame>,
+    max_frames: usize,
+    // How many operations a single run can execute, if limited
+    instruction_limit: Option<u64>,
+    // Whether to write the stack and each operation to the output before running it
+    trace: bool,
+    stats: Stats,
+    // Lines to stop at when a debugger is attached
+    breakpoints: BTreeSet<u32>,
+    debug: Option<DebugState>,
+    // Whether a debugger is looking at the VM, stopped before running an operation
+    paused: bool,
+    profile: Option<Profile>,
+    coverage: Option<Recorder>,
+    // Whether something has to look at each operation before it runs, like the tracing
+    instrumented: bool,
+    // Upvalues still pointing to a stack slot, sorted by that slot
+    open_upvalues: Vec<GcRef<ObjUpvalue>>,
+    heap: Heap,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
+        VM::with_gc_config(GcConfig::default())
+    }
+
+    pub fn with_gc_config(config: GcConfig) -> Self {
         let mut ret = VM {
             stack: Stack::new(),
-            globals: HashMap::new(),
-            call_stack: vec![],
+            globals: vec![],
+            interner: Interner::new(),
+            frames: vec![],
+            max_frames: DEFAULT_MAX_FRAMES,
+            instruction_limit: None,
+            trace: false,
+            stats: Stats::default(),
+            breakpoints: BTreeSet::new(),
+            debug: None,
+            paused: false,
+            profile: None,
+            coverage: None,
+            instrumented: false,
+            open_upvalues: vec![],
+            heap: Heap::new(config),
         };
-        ret.define_native("clock", clock).unwrap();
+        ret.define_native("clock", 0, clock);
         ret
     }
 
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    /// The interner code must be compiled with before this VM can run it, as strings are
+    /// compared by pointer and globals are looked up by symbol.
+    pub fn interner_mut(&mut self) -> &mut Interner {
+        &mut self.interner
+    }
+
+    /// Creates a string value, sharing it with every other equal string.
+    pub fn new_string(&mut self, value: &str) -> Value {
+        Value::String(self.interner.intern(value))
+    }
+
+    /// Limits how deep Lox calls can nest. Going past it fails with a "Stack overflow." error.
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    /// Stops each run after executing `limit` operations, so untrusted scripts can't loop
+    /// forever. `None` removes the limit.
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instruction_limit = limit;
+    }
+
+    /// Writes the stack and the operation about to be run to the output, before each step.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+        self.update_instrumented();
+    }
+
+    /// Hands control to `debugger` whenever the VM stops, from now on. It first stops as if it
+    /// had been resumed with `resume`, so [`Resume::StepIn`] stops at the first line run.
+    pub fn attach_debugger(&mut self, debugger: Box<dyn Debugger>, resume: Resume) {
+        self.debug = Some(DebugState {
+            debugger,
+            resume,
+            resumed_depth: self.frames.len(),
+            last: vec![],
+        });
+        self.update_instrumented();
+    }
+
+    pub fn detach_debugger(&mut self) -> Option<Box<dyn Debugger>> {
+        let debug = self.debug.take();
+        self.update_instrumented();
+        debug.map(|debug| debug.debugger)
+    }
+
+    /// Stops before running the first operation of the line, if a debugger is attached.
+    pub fn set_breakpoint(&mut self, line: u32) {
+        self.breakpoints.insert(line);
+    }
+
+    /// Returns whether there was a breakpoint at the line.
+    pub fn clear_breakpoint(&mut self, line: u32) -> bool {
+        self.breakpoints.remove(&line)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u32> {
+        &self.breakpoints
+    }
+
+    /// Measures where the time goes from now on, or stops doing so, forgetting what was
+    /// measured.
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profile = profiling.then(Profile::default);
+        self.update_instrumented();
+    }
+
+    /// What was measured since profiling started.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Records which lines and branches of the scripts run from now on, or stops doing so,
+    /// forgetting what was recorded.
+    pub fn set_coverage(&mut self, coverage: bool) {
+        self.coverage = coverage.then(Recorder::default);
+        self.update_instrumented();
+    }
+
+    /// What ran since coverage started being recorded.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref().map(Recorder::coverage)
+    }
+
+    fn update_instrumented(&mut self) {
+        self.instrumented = self.trace
+            || self.debug.is_some()
+            || self.profile.is_some()
+            || self.coverage.is_some();
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
     pub fn run_main<W: Write>(&mut self, function: &ObjFunction, output: &mut W) -> InterpretResult<()> {
-        self.stack.push(Value::Function(Rc::from(function.clone())));
-        self.run(function, output)
+        let function = Rc::new(function.clone());
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.load(&function);
+        }
+        let closure = self.alloc(ObjClosure::new(function, vec![]));
+        self.stack.push(Value::Closure(closure));
+        self.call(closure, 0)?;
+        if let Some(debug) = self.debug.as_mut() {
+            debug.last.clear();
+        }
+
+        let result = self.run(output).map_err(|error| {
+            let trace = self.call_stack();
+            RuntimeError::Traced(Box::new(error), trace)
+        });
+        if let Some(profile) = self.profile.as_mut() {
+            profile.finish();
+        }
+        if result.is_err() {
+            // Leave the VM ready to run something else, like the next line on the REPL. Closures
+            // that escaped keep what they captured, not whatever reuses its slot later.
+            self.close_upvalues(0);
+            self.stack.truncate(0);
+            self.frames.clear();
+        }
+        result
+    }
+
+    /// Where each of the frames being run is at, innermost first.
+    pub fn call_stack(&self) -> Vec<TraceFrame> {
+        let script = self
+            .frames
+            .first()
+            .map(|frame| &self.heap.get(frame.closure).function);
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, frame)| {
+                let function = &self.heap.get(frame.closure).function;
+                TraceFrame {
+                    function: if index == 0 {
+                        String::from("script")
+                    } else {
+                        function.name.clone()
+                    },
+                    location: function.chunk.location(self.frame_offset(index)),
+                    in_script: script
+                        .as_ref()
+                        .is_some_and(|script| script.declares(function)),
+                }
+            })
+            .collect()
+    }
+
+    /// Offset of the operation the frame at `index`, counting from the outermost, is running.
+    fn frame_offset(&self, index: usize) -> usize {
+        let ip = self.frames[index].ip;
+        if self.paused && index + 1 == self.frames.len() {
+            // Stopped right before running it
+            ip
+        } else {
+            // The ip already moved past the operation being run
+            ip.saturating_sub(1)
+        }
+    }
+
+    /// The local variables alive in a frame, by name. Frames are counted from the innermost one,
+    /// like in [`VM::call_stack`].
+    pub fn frame_locals(&self, depth: usize) -> Vec<(String, Value)> {
+        let index = match self.frames.len().checked_sub(depth + 1) {
+            Some(index) => index,
+            None => return vec![],
+        };
+        let frame = &self.frames[index];
+        let chunk = &self.heap.get(frame.closure).function.chunk;
+        chunk
+            .local_variables_at(self.frame_offset(index))
+            .into_iter()
+            .filter_map(|variable| {
+                let value = self.stack.get(frame.first_slot + variable.slot).ok()?;
+                Some((variable.name.clone(), value.clone()))
+            })
+            .collect()
+    }
+
+    /// Every global variable defined, sorted by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let mut globals: Vec<(String, Value)> = self
+            .globals
+            .iter()
+            .enumerate()
+            .filter_map(|(symbol, value)| {
+                let name = self.interner.symbol_name(symbol)?;
+                Some((name.to_string(), value.clone()?))
+            })
+            .collect();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        globals
+    }
+
+    /// The variable with that name in the innermost frame, or else the global one.
+    pub fn variable(&self, name: &str) -> Option<Value> {
+        let local = self
+            .frame_locals(0)
+            .into_iter()
+            .rev()
+            .find(|(local, _)| local == name);
+        match local {
+            Some((_, value)) => Some(value),
+            None => self
+                .globals()
+                .into_iter()
+                .find(|(global, _)| global == name)
+                .map(|(_, value)| value),
+        }
+    }
+
+    /// Frees every heap object that can't be reached from the stack, the globals, or the
+    /// frames being run. Returns the amount of bytes freed.
+    pub fn collect_garbage(&mut self) -> usize {
+        for value in self.stack.contents() {
+            self.heap.mark_value(value);
+        }
+        for value in self.globals.iter().flatten() {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark(frame.closure);
+        }
+        for upvalue in &self.open_upvalues {
+            self.heap.mark(*upvalue);
+        }
+
+        let strings = self.interner.bytes();
+        self.interner.sweep();
+        self.heap.set_external_bytes(self.interner.bytes());
+        let freed = self.heap.collect() + strings - self.interner.bytes();
+        self.stats.collections += 1;
+        self.stats.bytes_freed += freed;
+        freed
     }
 
-    fn run<W: Write>(&mut self, function: &ObjFunction, output: &mut W) -> InterpretResult<()> {
-        let mut frame = CallFrame::new(function, self.stack.len() - (function.arity as usize));
-        self.call_stack.push(function.name.clone());
+    /// Moves an object to the heap, collecting garbage first if it grew too much.
+    fn alloc<T: HeapObject>(&mut self, object: T) -> GcRef<T> {
+        if self.heap.should_collect() {
+            // The new object is not reachable from any root yet, but what it points to must
+            // survive
+            self.heap.mark_references(&object);
+            self.collect_garbage();
+        }
+        self.heap.alloc(object)
+    }
+
+    /// Interns a string made by the running code. Strings count toward when to collect
+    /// garbage, otherwise a loop that only builds strings would keep every one of them.
+    fn intern_result(&mut self, value: String) -> Rc<ObjString> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        let string = self.interner.intern_owned(value);
+        self.heap.set_external_bytes(self.interner.bytes());
+        string
+    }
+
+    /// Runs the frames on top of the call stack until the outermost one returns. Calls and
+    /// returns just push and pop frames, so deep Lox recursion never grows the native stack.
+    fn run<W: Write>(&mut self, output: &mut W) -> InterpretResult<()> {
+        // The current frame is cached here, and written back before switching frames
+        let mut frame = *self
+            .frames
+            .last()
+            .ok_or_else(|| RuntimeError::new("There is no function to run."))?;
+
+        let mut executed = 0;
+        let result = self.dispatch(&mut frame, output, &mut executed);
+        self.stats.instructions += executed;
+        if result.is_err() {
+            // So the stack trace knows where the error happened
+            self.save_ip(frame.ip);
+        }
+        result
+    }
 
-        let code = frame.function.chunk.code();
-        let chunk = &frame.function.chunk;
+    fn dispatch<W: Write>(
+        &mut self,
+        frame: &mut CallFrame,
+        output: &mut W,
+        executed: &mut u64,
+    ) -> InterpretResult<()> {
+        let mut function = Rc::clone(&self.heap.get(frame.closure).function);
+        let mut instructions_left = self.instruction_limit;
 
         loop {
-            let op = code
-                .get(frame.ip)
-                .ok_or(RuntimeError::NoMoreOperations(frame.ip))?;
-            frame.ip += 1;
-
-            #[cfg(feature = "trace")]
-            {
-                writeln!(output, "============").unwrap();
-                op.disassemble(&chunk, frame.ip - 1, output);
-                writeln!(output, "{}", self.stack).unwrap();
-                output.flush().unwrap();
+            if let Some(left) = instructions_left.as_mut() {
+                if *left == 0 {
+                    return Err(RuntimeError::new("Instruction limit exceeded."));
+                }
+                *left -= 1;
             }
+            *executed += 1;
+
+            let chunk = &function.chunk;
+
+            if self.instrumented {
+                if self.debug.is_some() {
+                    self.debug_operation(chunk, frame.ip)?;
+                }
+                if self.trace {
+                    self.trace_operation(chunk, frame.ip, output)?;
+                }
+                if let Some(profile) = self.profile.as_mut() {
+                    let code = chunk.code.get(frame.ip).copied().unwrap_or(u8::MAX);
+                    profile.operation(&function, self.frames.len(), code, chunk.line(frame.ip));
+                }
+                if let Some(coverage) = self.coverage.as_mut() {
+                    coverage.operation(self.frames.len(), frame.ip, chunk.line(frame.ip));
+                    if chunk.code.get(frame.ip) == Some(&(OpCode::JumpIfFalse as u8)) {
+                        let held = !self.stack.peek()?.is_falsey();
+                        coverage.condition(&function, frame.ip, held);
+                    }
+                }
+            }
+
+            let byte = Self::read_byte(chunk, &mut frame.ip)?;
+            let op = OpCode::from_byte(byte)
+                .ok_or_else(|| RuntimeError::new(&format!("Unknown operation code {}.", byte)))?;
+            let mut frame_changed = false;
 
             match op {
-                Operation::Constant(iid) => {
-                    let c = chunk.read_constant(*iid);
+                OpCode::Constant => {
+                    let index = Self::read_byte(chunk, &mut frame.ip)? as usize;
+                    let c = Self::read_constant(chunk, index)?;
+                    self.stack.push(c.clone());
+                }
+                OpCode::ConstantLong => {
+                    let index = Self::read_long(chunk, &mut frame.ip)?;
+                    let c = Self::read_constant(chunk, index)?;
                     self.stack.push(c.clone());
                 }
-                Operation::Nil => self.stack.push(Value::Nil),
-                Operation::True => self.stack.push(Value::Boolean(true)),
-                Operation::False => self.stack.push(Value::Boolean(false)),
-                Operation::Pop => {
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Boolean(true)),
+                OpCode::False => self.stack.push(Value::Boolean(false)),
+                OpCode::Pop => {
                     self.stack.pop()?; //.expect("There was nothing to pop");
                 }
-                Operation::GetGlobal(name) => {
-                    let val = self
-                        .globals
-                        .get(name)
-                        .ok_or(RuntimeError::new(&format!("Undefined variable '{}'", name)))?;
-                    self.stack.push(val.clone());
+                OpCode::GetGlobal => {
+                    let symbol = Self::read_short(chunk, &mut frame.ip)?;
+                    let val = self.global(symbol)?.clone();
+                    self.stack.push(val);
                 }
-                Operation::DefineGlobal(name) => {
-                    self.globals.insert(name.clone(), self.stack.pop()?);
+                OpCode::DefineGlobal => {
+                    let symbol = Self::read_short(chunk, &mut frame.ip)?;
+                    let value = self.stack.pop()?;
+                    self.define_global(symbol, value);
                 }
-                Operation::SetGlobal(name) => {
-                    if !self.globals.contains_key(name) {
-                        return Err(RuntimeError::new(&format!("Undefined variable '{}'", name)));
-                    }
-
-                    self.globals
-                        .insert(name.clone(), self.stack.peek()?.clone());
+                OpCode::SetGlobal => {
+                    let symbol = Self::read_short(chunk, &mut frame.ip)?;
+                    let value = self.stack.peek()?.clone();
+                    *self.global(symbol)? = value;
                 }
-                Operation::GetLocal(i) => {
-                    // writeln!(output, "frame.first_slot: {}", frame.first_slot);
+                OpCode::GetLocal => {
+                    let i = Self::read_byte(chunk, &mut frame.ip)? as usize;
                     let absolute_index = i + frame.first_slot;
                     let val = self
                         .stack
@@ -133,91 +584,406 @@ impl VM {
                         .clone();
                     self.stack.push(val);
                 }
-                Operation::SetLocal(i) => {
+                OpCode::SetLocal => {
+                    let i = Self::read_byte(chunk, &mut frame.ip)? as usize;
                     let absolute_index = frame.first_slot + i;
                     let val = self
                         .stack
                         .peek()?
                         // .expect("Expression not found in the stack to assign to local")
                         .clone();
-                    self.stack.set(absolute_index, val);
+                    self.stack.set(absolute_index, val)?;
                 }
-                Operation::Equal => {
+                OpCode::Equal => {
                     let b = self.stack.pop()?;
                     let a = self.stack.pop()?;
                     self.stack.push(Value::Boolean(a == b));
                 }
-                Operation::Greater => VM::binary(&mut self.stack, |a, b| Value::Boolean(a > b))?,
-                Operation::Less => VM::binary(&mut self.stack, |a, b| Value::Boolean(a < b))?,
-                Operation::Add => match self.stack.peek()? {
-                    Value::Number(_) => VM::binary(&mut self.stack, |a, b| Value::Number(a + b))?,
-                    Value::String(_) => {
-                        let b = self.stack.pop_string()?;
-                        let a = self.stack.pop_string()?;
-                        let result = format!("{}{}", a.value(), b.value());
-                        let value = Value::String(Rc::from(ObjString::from_owned(result)));
-                        self.stack.push(value);
-                    }
-                    v => Err(RuntimeError::new(&format!("Can't add the operand {:?}", v)))?,
-                },
-                Operation::Substract => VM::binary(&mut self.stack, |a, b| Value::Number(a - b))?,
-                Operation::Multiply => VM::binary(&mut self.stack, |a, b| Value::Number(a * b))?,
-                Operation::Divide => VM::binary(&mut self.stack, |a, b| Value::Number(a / b))?,
-                Operation::Not => {
+                OpCode::Greater => VM::binary(&mut self.stack, |a, b| Value::Boolean(a > b))?,
+                OpCode::Less => VM::binary(&mut self.stack, |a, b| Value::Boolean(a < b))?,
+                OpCode::Add => {
+                    let b = self.stack.pop()?;
+                    let a = self.stack.pop()?;
+                    let result = match (a, b) {
+                        (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+                        (Value::String(a), Value::String(b)) => {
+                            let result = format!("{}{}", a.value(), b.value());
+                            Value::String(self.intern_result(result))
+                        }
+                        _ => Err(RuntimeError::new(
+                            "Operands must be two numbers or two strings.",
+                        ))?,
+                    };
+                    self.stack.push(result);
+                }
+                OpCode::Substract => VM::binary(&mut self.stack, |a, b| Value::Number(a - b))?,
+                OpCode::Multiply => VM::binary(&mut self.stack, |a, b| Value::Number(a * b))?,
+                OpCode::Divide => VM::binary(&mut self.stack, |a, b| Value::Number(a / b))?,
+                OpCode::Not => {
                     let old = self.stack.pop()?;
                     let new = old.is_falsey();
                     self.stack.push(Value::Boolean(new));
                 }
-                Operation::Negate => {
+                OpCode::Negate => {
                     let n = self.stack.pop_number()?;
                     let res = -n;
                     self.stack.push(Value::Number(res));
                 }
-                Operation::Print => {
-                    writeln!(
-                        output,
-                        "{}",
-                        self.stack.pop()? // .expect("Tried to print a non-existing value")
-                    )
-                    .map_err(|x| {
+                OpCode::Print => {
+                    let value = self.stack.pop()?;
+                    writeln!(output, "{}", value.display(&self.heap)).map_err(|x| {
                         RuntimeError::new(&format!(
                             "Unexpected error while printing to output: {}",
                             x
                         ))
                     })?;
                 }
-                Operation::Return => {
-                    let result = self.stack.pop().unwrap();
-
-                    // Pop the arguments from the stack
-                    for _i in 0..function.arity {
-                        self.stack.pop().unwrap();
+                OpCode::GetUpvalue => {
+                    let i = Self::read_byte(chunk, &mut frame.ip)? as usize;
+                    let upvalue = self.upvalue(frame.closure, i)?;
+                    let val = match self.heap.get(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack.get(*slot)?.clone(),
+                        ObjUpvalue::Closed(val) => val.clone(),
+                    };
+                    self.stack.push(val);
+                }
+                OpCode::SetUpvalue => {
+                    let i = Self::read_byte(chunk, &mut frame.ip)? as usize;
+                    let val = self.stack.peek()?.clone();
+                    let upvalue = self.upvalue(frame.closure, i)?;
+                    match self.heap.get_mut(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack.set(*slot, val)?,
+                        ObjUpvalue::Closed(closed) => *closed = val,
                     }
+                }
+                OpCode::Return => {
+                    let result = self.stack.pop()?;
+
+                    // Move out the variables captured by closures before popping the frame
+                    self.close_upvalues(frame.first_slot);
+
+                    // Pop the arguments, locals and the function from the stack
+                    self.stack.truncate(frame.first_slot.saturating_sub(1));
 
-                    // Pop the function from the stack
-                    self.stack.pop().unwrap();
+                    self.frames.pop();
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
 
                     // Push the return value
                     self.stack.push(result);
-
-                    return Ok(());
+                    frame_changed = true;
                 }
-                Operation::JumpIfFalse(offset) => {
+                OpCode::JumpIfFalse => {
+                    let offset = Self::read_short(chunk, &mut frame.ip)?;
                     let exp = self.stack.peek()?; //.expect("Missing the if expression");
                     if exp.is_falsey() {
                         frame.ip += offset;
                     }
                 }
-                Operation::Jump(offset) => frame.ip += offset,
-                Operation::Loop(offset) => frame.ip -= offset,
-                Operation::Call(arg_count) => {
-                    let callee = self.stack.peek_many(*arg_count as usize)?.clone();
-                    self.call_value(&callee, *arg_count, output)?;
+                OpCode::Jump => {
+                    let offset = Self::read_short(chunk, &mut frame.ip)?;
+                    frame.ip += offset;
+                }
+                OpCode::Loop => {
+                    let offset = Self::read_short(chunk, &mut frame.ip)?;
+                    frame.ip = frame
+                        .ip
+                        .checked_sub(offset)
+                        .ok_or_else(|| RuntimeError::new("Tried to loop back before the function start."))?;
+                }
+                OpCode::Call => {
+                    let arg_count = Self::read_byte(chunk, &mut frame.ip)?;
+                    let callee = self.stack.peek_many(arg_count as usize)?.clone();
+                    self.save_ip(frame.ip);
+                    self.call_value(&callee, arg_count)?;
+                    frame_changed = true;
+                }
+                OpCode::Closure => {
+                    let index = Self::read_short(chunk, &mut frame.ip)?;
+                    let function = match Self::read_constant(chunk, index)? {
+                        Value::Function(function) => Rc::clone(function),
+                        other => {
+                            return Err(RuntimeError::new(&format!(
+                                "Expected a function to wrap in a closure, but found {}",
+                                other.display(&self.heap)
+                            )))
+                        }
+                    };
+                    let count = Self::read_byte(chunk, &mut frame.ip)?;
+                    let mut captured = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        let is_local = Self::read_byte(chunk, &mut frame.ip)? != 0;
+                        let index = Self::read_byte(chunk, &mut frame.ip)? as usize;
+                        captured.push(if is_local {
+                            self.capture_upvalue(frame.first_slot + index)
+                        } else {
+                            self.upvalue(frame.closure, index)?
+                        });
+                    }
+                    let closure = self.alloc(ObjClosure::new(function, captured));
+                    self.stack.push(Value::Closure(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.slot_below(0)?);
+                    self.stack.pop()?;
+                }
+                OpCode::Class => {
+                    let name = Self::read_name(chunk, &mut frame.ip)?;
+                    let class = self.alloc(ObjClass::new(name));
+                    self.stack.push(Value::Class(class));
                 }
+                OpCode::GetProperty => {
+                    let name = Self::read_name(chunk, &mut frame.ip)?;
+                    let instance = match self.stack.peek()? {
+                        Value::Instance(instance) => *instance,
+                        _ => return Err(RuntimeError::new("Only instances have properties.")),
+                    };
+
+                    let field = self.heap.get(instance).fields.get(name).cloned();
+                    let val = match field {
+                        Some(val) => val,
+                        None => {
+                            let method = self.find_method(self.heap.get(instance).class, name)?;
+                            let bound = ObjBoundMethod::new(Value::Instance(instance), method);
+                            Value::BoundMethod(self.alloc(bound))
+                        }
+                    };
+                    self.stack.pop()?;
+                    self.stack.push(val);
+                }
+                OpCode::SetProperty => {
+                    let name = Self::read_name(chunk, &mut frame.ip)?;
+                    let val = self.stack.pop()?;
+                    let instance = match self.stack.pop()? {
+                        Value::Instance(instance) => instance,
+                        _ => return Err(RuntimeError::new("Only instances have fields.")),
+                    };
+
+                    self.heap
+                        .get_mut(instance)
+                        .fields
+                        .insert(name.to_string(), val.clone());
+                    self.stack.push(val);
+                }
+                OpCode::Method => {
+                    let name = Self::read_name(chunk, &mut frame.ip)?;
+                    let method = match self.stack.pop()? {
+                        Value::Closure(closure) => closure,
+                        other => {
+                            return Err(RuntimeError::new(&format!(
+                                "Expected a method closure, but found {}",
+                                other.display(&self.heap)
+                            )))
+                        }
+                    };
+                    match self.stack.peek()? {
+                        Value::Class(class) => {
+                            self.heap
+                                .get_mut(*class)
+                                .methods
+                                .insert(name.to_string(), method);
+                        }
+                        other => {
+                            return Err(RuntimeError::new(&format!(
+                                "Expected a class to add the method to, but found {}",
+                                other.display(&self.heap)
+                            )))
+                        }
+                    }
+                }
+                OpCode::Invoke => {
+                    let name = Self::read_name(chunk, &mut frame.ip)?;
+                    let arg_count = Self::read_byte(chunk, &mut frame.ip)?;
+                    self.save_ip(frame.ip);
+                    self.invoke(name, arg_count)?;
+                    frame_changed = true;
+                }
+                OpCode::Inherit => {
+                    let subclass = self.pop_class()?;
+                    // The superclass stays on the stack as the 'super' local
+                    match self.stack.peek()? {
+                        Value::Class(superclass) => {
+                            let methods = self.heap.get(*superclass).methods.clone();
+                            self.heap.get_mut(subclass).methods.extend(methods);
+                        }
+                        _ => return Err(RuntimeError::new("Superclass must be a class.")),
+                    }
+                }
+                OpCode::GetSuper => {
+                    let name = Self::read_name(chunk, &mut frame.ip)?;
+                    let superclass = self.pop_class()?;
+                    let receiver = self.stack.pop()?;
+                    let method = self.find_method(superclass, name)?;
+                    let bound = self.alloc(ObjBoundMethod::new(receiver, method));
+                    self.stack.push(Value::BoundMethod(bound));
+                }
+                OpCode::SuperInvoke => {
+                    let name = Self::read_name(chunk, &mut frame.ip)?;
+                    let arg_count = Self::read_byte(chunk, &mut frame.ip)?;
+                    let superclass = self.pop_class()?;
+                    let receiver = self.stack.peek_many(arg_count as usize)?.clone();
+                    let method = self.find_method(superclass, name)?;
+                    self.save_ip(frame.ip);
+                    self.call_method(method, receiver, arg_count)?;
+                    frame_changed = true;
+                }
+            }
+
+            if frame_changed {
+                *frame = *self
+                    .frames
+                    .last()
+                    .ok_or_else(|| RuntimeError::new("There is no function to run."))?;
+                function = Rc::clone(&self.heap.get(frame.closure).function);
             }
         }
     }
 
+    #[inline]
+    fn read_byte(chunk: &Chunk, ip: &mut usize) -> InterpretResult<u8> {
+        let byte = chunk
+            .read_byte(*ip)
+            .ok_or(RuntimeError::NoMoreOperations(*ip))?;
+        *ip += 1;
+        Ok(byte)
+    }
+
+    #[inline]
+    fn read_short(chunk: &Chunk, ip: &mut usize) -> InterpretResult<usize> {
+        let short = chunk
+            .read_short(*ip)
+            .ok_or(RuntimeError::NoMoreOperations(*ip))?;
+        *ip += 2;
+        Ok(short as usize)
+    }
+
+    #[inline]
+    fn read_long(chunk: &Chunk, ip: &mut usize) -> InterpretResult<usize> {
+        let long = chunk
+            .read_long(*ip)
+            .ok_or(RuntimeError::NoMoreOperations(*ip))?;
+        *ip += 3;
+        Ok(long as usize)
+    }
+
+    /// Reads the operand of an operation referring to a name, and the name itself.
+    #[inline]
+    fn read_name<'c>(chunk: &'c Chunk, ip: &mut usize) -> InterpretResult<&'c str> {
+        let index = Self::read_short(chunk, ip)?;
+        match Self::read_constant(chunk, index)? {
+            Value::String(name) => Ok(&name.value),
+            other => Err(RuntimeError::new(&format!("Expected a name, but found {}", other))),
+        }
+    }
+
+    #[inline]
+    fn read_constant(chunk: &Chunk, index: usize) -> InterpretResult<&Value> {
+        chunk
+            .read_constant(index)
+            .ok_or_else(|| RuntimeError::new(&format!("There is no constant {}.", index)))
+    }
+
+    #[inline]
+    fn upvalue(&self, closure: GcRef<ObjClosure>, index: usize) -> InterpretResult<GcRef<ObjUpvalue>> {
+        self.heap
+            .get(closure)
+            .upvalues
+            .get(index)
+            .copied()
+            .ok_or_else(|| RuntimeError::new(&format!("There is no upvalue {}.", index)))
+    }
+
+    #[inline]
+    fn save_ip(&mut self, ip: usize) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = ip;
+        }
+    }
+
+    /// Stops before running the operation at `ip` if it starts a line the debugger wants to
+    /// stop at, and lets the debugger look around.
+    #[cold]
+    fn debug_operation(&mut self, chunk: &Chunk, ip: usize) -> InterpretResult<()> {
+        let depth = self.frames.len();
+        let line = chunk.line(ip);
+        let debug = self.debug.as_mut().expect("Only called when debugging");
+        // Returning to a frame resumes the line of the call instead of starting it
+        debug.last.truncate(depth);
+        let starts_line = match debug.last.get(depth.wrapping_sub(1)) {
+            // Jumping back, as in a loop, runs the line again
+            Some(&(last_line, last_ip)) => line != last_line || ip <= last_ip,
+            None => true,
+        };
+        if debug.last.len() < depth {
+            debug.last.resize(depth, (0, 0));
+        }
+        debug.last[depth - 1] = (line, ip);
+        if !starts_line || line == 0 {
+            return Ok(());
+        }
+
+        let reason = if self.breakpoints.contains(&line) {
+            PauseReason::Breakpoint
+        } else {
+            let step_done = match debug.resume {
+                Resume::StepIn => true,
+                Resume::StepOver => depth <= debug.resumed_depth,
+                Resume::StepOut => depth < debug.resumed_depth,
+                Resume::Continue | Resume::Stop => false,
+            };
+            if !step_done {
+                return Ok(());
+            }
+            PauseReason::Step
+        };
+
+        self.save_ip(ip);
+        let mut debug = self.debug.take().expect("Only called when debugging");
+        self.paused = true;
+        let resume = debug.debugger.paused(self, reason);
+        self.paused = false;
+        debug.resume = resume;
+        debug.resumed_depth = depth;
+        self.debug = Some(debug);
+
+        if resume == Resume::Stop {
+            return Err(RuntimeError::new("Stopped by the debugger."));
+        }
+        Ok(())
+    }
+
+    /// Writes the stack and the operation at `ip`, which is about to be run.
+    #[cold]
+    fn trace_operation<W: Write>(&self, chunk: &Chunk, ip: usize, output: &mut W) -> InterpretResult<()> {
+        let write_error = |x: std::io::Error| {
+            RuntimeError::new(&format!("Unexpected error while tracing to output: {}", x))
+        };
+        writeln!(output, "============").map_err(write_error)?;
+        chunk
+            .disassemble_instruction(ip, &self.interner, output)
+            .map_err(write_error)?;
+        writeln!(output, "{}", self.stack).map_err(write_error)?;
+        output.flush().map_err(write_error)
+    }
+
+    /// Pushes a new frame for the closure, whose arguments are on top of the stack.
+    #[inline]
+    fn call(&mut self, closure: GcRef<ObjClosure>, arg_count: u8) -> InterpretResult<()> {
+        let function = &self.heap.get(closure).function;
+        if function.arity != arg_count {
+            return Err(Self::arity_error(&function.name, function.arity, arg_count));
+        }
+        if self.frames.len() >= self.max_frames {
+            return Err(RuntimeError::new("Stack overflow."));
+        }
+
+        let first_slot = self.first_argument_slot(arg_count)?;
+        self.frames.push(CallFrame::new(closure, first_slot));
+        self.stats.max_frames = self.stats.max_frames.max(self.frames.len());
+        Ok(())
+    }
+
     fn binary<F>(stack: &mut Stack, implementation: F) -> InterpretResult<()>
     where
         F: Fn(f64, f64) -> Value,
@@ -229,53 +995,203 @@ impl VM {
         Ok(())
     }
 
-    fn call_value<W: Write>(
-        &mut self,
-        callee: &Value,
-        _arg_count: u8,
-        output: &mut W,
-    ) -> InterpretResult<()> {
+    #[inline]
+    fn call_value(&mut self, callee: &Value, arg_count: u8) -> InterpretResult<()> {
         match callee {
-            Value::Function(fun) => {
-                self.run(fun, output)?;
-                Ok(())
+            Value::Closure(closure) => self.call(*closure, arg_count),
+            Value::Class(class) => {
+                let callee_slot = self.stack.slot_below(arg_count as usize)?;
+                let instance = Value::Instance(self.alloc(ObjInstance::new(*class)));
+                match self.heap.get(*class).find_method("init") {
+                    Some(initializer) => self.call_method(initializer, instance, arg_count),
+                    None if arg_count != 0 => Err(Self::arity_error(
+                        &self.heap.get(*class).name,
+                        0,
+                        arg_count,
+                    )),
+                    None => {
+                        self.stack.set(callee_slot, instance)?;
+                        Ok(())
+                    }
+                }
+            }
+            Value::BoundMethod(bound) => {
+                let bound = self.heap.get(*bound);
+                self.call_method(bound.method, bound.receiver.clone(), arg_count)
             }
             Value::Native(native) => {
-                let result = (native.function)();
-                self.stack.push(Value::Number(result));
+                if native.arity != arg_count {
+                    return Err(Self::arity_error(&native.name, native.arity, arg_count));
+                }
+
+                // The arguments stay on the stack during the call so the collector sees them
+                let callee_slot = self.stack.slot_below(arg_count as usize)?;
+                let args = (callee_slot + 1..self.stack.len())
+                    .map(|slot| self.stack.get(slot).cloned())
+                    .collect::<InterpretResult<Vec<Value>>>()?;
+                let result = match (native.function)(self, &args)? {
+                    // Natives may build strings on their own, share them like any other
+                    Value::String(string) => Value::String(self.interner.intern_rc(string)),
+                    result => result,
+                };
+
+                self.stack.truncate(callee_slot);
+                self.stack.push(result);
                 Ok(())
             }
             other => Err(RuntimeError::new(&format!(
                 "Expected a function or a class to call, but found {}",
-                other
+                other.display(&self.heap)
             ))),
         }
     }
 
-    fn define_native(&mut self, name: &str, function: fn() -> f64) -> InterpretResult<()> {
-        let obj_native = ObjNative::new(name, function);
-        let native = Value::Native(obj_native);
-        self.globals.insert(name.to_string(), native);
+    /// Calls a method with `receiver` bound to `this`. The receiver is placed right above the
+    /// callee so it becomes the method's local 0, and returning discards both of them.
+    fn call_method(
+        &mut self,
+        method: GcRef<ObjClosure>,
+        receiver: Value,
+        arg_count: u8,
+    ) -> InterpretResult<()> {
+        let receiver_slot = self.first_argument_slot(arg_count)?;
+        self.call(method, arg_count)?;
+        self.stack.insert(receiver_slot, receiver);
         Ok(())
     }
+
+    #[inline]
+    fn first_argument_slot(&self, arg_count: u8) -> InterpretResult<usize> {
+        self.stack
+            .len()
+            .checked_sub(arg_count as usize)
+            .ok_or_else(|| RuntimeError::new("Missing arguments in the stack."))
+    }
+
+    fn arity_error(callee: &str, arity: u8, arg_count: u8) -> RuntimeError {
+        RuntimeError::new(&format!(
+            "Expected {} arguments but got {} when calling '{}'.",
+            arity, arg_count, callee
+        ))
+    }
+
+    /// Calls a property of the instance sitting below the arguments, without creating an
+    /// intermediate bound method when it's a method.
+    fn invoke(&mut self, name: &str, arg_count: u8) -> InterpretResult<()> {
+        let receiver = self.stack.peek_many(arg_count as usize)?.clone();
+        let instance = match &receiver {
+            Value::Instance(instance) => self.heap.get(*instance),
+            _ => return Err(RuntimeError::new("Only instances have methods.")),
+        };
+
+        if let Some(field) = instance.fields.get(name).cloned() {
+            let callee_slot = self.stack.slot_below(arg_count as usize)?;
+            self.stack.set(callee_slot, field.clone())?;
+            return self.call_value(&field, arg_count);
+        }
+
+        let method = self.find_method(instance.class, name)?;
+        self.call_method(method, receiver, arg_count)
+    }
+
+    fn pop_class(&mut self) -> InterpretResult<GcRef<ObjClass>> {
+        match self.stack.pop()? {
+            Value::Class(class) => Ok(class),
+            other => Err(RuntimeError::new(&format!(
+                "Expected a class but found {}",
+                other.display(&self.heap)
+            ))),
+        }
+    }
+
+    fn find_method(&self, class: GcRef<ObjClass>, name: &str) -> InterpretResult<GcRef<ObjClosure>> {
+        self.heap
+            .get(class)
+            .find_method(name)
+            .ok_or_else(|| RuntimeError::new(&format!("Undefined property '{}'.", name)))
+    }
+
+    /// Returns the upvalue pointing to the given stack slot, reusing it if some other closure
+    /// already captured that same variable.
+    fn capture_upvalue(&mut self, slot: usize) -> GcRef<ObjUpvalue> {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, upvalue) in self.open_upvalues.iter().enumerate() {
+            if let ObjUpvalue::Open(open_slot) = *self.heap.get(*upvalue) {
+                if open_slot == slot {
+                    return *upvalue;
+                } else if open_slot > slot {
+                    insert_at = i;
+                    break;
+                }
+            }
+        }
+
+        let upvalue = self.alloc(ObjUpvalue::Open(slot));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    /// Closes every open upvalue pointing at `last_slot` or above it on the stack.
+    fn close_upvalues(&mut self, last_slot: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *self.heap.get(*upvalue) {
+                ObjUpvalue::Open(slot) if slot >= last_slot => slot,
+                _ => break,
+            };
+            let value = self.stack.get(slot).cloned().unwrap_or(Value::Nil);
+            *self.heap.get_mut(*upvalue) = ObjUpvalue::Closed(value);
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// Makes a host function callable from Lox as a global named `name`, taking exactly `arity`
+    /// arguments.
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let obj_native = ObjNative::new(name, arity, function);
+        let native = Value::Native(obj_native);
+        let symbol = self.interner.symbol(name);
+        self.define_global(symbol, native);
+    }
+
+    #[inline]
+    fn define_global(&mut self, symbol: SymbolId, value: Value) {
+        if symbol >= self.globals.len() {
+            self.globals.resize(symbol + 1, None);
+        }
+        self.globals[symbol] = Some(value);
+    }
+
+    /// The global variable for a symbol, failing if it was never defined.
+    #[inline]
+    fn global(&mut self, symbol: SymbolId) -> InterpretResult<&mut Value> {
+        match self.globals.get_mut(symbol) {
+            Some(Some(value)) => Ok(value),
+            _ => Err(RuntimeError::new(&format!(
+                "Undefined variable '{}'",
+                self.interner.symbol_name(symbol).unwrap_or("?")
+            ))),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::VM;
-    use crate::{chunk::Operation, object::ObjFunction, value::Value, vm::RuntimeError};
+    use crate::{
+        assembler::assemble,
+        object::ObjClosure,
+        value::Value,
+        vm::RuntimeError,
+    };
     use std::{io, rc::Rc};
 
     #[test]
     fn constants() {
-        let function = &mut ObjFunction::from_operations(
-            "test",
-            &mut vec![Operation::Constant(0), Operation::True, Operation::Nil],
-        );
-        function.chunk.add_constant(Value::Number(2.0));
-
         assert_stack(
-            function,
+            "
+    Constant 2
+    True
+    Nil",
             vec![Value::Number(2.0), Value::Boolean(true), Value::Nil],
         );
     }
@@ -283,70 +1199,53 @@ mod tests {
     #[test]
     fn recursive_functions() {
         // Recursive definition of fact, will use it on the tests
-        let mut fact = ObjFunction::new("fact");
-        fact.arity = 1;
-        fact.chunk.emit_many(&mut vec![
-            // Condition
-            Operation::GetLocal(0),
-            Operation::Constant(0),
-            Operation::Greater,
-            Operation::Not,
-            Operation::JumpIfFalse(4),
-            // Then
-            Operation::Pop,
-            Operation::Constant(1),
-            Operation::Return,
-            // Else
-            Operation::Jump(9),
-            Operation::Pop,
-            Operation::GetLocal(0),
-            Operation::GetGlobal("fact".to_string()),
-            Operation::GetLocal(0),
-            Operation::Constant(2),
-            Operation::Substract,
-            Operation::Call(1),
-            Operation::Multiply,
-            Operation::Return,
-            // Cleanup
-            Operation::Nil,
-            Operation::Return,
-        ]);
-        fact.chunk.add_constant(Value::Number(1.0));
-        fact.chunk.add_constant(Value::Number(1.0));
-        fact.chunk.add_constant(Value::Number(1.0));
-
-
-        let main = &mut ObjFunction::from_operations(
-            "main",
-            &mut vec![
-                // Definition
-                Operation::Constant(0),
-                Operation::DefineGlobal("fact".to_string()),
-                // Call
-                Operation::GetGlobal("fact".to_string()),
-                Operation::Constant(1),
-                Operation::Call(1),
-                // Print
-                Operation::Print,
-            ],
-        );
-        main.chunk
-            .add_constant(Value::Function(Rc::from(fact.clone())));
-        main.chunk.add_constant(Value::Number(5.0));
-
         assert_stack(
-            main,
+            "
+fun fact 1 {
+    ; Condition
+    GetLocal 0
+    Constant 1
+    Greater
+    Not
+    JumpIfFalse else
+    ; Then
+    Pop
+    Constant 1
+    Return
+else:
+    Pop
+    GetLocal 0
+    GetGlobal fact
+    GetLocal 0
+    Constant 1
+    Substract
+    Call 1
+    Multiply
+    Return
+}
+    ; Definition
+    Closure fact
+    DefineGlobal fact
+    ; Call
+    GetGlobal fact
+    Constant 5
+    Call 1
+    Print",
             vec![],
         );
     }
 
     ////////////////
 
-    fn assert_stack(function: &mut ObjFunction, stack: Vec<Value>) {
+    /// Runs the assembly, which must leave the stack as given when it runs out of operations.
+    fn assert_stack(source: &str, stack: Vec<Value>) {
         let mut stdout = io::stdout();
 
         let mut vm = VM::new();
-        match vm.run(&function, &mut stdout) {
+        let function = assemble(source, vm.interner_mut()).unwrap();
+        let closure = vm.alloc(ObjClosure::new(Rc::from(function), vec![]));
+        vm.call(closure, 0).unwrap();
+        match vm.run(&mut stdout) {
             Ok(_) => panic!("Expected the VM to halt but it didn't"),
             Err(RuntimeError::NoMoreOperations(_)) => {
                 assert_eq!(
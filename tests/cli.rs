@@ -0,0 +1,225 @@
+//! Runs the `lox` binary the way scripts and CI do, checking what it prints and how it exits.
+
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+fn lox(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rlox_vm"))
+        .args(args)
+        .arg("--no-color")
+        .output()
+        .expect("The binary should start")
+}
+
+/// A file only this test writes to, so tests can run in parallel.
+fn script(name: &str, source: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("rlox_vm_cli_{}_{}", std::process::id(), name));
+    fs::write(&path, source).unwrap();
+    path
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn exit_codes() {
+    let ok = script("ok.lox", "print 1 + 2;");
+    let output = lox(&["run", ok.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n");
+
+    // Errors are kept apart from what the script prints
+    let broken = script("broken.lox", "print 1;\nprint 1 +;");
+    let output = lox(&[broken.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).starts_with("error: Expect expression."));
+    assert_eq!(lox(&["check", broken.to_str().unwrap()]).status.code(), Some(65));
+    assert_eq!(lox(&["-e", "1 +"]).status.code(), Some(65));
+
+    let failing = script("failing.lox", "print 1;\nprint 1 + nil;");
+    let output = lox(&["run", failing.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "1\n");
+    assert!(stderr(&output).starts_with("error: Operands must be two numbers or two strings."));
+
+    assert_eq!(lox(&["run", "/nonexistent/script.lox"]).status.code(), Some(74));
+    assert_eq!(lox(&["run"]).status.code(), Some(64));
+
+    for path in [ok, broken, failing] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn compiled_scripts() {
+    let source = script("compiled.lox", "fun f(n) { return n * 2; }\nprint f(21);");
+    let compiled = source.with_extension("loxc");
+
+    let output = lox(&["compile", source.to_str().unwrap(), "-o", compiled.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let output = lox(&["run", compiled.to_str().unwrap()]);
+    assert_eq!(stdout(&output), "42\n");
+
+    // Damaged files are data errors, like scripts that don't compile
+    let mut bytes = fs::read(&compiled).unwrap();
+    bytes.truncate(bytes.len() - 1);
+    fs::write(&compiled, bytes).unwrap();
+    assert_eq!(lox(&["run", compiled.to_str().unwrap()]).status.code(), Some(65));
+
+    fs::remove_file(source).unwrap();
+    fs::remove_file(compiled).unwrap();
+}
+
+#[test]
+fn eval() {
+    assert_eq!(stdout(&lox(&["-e", "1 + 2"])), "3\n");
+    assert_eq!(stdout(&lox(&["-e", "var a = \"b\"; print a + a;"])), "bb\n");
+}
+
+#[test]
+fn debug() {
+    let path = script("debug.lox", "var a = 1;\nprint a + 1;");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox_vm"))
+        .args(["--debug", path.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("The binary should start");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"n\np a\nc\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        "\
+Stopped at line 1 in script: var a = 1;
+(debug) Stopped at line 2 in script: print a + 1;
+(debug) a = 1
+(debug) 2
+"
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn profile() {
+    let path = script("profile.lox", "fun f() { return 1; }\nprint f() + f();");
+    let folded = path.with_extension("folded");
+    let output = lox(&[
+        "run",
+        path.to_str().unwrap(),
+        "--profile",
+        "--folded",
+        folded.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "2\n");
+    let report = String::from_utf8_lossy(&output.stderr);
+    assert!(report.starts_with("Functions, by self time:\n"));
+    assert!(report.contains("  f:1\n"));
+
+    let stacks = fs::read_to_string(&folded).unwrap();
+    let stacks: Vec<&str> = stacks
+        .lines()
+        .map(|line| line.rsplit_once(' ').unwrap().0)
+        .collect();
+    assert_eq!(stacks, ["script", "script;f:1"]);
+
+    assert_eq!(lox(&["--profile", "check", path.to_str().unwrap()]).status.code(), Some(64));
+
+    fs::remove_file(path).unwrap();
+    fs::remove_file(folded).unwrap();
+}
+
+#[test]
+fn coverage() {
+    let path = script(
+        "covered.lox",
+        "var n = 1;\nif (n > 0) print \"positive\";\nelse print \"other\";\n",
+    );
+    let lcov = path.with_extension("info");
+    let _ = fs::remove_file(&lcov);
+
+    let output = lox(&["--coverage", lcov.to_str().unwrap(), path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let text = fs::read_to_string(&lcov).unwrap();
+    assert!(text.starts_with(&format!("TN:\nSF:{}\n", path.display())));
+    assert!(text.contains("BRH:1\n"));
+    assert!(text.contains("DA:2,1\nDA:3,0\n"));
+
+    // Runs add up, even when the script fails
+    let failing = "var n = 0;\nif (n > 0) print \"positive\";\nelse print nil + 1;\n";
+    fs::write(&path, failing).unwrap();
+    let output = lox(&["--coverage", lcov.to_str().unwrap(), path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(70));
+    let text = fs::read_to_string(&lcov).unwrap();
+    assert!(text.contains("BRH:2\n"));
+    assert!(text.contains("DA:2,2\nDA:3,1\nLF:3\nLH:3\n"));
+
+    fs::write(&lcov, "DA:1,1\n").unwrap();
+    let output = lox(&["--coverage", lcov.to_str().unwrap(), path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(65));
+
+    fs::remove_file(path).unwrap();
+    fs::remove_file(lcov).unwrap();
+}
+
+#[test]
+fn dap() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox_vm"))
+        .arg("dap")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("The binary should start");
+    let request = r#"{"seq":1,"type":"request","command":"initialize","arguments":{}}"#;
+    write!(
+        child.stdin.take().unwrap(),
+        "Content-Length: {}\r\n\r\n{}",
+        request.len(),
+        request
+    )
+    .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with("Content-Length: "));
+    assert!(stdout.contains(r#""command":"initialize""#));
+    assert!(stdout.contains(r#""event":"initialized""#));
+}
+
+#[test]
+fn lsp() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox_vm"))
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("The binary should start");
+    let mut stdin = child.stdin.take().unwrap();
+    for message in [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+        r#"{"jsonrpc":"2.0","method":"exit"}"#,
+    ] {
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", message.len(), message).unwrap();
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).contains(r#""definitionProvider":true"#));
+}
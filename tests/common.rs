@@ -1,8 +1,8 @@
 use std::io::Write;
 
-use rlox_vm::{compiler::Compiler, vm::{VM, RuntimeError}};
+use rlox_vm::{compiler::Compiler, heap::GcConfig, interner::Interner, vm::{VM, RuntimeError}};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Output {
     pub contents: String,
 }
@@ -17,7 +17,7 @@ impl Output {
 
 impl Write for Output {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.contents.push_str(&std::str::from_utf8(buf).unwrap());
+        self.contents.push_str(std::str::from_utf8(buf).unwrap());
 
         Ok(buf.len())
     }
@@ -29,12 +29,10 @@ impl Write for Output {
 
 pub fn assert_expression(exp_source: &str, expected: &str) {
 	let source= format!("print {};", exp_source);
-    let mut compiler = Compiler::from_source(&source);
-	let frame = compiler.compile();
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+	let frame = compiler.compile().expect("This script should compile");
 
-	assert!(!compiler.had_error);
-
-	let mut vm = VM::new();
 	let mut stdout = Output::new();
 	
 	vm.run_main(&frame, &mut stdout).unwrap();
@@ -43,13 +41,11 @@ pub fn assert_expression(exp_source: &str, expected: &str) {
 }
 
 pub fn assert_script_output(script_source: &str, expected: &str) {
-	let source= format!("{}", script_source);
-    let mut compiler = Compiler::from_source(&source);
-	let frame = compiler.compile();
-
-	assert!(!compiler.had_error);
+	let source= script_source.to_string();
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+	let frame = compiler.compile().expect("This script should compile");
 
-	let mut vm = VM::new();
 	let mut stdout = Output::new();
 	
 	vm.run_main(&frame, &mut stdout).unwrap();
@@ -58,18 +54,39 @@ pub fn assert_script_output(script_source: &str, expected: &str) {
 }
 
 pub fn assert_script_error(script_source: &str, expected_error_message: &str) {
-	let source= format!("{}", script_source);
-    let mut compiler = Compiler::from_source(&source);
-	let frame = compiler.compile();
+	let source= script_source.to_string();
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+	let frame = compiler.compile().expect("This script should compile");
 
-	assert!(!compiler.had_error);
-
-	let mut vm = VM::new();
 	let mut stdout = Output::new();
 	
 	let result = vm.run_main(&frame, &mut stdout);
     match result.expect_err("This script should have failed") {
-        RuntimeError::Other(msg) =>assert_eq!(msg.trim_end_matches("\n"), expected_error_message),
-        err => panic!("Not the expected error: {:?}", err),
+        RuntimeError::NoMoreOperations(ip) => panic!("Not the expected error: no more operations at {}", ip),
+        err => assert_eq!(err.message().trim_end_matches("\n"), expected_error_message),
     }
 }
+
+pub fn assert_compile_error(script_source: &str, expected_error_message: &str) {
+	let source = script_source.to_string();
+    let mut interner = Interner::new();
+    let mut compiler = Compiler::from_source(&source, &mut interner);
+	let diagnostics = compiler.compile().expect_err("This script should have failed to compile");
+
+	assert_eq!(diagnostics[0].message, expected_error_message);
+}
+
+pub fn assert_gc_stress_output(script_source: &str, expected: &str) {
+	let source = script_source.to_string();
+	// Collecting on every allocation makes any object that is not properly rooted disappear
+    let mut vm = VM::with_gc_config(GcConfig::stress());
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+	let frame = compiler.compile().expect("This script should compile");
+
+	let mut stdout = Output::new();
+
+	vm.run_main(&frame, &mut stdout).unwrap();
+
+	assert_eq!(stdout.contents.trim_end_matches("\n"), expected);
+}
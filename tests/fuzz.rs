@@ -0,0 +1,170 @@
+//! Property tests feeding mangled programs to the compiler and the VM. Whatever the input, they
+//! must report a diagnostic or a runtime error instead of panicking.
+//!
+//! The seeds are the same corpus used by the libFuzzer target in `fuzz/`.
+
+use std::{fs, io, path::Path};
+
+use proptest::prelude::*;
+use rlox_vm::{
+    compiler::Compiler,
+    interner::Interner,
+    object::ObjFunction,
+    render::{Renderer, Style},
+    vm::VM,
+};
+
+fn corpus() -> Vec<String> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/interpret");
+    let mut seeds: Vec<String> = fs::read_dir(dir)
+        .expect("The fuzz corpus should exist")
+        .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+        .collect();
+    seeds.sort();
+    seeds
+}
+
+/// Compiles and runs the source the way the binary does, reporting errors to nowhere. The
+/// compiled code must pass the verifier.
+fn interpret(source: &str) {
+    let renderer = Renderer::new("fuzz.lox", source, Style::Ansi);
+    let source = source.to_string();
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+
+    match compiler.compile() {
+        Ok(function) => {
+            function
+                .verify()
+                .expect("Everything the compiler emits should verify");
+            // Mangled programs can easily loop forever, or run fib(35)
+            vm.set_instruction_limit(Some(20_000));
+            if let Err(error) = vm.run_main(&function, &mut io::sink()) {
+                renderer.runtime_error(&error);
+            }
+        }
+        Err(diagnostics) => {
+            for diagnostic in diagnostics {
+                renderer.diagnostic(&diagnostic);
+            }
+        }
+    }
+}
+
+const TOKENS: &[&str] = &[
+    "(", ")", "{", "}", ",", ".", "-", "+", ";", "/", "*", "!", "!=", "=", "==", ">", ">=", "<",
+    "<=", "and", "class", "else", "false", "for", "fun", "if", "nil", "or", "print", "return",
+    "super", "this", "true", "var", "while", "a", "b", "init", "1", "2.5", "\"s\"", "\"",
+];
+
+fn token_soup() -> impl Strategy<Value = String> {
+    prop::collection::vec(prop::sample::select(TOKENS), 0..64).prop_map(|tokens| tokens.join(" "))
+}
+
+#[derive(Debug, Clone)]
+enum Edit {
+    Delete(usize, usize),
+    Insert(usize, &'static str),
+    Duplicate(usize, usize),
+}
+
+/// A seed from the corpus with a few random edits.
+fn mutated_seed() -> impl Strategy<Value = String> {
+    let edit = prop_oneof![
+        (any::<usize>(), 0..16usize).prop_map(|(at, len)| Edit::Delete(at, len)),
+        (any::<usize>(), prop::sample::select(TOKENS)).prop_map(|(at, t)| Edit::Insert(at, t)),
+        (any::<usize>(), 0..32usize).prop_map(|(at, len)| Edit::Duplicate(at, len)),
+    ];
+    (
+        prop::sample::select(corpus()),
+        prop::collection::vec(edit, 1..6),
+    )
+        .prop_map(|(seed, edits)| {
+            let mut chars: Vec<char> = seed.chars().collect();
+            for edit in edits {
+                let len = chars.len() + 1;
+                match edit {
+                    Edit::Delete(at, count) => {
+                        let at = at % len;
+                        let end = (at + count).min(chars.len());
+                        chars.drain(at.min(end)..end);
+                    }
+                    Edit::Insert(at, token) => {
+                        let at = at % len;
+                        chars.splice(at..at, format!(" {} ", token).chars());
+                    }
+                    Edit::Duplicate(at, count) => {
+                        let at = at % len;
+                        let end = (at + count).min(chars.len());
+                        let copy: Vec<char> = chars[at.min(end)..end].to_vec();
+                        chars.splice(at..at, copy);
+                    }
+                }
+            }
+            chars.into_iter().collect()
+        })
+}
+
+/// The corpus compiled to `.loxc`, with some of its bytes overwritten.
+fn mutated_loxc() -> impl Strategy<Value = Vec<u8>> {
+    let compiled: Vec<Vec<u8>> = corpus()
+        .into_iter()
+        .filter_map(|seed| {
+            let mut interner = Interner::new();
+            let function = Compiler::from_source(&seed, &mut interner).compile().ok()?;
+            function.serialize(&interner).ok()
+        })
+        .collect();
+
+    (
+        prop::sample::select(compiled),
+        prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..4),
+    )
+        .prop_map(|(mut bytes, mutations)| {
+            for (index, byte) in mutations {
+                let at = index.index(bytes.len());
+                bytes[at] = byte;
+            }
+            bytes
+        })
+}
+
+/// Loads and runs a compiled script, which must fail cleanly if it was damaged.
+fn load_and_run(bytes: &[u8]) {
+    let mut vm = VM::new();
+    if let Ok(function) = ObjFunction::deserialize(bytes, vm.interner_mut()) {
+        vm.set_instruction_limit(Some(20_000));
+        let _ = vm.run_main(&function, &mut io::sink());
+    }
+}
+
+#[test]
+fn corpus_runs() {
+    for seed in corpus() {
+        interpret(&seed);
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn arbitrary_text_does_not_panic(source in any::<String>()) {
+        interpret(&source);
+    }
+
+    #[test]
+    fn token_soup_does_not_panic(source in token_soup()) {
+        interpret(&source);
+    }
+
+    #[test]
+    fn mutated_programs_do_not_panic(source in mutated_seed()) {
+        interpret(&source);
+    }
+
+    #[test]
+    fn mutated_loxc_does_not_panic(bytes in mutated_loxc()) {
+        load_and_run(&bytes);
+    }
+}
@@ -1,5 +1,16 @@
 mod common;
-use common::{assert_expression, assert_script_output, assert_script_error};
+use common::{
+    assert_compile_error, assert_expression, assert_gc_stress_output, assert_script_error,
+    assert_script_output, Output,
+};
+use rlox_vm::{
+    compiler::Compiler,
+    interner::Interner,
+    interpreter::{InterpretError, Interpreter},
+    object::ObjFunction,
+    value::Value,
+    vm::{RuntimeError, VM},
+};
 
 #[test]
 fn expresions() {
@@ -42,6 +53,19 @@ fn runtime_errors() {
         "var b; print a;",
         "Undefined variable 'a'",
     );
+    // Objects are handles into the heap, which mustn't show up in messages
+    assert_script_error(
+        "class A {} print 1 + A();",
+        "Operands must be two numbers or two strings.",
+    );
+    assert_script_error(
+        "print \"a\" + 1;",
+        "Operands must be two numbers or two strings.",
+    );
+    assert_script_error(
+        "print \"a\" - 1;",
+        "Expected to pop a number but found 'a'.",
+    );
 }
 
 
@@ -111,19 +135,779 @@ fn native_functions() {
         "print clock;",
         "<native 'clock'>",
     );
-    // assert_script_output(
-    //     "print clock();",
-    //     "",
-    // );
+    assert_script_output(
+        "
+var start = clock();
+print clock() - start >= 0;",
+        "true",
+    );
+    assert_script_error("clock(1);", "Expected 0 arguments but got 1 when calling 'clock'.");
+}
+
+fn describe(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    match &args[0] {
+        Value::Number(n) if *n < 0.0 => Err(RuntimeError::new("Can't describe a negative number.")),
+        Value::Number(n) => Ok(Value::new_string(&format!("{} is {}", n, args[1]))),
+        Value::Nil => Ok(Value::Nil),
+        _ => Ok(Value::Boolean(false)),
+    }
+}
+
+fn run_with_describe(source: &str) -> (Result<(), RuntimeError>, String) {
+    let source = source.to_string();
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+    let function = compiler.compile().unwrap();
+
+    vm.define_native("describe", 2, describe);
+    let mut stdout = Output::new();
+    let result = vm.run_main(&function, &mut stdout);
+    (result, stdout.contents)
+}
+
+#[test]
+fn user_defined_native_functions() {
+    let (result, output) = run_with_describe(
+        "
+print describe(1, \"odd\");
+print describe(nil, 2);
+print describe(true, 2);
+print describe(2, \"even\") + \"!\";",
+    );
+    result.unwrap();
+    assert_eq!(output, "1 is odd\nnil\nfalse\n2 is even!\n");
+
+    let (result, _) = run_with_describe("describe(-1, 0);");
+    assert_eq!(result.unwrap_err().message(), "Can't describe a negative number.");
+
+    let (result, _) = run_with_describe("describe(1);");
+    assert_eq!(result.unwrap_err().message(), "Expected 2 arguments but got 1 when calling 'describe'.");
+}
+
+#[test]
+fn closures() {
+    assert_script_output(
+        "
+fun make_counter() {
+    var count = 0;
+    fun counter() {
+        count = count + 1;
+        return count;
+    }
+    return counter;
+}
+
+var counter = make_counter();
+print counter();
+print counter();",
+        "1\n2",
+    );
+    assert_script_output(
+        "
+fun outer() {
+    var x = \"outside\";
+    fun middle() {
+        fun inner() {
+            print x;
+        }
+        return inner;
+    }
+    return middle;
+}
+
+outer()()();",
+        "outside",
+    );
+    assert_script_output(
+        "
+var get;
+var set;
+fun pair() {
+    var shared = 1;
+    fun g() { return shared; }
+    fun s(value) { shared = value; }
+    get = g;
+    set = s;
+}
+
+pair();
+set(42);
+print get();",
+        "42",
+    );
+    assert_script_output(
+        "
+{
+    var a = \"block\";
+    fun show() { print a; }
+    a = \"changed\";
+    show();
+}",
+        "changed",
+    );
+}
+
+#[test]
+fn closures_after_runtime_errors() {
+    // Like on the REPL, where the VM is reused after an error
+    let mut output = Output::new();
+    let mut interpreter = Interpreter::new(&mut output);
+    let failed = interpreter.interpret(
+        "var g; fun f() { var x = \"captured\"; fun h() { return x; } g = h; nil + 1; } f();",
+    );
+    assert!(matches!(failed, Err(InterpretError::Runtime(_))));
+    interpreter
+        .interpret("{ var a = \"one\"; var b = \"two\"; print g(); }")
+        .unwrap();
+
+    assert!(output.contents.ends_with("captured\n"), "{}", output.contents);
+}
+
+#[test]
+fn classes() {
+    assert_script_output(
+        "class Point {} print Point; print Point();",
+        "Point\nPoint instance",
+    );
+    assert_script_output(
+        "
+class Point {}
+var p = Point();
+p.x = 1;
+p.y = p.x + 1;
+print p.y;",
+        "2",
+    );
+    assert_script_output(
+        "
+class Point {
+    init(x, y) {
+        this.x = x;
+        this.y = y;
+    }
+
+    sum() {
+        return this.x + this.y;
+    }
+}
 
-/*
+var p = Point(1, 2);
+print p.sum();
+var sum = p.sum;
+p.x = 10;
+print sum();",
+        "3\n12",
+    );
+    assert_script_output(
+        "
+class Counter {
+    init() { this.count = 0; }
+    increment() {
+        fun add() { this.count = this.count + 1; }
+        return add;
+    }
+}
+
+var counter = Counter();
+var add = counter.increment();
+add();
+add();
+print counter.count;",
+        "2",
+    );
+    assert_script_output(
+        "
+class Box {}
+fun hello() { return \"hello\"; }
+var box = Box();
+box.hello = hello;
+print box.hello();",
+        "hello",
+    );
+    assert_script_error(
+        "class Point {} var p = Point(); print p.z;",
+        "Undefined property 'z'.",
+    );
+    assert_script_error("var a = 1; print a.b;", "Only instances have properties.");
+}
+
+#[test]
+fn inheritance() {
+    assert_script_output(
+        "
+class Animal {
+    init(name) { this.name = name; }
+    speak() { return this.name + \" makes a sound\"; }
+    describe() { return this.speak(); }
+}
+
+class Dog < Animal {
+    speak() { return super.speak() + \" and barks\"; }
+}
+
+var dog = Dog(\"Rex\");
+print dog.describe();
+print dog.name;",
+        "Rex makes a sound and barks\nRex",
+    );
+    assert_script_output(
+        "
+class A { method() { return \"A\"; } }
+class B < A {
+    method() { return \"B\"; }
+    test() {
+        var parent = super.method;
+        return parent() + this.method();
+    }
+}
+class C < B {}
+print C().test();",
+        "AB",
+    );
+    assert_script_output(
+        "
+{
+    class Base { greet() { return \"hi\"; } }
+    class Derived < Base {
+        greet() {
+            fun inner() { return super.greet(); }
+            return inner();
+        }
+    }
+    print Derived().greet();
+}",
+        "hi",
+    );
+    assert_script_error("var NotAClass = 1; class A < NotAClass {}", "Superclass must be a class.");
+    assert_compile_error("class A < A {}", "A class can't inherit from itself.");
+    assert_compile_error(
+        "fun f() { return super.f(); }",
+        "Can't use 'super' outside of a class.",
+    );
+    assert_compile_error(
+        "class A { f() { return super.f(); } }",
+        "Can't use 'super' in a class with no superclass.",
+    );
+}
+
+#[test]
+fn garbage_collection() {
+    assert_gc_stress_output(
+        "
+fun make_counter() {
+    var count = 0;
+    fun counter() {
+        count = count + 1;
+        return count;
+    }
+    return counter;
+}
+
+var counter = make_counter();
+counter();
+print counter();",
+        "2",
+    );
+    assert_gc_stress_output(
+        "
+class Node {
+    init(value) { this.value = value; }
+    link(next) {
+        this.next = next;
+        return this;
+    }
+}
+
+class Tagged < Node {
+    init(value) { super.init(\"tagged \" + value); }
+    link(next) {
+        var parent = super.link;
+        return parent(next);
+    }
+}
+
+var list = Node(\"a\").link(Tagged(\"b\").link(nil));
+var method = list.next.link;
+print method(nil).value;
+print list.next.value;",
+        "tagged b\ntagged b",
+    );
+}
+
+#[test]
+fn garbage_collection_frees_cycles() {
+    let source = String::from(
+        "
+class Node {}
+for (var i = 0; i < 10; i = i + 1) {
+    var a = Node();
+    var b = Node();
+    a.other = b;
+    b.other = a;
+    fun self_referencing() { return self_referencing; }
+}
+var kept = Node();",
+    );
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+    let function = compiler.compile().unwrap();
+
+    vm.run_main(&function, &mut Output::new()).unwrap();
+    assert!(vm.heap().object_count() > 30);
+
+    vm.collect_garbage();
+
+    // Only the class and the instance in 'kept' survive
+    assert_eq!(vm.heap().object_count(), 2);
+}
+
+#[test]
+fn garbage_collection_frees_strings() {
+    // Each concatenation makes a new string, but only the last one is still in use
+    let source = String::from(
+        "var s = \"\"; for (var i = 0; i < 20000; i = i + 1) { s = s + \"x\"; }",
+    );
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+    let function = compiler.compile().unwrap();
+
+    vm.run_main(&function, &mut Output::new()).unwrap();
+    assert!(vm.stats().collections > 0);
+    // Every string made would take around 200 MB
+    assert!(vm.interner().bytes() < 4 * 1024 * 1024, "{}", vm.interner().bytes());
+}
+
+#[test]
+fn stack_overflow() {
+    assert_script_error(
+        "
+fun forever(n) { return forever(n + 1); }
+forever(0);",
+        "Stack overflow.",
+    );
+
+    // Deep recursion below the limit still works, and doesn't grow the native stack
+    assert_script_output(
+        "
+fun count(n) {
+    if (n == 0) return 0;
+    return 1 + count(n - 1);
+}
+print count(1000);",
+        "1000",
+    );
+}
+
+#[test]
+fn max_frames_is_configurable() {
+    let source = String::from(
+        "
+fun count(n) {
+    if (n == 0) return 0;
+    return 1 + count(n - 1);
+}
+print count(10);",
+    );
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+    let function = compiler.compile().unwrap();
+
+    vm.set_max_frames(5);
+    assert!(vm.run_main(&function, &mut Output::new()).is_err());
+
+    // The VM can keep being used after a runtime error
+    vm.set_max_frames(20);
+    let mut stdout = Output::new();
+    vm.run_main(&function, &mut stdout).unwrap();
+    assert_eq!(stdout.contents, "10\n");
+}
+
+#[test]
+fn arity_errors() {
+    let fact = "
+fun fact(n) {
+    if (n < 2) return 1;
+    return n * fact(n - 1);
+}
+";
+    assert_script_output(&format!("{}print fact(5);", fact), "120");
+    assert_script_error(
+        &format!("{}fact();", fact),
+        "Expected 1 arguments but got 0 when calling 'fact'.",
+    );
+    assert_script_error(
+        &format!("{}fact(1, 2);", fact),
+        "Expected 1 arguments but got 2 when calling 'fact'.",
+    );
+
+    let point = "
+class Point {
+    init(x, y) {
+        this.x = x;
+        this.y = y;
+    }
+    move(dx, dy) { return Point(this.x + dx, this.y + dy); }
+}
+";
+    assert_script_output(&format!("{}print Point(1, 2).move(1, 1).y;", point), "3");
+    assert_script_error(
+        &format!("{}Point(1);", point),
+        "Expected 2 arguments but got 1 when calling 'init'.",
+    );
+    assert_script_error(
+        &format!("{}var move = Point(1, 2).move; move(1);", point),
+        "Expected 2 arguments but got 1 when calling 'move'.",
+    );
+    assert_script_error(
+        &format!("{}Point(1, 2).move();", point),
+        "Expected 2 arguments but got 0 when calling 'move'.",
+    );
+    assert_script_error(
+        "class Empty {} Empty(1);",
+        "Expected 0 arguments but got 1 when calling 'Empty'.",
+    );
+}
+
+#[test]
+fn stack_traces() {
+    let source = String::from(
+        "
 fun fib(n) {
-  if (n < 2) return n;
-  return fib(n - 2) + fib(n - 1);
+    if (n < 2) return n + nil;
+    return fib(n - 2) + fib(n - 1);
 }
 
-print fib(5);
-var start = clock();
-print clock() - start;
- */
+print fib(3);",
+    );
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+    let function = compiler.compile().unwrap();
+
+    let error = vm.run_main(&function, &mut Output::new()).unwrap_err();
+
+    assert_eq!(
+        error.to_string(),
+        "Operands must be two numbers or two strings.
+[line 3] in fib
+[line 4] in fib
+[line 7] in script"
+    );
+    assert_eq!(error.trace()[0].location.column, 25);
+}
+
+#[test]
+fn stack_overflow_traces() {
+    let source = String::from("fun f(n) { return f(n + 1); }\nf(0);");
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+    let function = compiler.compile().unwrap();
+
+    let error = vm.run_main(&function, &mut Output::new()).unwrap_err();
+
+    // Every frame is kept, but the repeated ones aren't all shown
+    assert_eq!(error.trace().len(), 1024);
+    assert_eq!(
+        error.to_string(),
+        "Stack overflow.
+[line 1] in f
+[line 1] in f
+[line 1] in f
+... 1020 more frames in f
+[line 2] in script"
+    );
+}
+
+#[test]
+fn compile_diagnostics() {
+    let source = String::from(
+        "
+print 1
+var a = 2;
+{
+    var b = 1;
+    var b;
+}
+this.x = @;",
+    );
+    let mut interner = Interner::new();
+    let mut compiler = Compiler::from_source(&source, &mut interner);
+    let diagnostics = compiler.compile().unwrap_err();
+
+    let found: Vec<(u32, u32, &str)> = diagnostics
+        .iter()
+        .map(|diagnostic| (diagnostic.line, diagnostic.column, diagnostic.message.as_str()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (3, 1, "Expect ';' after value."),
+            (6, 9, "There is already a local variable called 'b' in this scope."),
+            (8, 1, "Can't use 'this' outside of a class."),
+        ]
+    );
+    assert_eq!(diagnostics[0].span.len(), 3);
+    assert_eq!(diagnostics[0].to_string(), "[line 3:1] Error: Expect ';' after value.");
+}
+
+#[test]
+fn rendered_errors() {
+    let mut output = Output::new();
+    let mut interpreter = Interpreter::new(&mut output);
+    interpreter.set_file_name("broken.lox");
+    let compile_error = interpreter.interpret("var a = 1;\nprint a");
+    assert!(matches!(compile_error, Err(InterpretError::Compile(diagnostics)) if diagnostics.len() == 1));
+    let runtime_error = interpreter.interpret("fun f() {\n    return 1 + nil;\n}\nf();");
+    assert!(matches!(runtime_error, Err(InterpretError::Runtime(_))));
+
+    assert_eq!(
+        output.contents,
+        "error: Expect ';' after value.
+ --> broken.lox:2:8
+  |
+2 | print a
+  |        ^
+error: Operands must be two numbers or two strings.
+ --> broken.lox:2:14
+  |
+2 |     return 1 + nil;
+  |              ^
+  = note: [line 2] in f
+  = note: [line 4] in script
+"
+    );
+}
+
+#[test]
+fn rendered_errors_from_earlier_sources() {
+    // Like on the REPL, where functions can come from lines that were already run
+    let mut output = Output::new();
+    let mut interpreter = Interpreter::new(&mut output);
+    interpreter.interpret("fun f() {\n    return 1 + nil;\n}").unwrap();
+    let runtime_error = interpreter.interpret("print 1;\nf();");
+    assert!(matches!(runtime_error, Err(InterpretError::Runtime(_))));
+
+    assert_eq!(
+        output.contents,
+        "1
+error: Operands must be two numbers or two strings.
+  = note: [line 2] in f
+  = note: [line 2] in script
+"
+    );
+}
+
+#[test]
+fn compile_limits() {
+    let arguments = vec!["1"; 256].join(", ");
+    assert_compile_error(
+        &format!("fun f() {{}} f({});", arguments),
+        "Can't have more than 255 arguments.",
+    );
+    let parameters: Vec<String> = (0..256).map(|i| format!("p{}", i)).collect();
+    assert_compile_error(
+        &format!("fun f({}) {{}}", parameters.join(", ")),
+        "Can't have more than 255 parameters.",
+    );
+
+    let deep_expression = format!("print {}1{};", "(".repeat(10_000), ")".repeat(10_000));
+    assert_compile_error(&deep_expression, "Too much nesting.");
+    let deep_blocks = format!("{}{}", "{".repeat(10_000), "}".repeat(10_000));
+    assert_compile_error(&deep_blocks, "Too much nesting.");
+
+    // Nesting below the limit is fine
+    assert_expression(&format!("{}1{}", "(".repeat(100), ")".repeat(100)), "1");
+    assert_script_output(
+        &format!("{}print 1;{}", "{".repeat(200), "}".repeat(200)),
+        "1",
+    );
+}
+
+#[test]
+fn encoding_limits() {
+    // Local slots are a byte
+    let locals: Vec<String> = (0..256).map(|i| format!("var l{} = {};", i, i)).collect();
+    assert_script_output(&format!("{{ {} print l255; }}", locals.join(" ")), "255");
+    assert_compile_error(
+        &format!("{{ {} var one_too_many; }}", locals.join(" ")),
+        "Too many local variables in function.",
+    );
+
+    // Past 256 constants they take a wider operand
+    let additions: Vec<String> = (1..=300).map(|i| format!("a = a + {};", i)).collect();
+    assert_script_output(&format!("var a = 0; {} print a;", additions.join(" ")), "45150");
+
+    let long_body = "nil;".repeat(40_000);
+    assert_compile_error(
+        &format!("if (true) {{ {} }}", long_body),
+        "Too much code to jump over.",
+    );
+    assert_compile_error(
+        &format!("while (false) {{ {} }}", long_body),
+        "Loop body too large.",
+    );
+}
+
+#[test]
+fn instruction_limit() {
+    let source = String::from("while (true) {}");
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+    let function = compiler.compile().unwrap();
+
+    vm.set_instruction_limit(Some(1000));
+    let error = vm.run_main(&function, &mut Output::new()).unwrap_err();
+    assert_eq!(error.message(), "Instruction limit exceeded.");
+}
+
+#[test]
+fn tracing() {
+    let source = String::from("print 1 + 2;");
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+    let function = compiler.compile().unwrap();
+
+    let mut output = Output::new();
+    vm.set_trace(true);
+    vm.run_main(&function, &mut output).unwrap();
+    let add = output.contents.find("Add").expect("Each operation should be traced");
+    let print = output.contents.find("Print").unwrap();
+    assert!(add < print);
+    assert!(output.contents.contains("[2]  2"), "The stack should be traced");
+
+    let mut output = Output::new();
+    vm.set_trace(false);
+    vm.run_main(&function, &mut output).unwrap();
+    assert_eq!(output.contents, "3\n");
+}
+
+#[test]
+fn stats() {
+    let source = String::from(
+        "
+fun f(n) {
+    if (n > 0) f(n - 1);
+}
+f(3);
+",
+    );
+    let mut vm = VM::new();
+    let mut compiler = Compiler::from_source(&source, vm.interner_mut());
+    let function = compiler.compile().unwrap();
+
+    vm.run_main(&function, &mut Output::new()).unwrap();
+    let stats = vm.stats();
+    // The script, then f(3) down to f(0)
+    assert_eq!(stats.max_frames, 5);
+    assert_eq!(stats.collections, 0);
+    let instructions = stats.instructions;
+    assert!(instructions > 0);
+
+    vm.collect_garbage();
+    vm.run_main(&function, &mut Output::new()).unwrap();
+    let stats = vm.stats();
+    assert_eq!(stats.instructions, 2 * instructions);
+    assert_eq!(stats.collections, 1);
+}
+
+#[test]
+fn nested_functions() {
+    assert_script_output(
+        "
+fun outer(n) {
+    var total = 0;
+    {
+        var step = 2;
+        fun add(x) {
+            var doubled = x * step;
+            total = total + doubled;
+            return doubled;
+        }
+        for (var i = 0; i < n; i = i + 1) {
+            add(i);
+        }
+    }
+    fun describe(prefix) {
+        fun join(a, b) { return a + b; }
+        return join(prefix, \"done\");
+    }
+    print describe(\"outer \");
+    return total;
+}
+print outer(4);",
+        "outer done\n12",
+    );
+    assert_compile_error(
+        "{ var a = 1; { var a = a; } }",
+        "Can't read local variable in its own initializer.",
+    );
+}
+
+#[test]
+fn interned_strings() {
+    assert_expression("\"a\" + \"b\" == \"ab\"", "true");
+    assert_expression("\"ab\" == \"a\" + \"c\"", "false");
+    assert_script_output(
+        "
+var greeting = \"hello\";
+fun build() { return \"hel\" + \"lo\"; }
+print greeting == build();",
+        "true",
+    );
+
+    // Strings created by natives are shared with the ones from the script
+    let (result, output) = run_with_describe("print describe(1, true) == \"1 is true\";");
+    result.unwrap();
+    assert_eq!(output, "true\n");
+}
+
+#[test]
+fn global_symbols() {
+    let mut vm = VM::new();
+    let first = String::from("var a = 1;");
+    let function = Compiler::from_source(&first, vm.interner_mut()).compile().unwrap();
+    vm.run_main(&function, &mut Output::new()).unwrap();
+
+    // Later compilations with the same interner see the globals defined before
+    let second = String::from("a = a + 1; print a;");
+    let function = Compiler::from_source(&second, vm.interner_mut()).compile().unwrap();
+    let mut stdout = Output::new();
+    vm.run_main(&function, &mut stdout).unwrap();
+    assert_eq!(stdout.contents, "2\n");
+
+    let third = String::from("print b;");
+    let function = Compiler::from_source(&third, vm.interner_mut()).compile().unwrap();
+    let error = vm.run_main(&function, &mut Output::new()).unwrap_err();
+    assert_eq!(error.message(), "Undefined variable 'b'");
+    assert_eq!(vm.interner().symbol_name(0), Some("clock"));
+}
+
+#[test]
+fn compiled_files() {
+    let source = String::from(
+        "
+class Counter {
+    init() { this.count = 0; }
+    add(n) { this.count = this.count + n; return this; }
+}
+fun twice(f) { return fun_twice(f); }
+fun fun_twice(f) { fun go(x) { return f(f(x)); } return go; }
+fun square(x) { return x * x; }
+print twice(square)(3);
+print Counter().add(2).add(3).count;
+print \"done\" == \"do\" + \"ne\";",
+    );
+    let mut compiling = VM::new();
+    let function = Compiler::from_source(&source, compiling.interner_mut())
+        .compile()
+        .unwrap();
+    let bytes = function.serialize(compiling.interner()).unwrap();
+
+    // The VM loading it has other globals, so the symbols don't match the original ones
+    let mut vm = VM::new();
+    vm.define_native("describe", 2, describe);
+    let loaded = ObjFunction::deserialize(&bytes, vm.interner_mut()).unwrap();
+    let mut stdout = Output::new();
+    vm.run_main(&loaded, &mut stdout).unwrap();
+
+    assert_eq!(stdout.contents, "81\n5\ntrue\n");
 }